@@ -0,0 +1,30 @@
+use std::fmt::Display;
+
+/// Errors raised while turning a configuration into running modules.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigurationError {
+    /// No module is registered under the given type name.
+    UnknownModuleType(String),
+
+    /// The module settings could not be read. `path` points at the offending
+    /// key inside the module's settings, or is "." for the settings as a whole.
+    InvalidSettings { path: String, message: String },
+}
+
+impl Display for ConfigurationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigurationError::UnknownModuleType(name) => {
+                write!(f, "unknown module type {:?}", name)
+            }
+            ConfigurationError::InvalidSettings { path, message } if path == "." => {
+                write!(f, "invalid settings: {}", message)
+            }
+            ConfigurationError::InvalidSettings { path, message } => {
+                write!(f, "invalid setting {}: {}", path, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigurationError {}
@@ -1,5 +1,10 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    fs::read_to_string,
+    path::{Path, PathBuf},
+};
 
+use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
 
 use super::module_properties::ModuleProperties;
@@ -12,16 +17,228 @@ pub struct GlobalConfiguration {
     // List of modules.
     pub modules: HashMap<String, ModuleProperties>,
 
-    // List of routes.
+    // List of routes. Nodes only receiving from other nodes need none.
+    #[serde(default)]
     pub routes: HashMap<String, RouteConfiguration>,
+
+    // Shared libraries to load extra module types from.
+    #[serde(default)]
+    pub plugins: Vec<PathBuf>,
+
+    #[serde(default)]
+    pub shutdown: ShutdownConfiguration,
+
+    // How this instance talks to other hulaak instances.
+    #[serde(default)]
+    pub node: NodeConfiguration,
+
+    // Finding the other nodes, instead of listing them all in node.peers.
+    #[serde(default)]
+    pub cluster: Option<ClusterConfiguration>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl GlobalConfiguration {
+    /// Reads and parses a configuration file, without validating it.
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents =
+            read_to_string(path).with_context(|| format!("error reading {}", path.display()))?;
+
+        toml::from_str(&contents).with_context(|| format!("error parsing {}", path.display()))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShutdownConfiguration {
+    // How long to wait for in-flight messages to reach their sinks after the
+    // sources have stopped, before giving up on them.
+    #[serde(default = "ShutdownConfiguration::default_drain_timeout")]
+    pub drain_timeout_ms: u64,
+}
+
+impl ShutdownConfiguration {
+    fn default_drain_timeout() -> u64 {
+        5000
+    }
+}
+
+impl Default for ShutdownConfiguration {
+    fn default() -> Self {
+        ShutdownConfiguration {
+            drain_timeout_ms: Self::default_drain_timeout(),
+        }
+    }
+}
+
+/// Settings for sending messages to modules of other hulaak instances, and
+/// receiving messages from them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeConfiguration {
+    // Name other nodes know this one by. The stage id is used if unset.
+    #[serde(default)]
+    pub name: Option<String>,
+
+    // Address other nodes connect to, e.g. "0.0.0.0:7700". Nothing is
+    // received from other nodes if unset.
+    #[serde(default)]
+    pub listen: Option<String>,
+
+    // Addresses of the nodes that routes send to, by name.
+    #[serde(default)]
+    pub peers: HashMap<String, String>,
+
+    // Messages sent to a node and not acknowledged yet, kept to be sent again
+    // if the connection drops. Once full, no more messages are taken from the
+    // routes to that node until some are acknowledged.
+    #[serde(default = "NodeConfiguration::default_max_unacknowledged")]
+    pub max_unacknowledged: usize,
+
+    // Delay before reconnecting to a node, doubled after every failed attempt.
+    #[serde(default = "NodeConfiguration::default_reconnect_backoff")]
+    pub reconnect_backoff_ms: u64,
+    #[serde(default = "NodeConfiguration::default_max_reconnect_backoff")]
+    pub max_reconnect_backoff_ms: u64,
+}
+
+impl NodeConfiguration {
+    fn default_max_unacknowledged() -> usize {
+        1024
+    }
+
+    fn default_reconnect_backoff() -> u64 {
+        500
+    }
+
+    fn default_max_reconnect_backoff() -> u64 {
+        30_000
+    }
+}
+
+impl Default for NodeConfiguration {
+    fn default() -> Self {
+        NodeConfiguration {
+            name: None,
+            listen: None,
+            peers: HashMap::new(),
+            max_unacknowledged: Self::default_max_unacknowledged(),
+            reconnect_backoff_ms: Self::default_reconnect_backoff(),
+            max_reconnect_backoff_ms: Self::default_max_reconnect_backoff(),
+        }
+    }
+}
+
+/// Settings for joining a cluster of nodes that find each other by gossiping
+/// about who is part of it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClusterConfiguration {
+    // Addresses of nodes to join the cluster through.
+    #[serde(default)]
+    pub seeds: Vec<String>,
+
+    // Address the other members reach this node at. node.listen is used if
+    // unset.
+    #[serde(default)]
+    pub advertise: Option<String>,
+
+    // How often to gossip with another member.
+    #[serde(default = "ClusterConfiguration::default_gossip_interval")]
+    pub gossip_interval_ms: u64,
+
+    // A member not heard from for this long is suspected to be down, and no
+    // longer sent new messages.
+    #[serde(default = "ClusterConfiguration::default_suspect_after")]
+    pub suspect_after_ms: u64,
+
+    // A member not heard from for this long is dead, and the messages it has
+    // not acknowledged are sent to other members.
+    #[serde(default = "ClusterConfiguration::default_dead_after")]
+    pub dead_after_ms: u64,
+}
+
+impl ClusterConfiguration {
+    fn default_gossip_interval() -> u64 {
+        1000
+    }
+
+    fn default_suspect_after() -> u64 {
+        5000
+    }
+
+    fn default_dead_after() -> u64 {
+        15_000
+    }
+}
+
+/// Node name of route destinations like `module@*`, which go to any member
+/// of the cluster receiving messages for the module.
+pub const ANY_NODE: &str = "*";
+
+/// Splits a route destination of the form `module@node` into the module and
+/// the node it runs on. Returns None for local modules.
+pub fn remote_destination(destination: &str) -> Option<(&str, &str)> {
+    destination.split_once('@')
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum RouteCardinality {
     Multiple(Vec<String>),
     Single(String),
 }
 
+/// How messages on a route are handed out when it has several destinations.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Delivery {
+    /// Every destination receives a copy of every message.
+    #[default]
+    Broadcast,
+
+    /// Destinations take turns receiving messages.
+    RoundRobin,
+
+    /// Messages with the same value for the given field always go to the
+    /// same destination.
+    Hash(String),
+
+    /// Destinations share a single receiver, and whichever is free first
+    /// gets the message.
+    Competing,
+}
+
+impl TryFrom<String> for Delivery {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.as_str() {
+            "broadcast" => Ok(Delivery::Broadcast),
+            "round_robin" => Ok(Delivery::RoundRobin),
+            "competing" => Ok(Delivery::Competing),
+            _ => match value
+                .strip_prefix("hash(")
+                .and_then(|rest| rest.strip_suffix(')'))
+            {
+                Some(field) if !field.trim().is_empty() => {
+                    Ok(Delivery::Hash(field.trim().to_string()))
+                }
+                _ => Err(format!(
+                    "unknown delivery mode {:?}, expected one of \"broadcast\", \"round_robin\", \"hash(field)\" or \"competing\"",
+                    value
+                )),
+            },
+        }
+    }
+}
+
+impl From<Delivery> for String {
+    fn from(value: Delivery) -> Self {
+        match value {
+            Delivery::Broadcast => "broadcast".into(),
+            Delivery::RoundRobin => "round_robin".into(),
+            Delivery::Hash(field) => format!("hash({})", field),
+            Delivery::Competing => "competing".into(),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RouteConfiguration {
     #[serde(default = "uuid::Uuid::new_v4")]
@@ -29,6 +246,52 @@ pub struct RouteConfiguration {
 
     pub from: RouteCardinality,
     pub to: RouteCardinality,
+
+    #[serde(default)]
+    pub delivery: Delivery,
+
+    // Maximum number of messages waiting on the route. Unbounded if unset.
+    #[serde(default)]
+    pub capacity: Option<usize>,
+
+    // What to do with new messages once the route is at capacity.
+    #[serde(default)]
+    pub overflow: OverflowPolicy,
+
+    // Directory for spill files, when overflowing to disk.
+    #[serde(default)]
+    pub spill_path: Option<PathBuf>,
+}
+
+/// What a bounded route does with a message when it is full.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Wait until there is room on the route.
+    #[default]
+    Block,
+
+    /// Discard the message being sent.
+    DropNewest,
+
+    /// Discard the oldest message waiting on the route to make room.
+    DropOldest,
+
+    /// Write the message to a file on disk, and feed it back into the route
+    /// once there is room again.
+    SpillToDisk,
+}
+
+impl RouteConfiguration {
+    /// True if both describe the same route, leaving out the generated id.
+    pub fn same_configuration(&self, other: &RouteConfiguration) -> bool {
+        self.from == other.from
+            && self.to == other.to
+            && self.delivery == other.delivery
+            && self.capacity == other.capacity
+            && self.overflow == other.overflow
+            && self.spill_path == other.spill_path
+    }
 }
 
 impl RouteCardinality {
@@ -38,4 +301,4 @@ impl RouteCardinality {
             RouteCardinality::Single(name) => vec![name.clone()],
         }
     }
-}
\ No newline at end of file
+}
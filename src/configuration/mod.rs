@@ -1,2 +1,5 @@
+pub mod error;
 pub mod global_configuration;
-pub mod module_properties;
\ No newline at end of file
+pub mod module_properties;
+pub mod validation;
+pub mod watcher;
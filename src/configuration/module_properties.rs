@@ -1,11 +1,16 @@
 use std::collections::HashMap;
 
+use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
-use async_channel::{Receiver, Sender};
-use serde::{Deserialize, Serialize};
+use serde::{
+    de::{value::MapDeserializer, DeserializeOwned},
+    Deserialize, Serialize,
+};
 
-use crate::messaging::message::Message;
+use crate::messaging::mailbox::{Inboxes, Outboxes};
+
+use super::error::ConfigurationError;
 
 #[allow(unused)]
 #[derive(Debug, Serialize, Default, Deserialize, Clone)]
@@ -16,11 +21,105 @@ pub struct ModuleProperties {
     pub module_type: String,
     pub description: Option<String>,
 
+    // How the module is restarted when it stops. Must come before the module
+    // settings, so that its keys are not mistaken for module settings.
+    #[serde(flatten)]
+    pub supervision: SupervisionConfiguration,
+
     #[serde(flatten)]
     pub module_settings: Option<HashMap<String, serde_json::Value>>,
 
     #[serde(skip)]
-    pub inbox: Option<Receiver<Message>>,
+    pub inboxes: Inboxes,
     #[serde(skip)]
-    pub outbox: Option<Sender<Message>>,
+    pub outboxes: Outboxes,
+
+    // Cancelled when hulaak is shutting down. Sources stop producing messages
+    // when it fires, while sinks keep going until their inboxes are drained.
+    #[serde(skip)]
+    pub shutdown: CancellationToken,
+}
+
+/// When a stopped module is started again.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RestartPolicy {
+    #[default]
+    Never,
+    Always,
+    /// Only when the module crashed, not when it finished on its own.
+    OnFailure,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SupervisionConfiguration {
+    #[serde(default)]
+    pub restart: RestartPolicy,
+
+    // The module is given up on after this many restarts within the window.
+    #[serde(default = "SupervisionConfiguration::default_max_restarts")]
+    pub max_restarts: u32,
+    #[serde(default = "SupervisionConfiguration::default_restart_window")]
+    pub restart_window_secs: u64,
+
+    // Delay before a restart, doubled for every restart within the window.
+    #[serde(default = "SupervisionConfiguration::default_backoff")]
+    pub backoff_ms: u64,
+    #[serde(default = "SupervisionConfiguration::default_max_backoff")]
+    pub max_backoff_ms: u64,
+}
+
+impl SupervisionConfiguration {
+    fn default_max_restarts() -> u32 {
+        5
+    }
+
+    fn default_restart_window() -> u64 {
+        60
+    }
+
+    fn default_backoff() -> u64 {
+        500
+    }
+
+    fn default_max_backoff() -> u64 {
+        30_000
+    }
+}
+
+impl Default for SupervisionConfiguration {
+    fn default() -> Self {
+        SupervisionConfiguration {
+            restart: RestartPolicy::default(),
+            max_restarts: Self::default_max_restarts(),
+            restart_window_secs: Self::default_restart_window(),
+            backoff_ms: Self::default_backoff(),
+            max_backoff_ms: Self::default_max_backoff(),
+        }
+    }
+}
+
+impl ModuleProperties {
+    /// True if both describe the same module. The uuid is left out, as it is
+    /// generated anew every time a configuration is read.
+    pub fn same_configuration(&self, other: &ModuleProperties) -> bool {
+        self.module_type == other.module_type
+            && self.description == other.description
+            && self.supervision == other.supervision
+            && self.module_settings == other.module_settings
+    }
+
+    /// Deserializes the module specific settings into the module's own
+    /// configuration type. The error carries the path of the offending key.
+    pub fn settings<T: DeserializeOwned>(&self) -> Result<T, ConfigurationError> {
+        let settings = self.module_settings.clone().unwrap_or_default();
+        let deserializer = MapDeserializer::<_, serde_json::Error>::new(settings.into_iter());
+
+        serde_path_to_error::deserialize(deserializer).map_err(|e| {
+            ConfigurationError::InvalidSettings {
+                path: e.path().to_string(),
+                message: e.into_inner().to_string(),
+            }
+        })
+    }
 }
@@ -0,0 +1,406 @@
+use std::{collections::HashSet, fmt::Display, net::SocketAddr};
+
+use crate::modules::registry::ModulesRegistry;
+
+use super::{
+    error::ConfigurationError,
+    global_configuration::{remote_destination, GlobalConfiguration, ANY_NODE},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A single problem found in the configuration, along with the TOML key it
+/// was found at.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Problem {
+    pub severity: Severity,
+    pub path: String,
+    pub message: String,
+}
+
+/// All the problems found while validating a configuration.
+#[derive(Debug, Default)]
+pub struct ValidationReport {
+    pub problems: Vec<Problem>,
+}
+
+impl ValidationReport {
+    fn error(&mut self, path: impl Into<String>, message: impl Into<String>) {
+        self.problems.push(Problem {
+            severity: Severity::Error,
+            path: path.into(),
+            message: message.into(),
+        });
+    }
+
+    fn warning(&mut self, path: impl Into<String>, message: impl Into<String>) {
+        self.problems.push(Problem {
+            severity: Severity::Warning,
+            path: path.into(),
+            message: message.into(),
+        });
+    }
+
+    /// True if there are no errors. Warnings do not stop a configuration
+    /// from running.
+    pub fn is_ok(&self) -> bool {
+        !self
+            .problems
+            .iter()
+            .any(|problem| problem.severity == Severity::Error)
+    }
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+
+        write!(f, "{}: {}: {}", severity, self.path, self.message)
+    }
+}
+
+impl Display for ValidationReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for problem in &self.problems {
+            writeln!(f, "{}", problem)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl GlobalConfiguration {
+    /// Checks the configuration for problems without starting anything. All
+    /// problems are collected, instead of stopping at the first one.
+    pub fn validate(&self) -> ValidationReport {
+        let mut report = ValidationReport::default();
+        let mut routed = HashSet::new();
+
+        for (name, module) in &self.modules {
+            let path = format!("modules.{}", name);
+
+            match ModulesRegistry::validate(&module.module_type, module) {
+                Ok(_) => {}
+                Err(e @ ConfigurationError::UnknownModuleType(_)) => {
+                    report.error(format!("{}.module_type", path), e.to_string());
+                }
+                Err(ConfigurationError::InvalidSettings { path: key, message }) => {
+                    let path = if key == "." {
+                        path
+                    } else {
+                        format!("{}.{}", path, key)
+                    };
+
+                    report.error(path, message);
+                }
+            }
+        }
+
+        for (name, route) in &self.routes {
+            let path = format!("routes.{}", name);
+
+            for (side, modules) in [("from", &route.from), ("to", &route.to)] {
+                for module_name in modules.get_modules() {
+                    if let Some((_, peer)) = remote_destination(&module_name) {
+                        if side == "from" {
+                            report.error(
+                                format!("{}.{}", path, side),
+                                format!(
+                                    "{:?} is on another node, it can only be a route destination",
+                                    module_name
+                                ),
+                            );
+                        } else if peer == ANY_NODE && self.cluster.is_none() {
+                            report.error(
+                                format!("{}.{}", path, side),
+                                format!(
+                                    "{:?} needs a [cluster] to find nodes to send to",
+                                    module_name
+                                ),
+                            );
+                        } else if !self.node.peers.contains_key(peer) && self.cluster.is_none() {
+                            report.error(
+                                format!("{}.{}", path, side),
+                                format!("node {:?} is not defined in node.peers", peer),
+                            );
+                        }
+                        continue;
+                    }
+
+                    routed.insert(module_name.clone());
+
+                    let Some(module) = self.modules.get(&module_name) else {
+                        report.error(
+                            format!("{}.{}", path, side),
+                            format!("module {:?} is not defined", module_name),
+                        );
+                        continue;
+                    };
+
+                    let Some(capabilities) = ModulesRegistry::capabilities(&module.module_type)
+                    else {
+                        continue;
+                    };
+
+                    if side == "from" && !capabilities.outbox {
+                        report.error(
+                            format!("{}.{}", path, side),
+                            format!(
+                                "module {:?} ({}) cannot send messages, so it cannot be a route source",
+                                module_name, module.module_type
+                            ),
+                        );
+                    }
+
+                    if side == "to" && !capabilities.inbox {
+                        report.error(
+                            format!("{}.{}", path, side),
+                            format!(
+                                "module {:?} ({}) cannot receive messages, so it cannot be a route destination",
+                                module_name, module.module_type
+                            ),
+                        );
+                    }
+                }
+            }
+        }
+
+        if let Some(cluster) = &self.cluster {
+            match (&self.node.listen, &cluster.advertise) {
+                (None, _) => report.error(
+                    "cluster",
+                    "node.listen must be set for the other members to reach this node",
+                ),
+                (Some(listen), None)
+                    if listen
+                        .parse::<SocketAddr>()
+                        .is_ok_and(|address| address.ip().is_unspecified()) =>
+                {
+                    report.error(
+                        "cluster.advertise",
+                        format!(
+                            "must be set, as the other members cannot reach this node at {}",
+                            listen
+                        ),
+                    )
+                }
+                _ => {}
+            }
+        }
+
+        for (name, module) in &self.modules {
+            // Other nodes can send messages to any module that receives them.
+            let remotely_routed = self.node.listen.is_some()
+                && ModulesRegistry::capabilities(&module.module_type)
+                    .is_some_and(|capabilities| capabilities.inbox);
+
+            if !routed.contains(name) && !remotely_routed {
+                report.warning(
+                    format!("modules.{}", name),
+                    "module is not used by any route, it will not be run",
+                );
+            }
+        }
+
+        report.problems.sort();
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validate(configuration: &str) -> Vec<String> {
+        let configuration: GlobalConfiguration = toml::from_str(configuration).unwrap();
+        configuration
+            .validate()
+            .problems
+            .iter()
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    const ECHO_ROUTE: &str = r#"
+        [modules.input]
+        module_type = "stdin"
+
+        [modules.output]
+        module_type = "echo"
+
+        [routes.main]
+        from = { Single = "input" }
+        to = { Single = "output" }
+    "#;
+
+    #[test]
+    fn a_valid_configuration_has_no_problems() {
+        let report = toml::from_str::<GlobalConfiguration>(ECHO_ROUTE)
+            .unwrap()
+            .validate();
+
+        assert!(report.is_ok());
+        assert!(report.problems.is_empty());
+    }
+
+    #[test]
+    fn unknown_module_types_are_errors() {
+        let problems = validate(&format!(
+            "{}\n[modules.other]\nmodule_type = \"nope\"\n",
+            ECHO_ROUTE
+        ));
+
+        assert_eq!(
+            problems,
+            vec![
+                "error: modules.other.module_type: unknown module type \"nope\"",
+                "warning: modules.other: module is not used by any route, it will not be run",
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_settings_are_reported_at_their_path() {
+        let problems = validate(&ECHO_ROUTE.replace(
+            "module_type = \"echo\"",
+            "module_type = \"tcpwriter\"\naddress = \"localhost\"\nport = \"http\"",
+        ));
+
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(
+            problems[0].starts_with("error: modules.output.port: "),
+            "{:?}",
+            problems
+        );
+    }
+
+    #[test]
+    fn routes_must_use_defined_modules() {
+        let problems = validate(&format!(
+            "{}\n[routes.broken]\nfrom = {{ Single = \"missing\" }}\nto = {{ Multiple = [\"output\", \"gone\"] }}\n",
+            ECHO_ROUTE
+        ));
+
+        assert_eq!(
+            problems,
+            vec![
+                "error: routes.broken.from: module \"missing\" is not defined",
+                "error: routes.broken.to: module \"gone\" is not defined",
+            ]
+        );
+    }
+
+    #[test]
+    fn sinks_cannot_be_route_sources() {
+        let problems = validate(&format!(
+            "{}\n[routes.backwards]\nfrom = {{ Single = \"output\" }}\nto = {{ Single = \"input\" }}\n",
+            ECHO_ROUTE
+        ));
+
+        assert_eq!(
+            problems,
+            vec![
+                "error: routes.backwards.from: module \"output\" (echo) cannot send messages, so it cannot be a route source",
+                "error: routes.backwards.to: module \"input\" (stdin) cannot receive messages, so it cannot be a route destination",
+            ]
+        );
+    }
+
+    #[test]
+    fn unrouted_modules_are_only_warnings() {
+        let configuration: GlobalConfiguration = toml::from_str(&format!(
+            "{}\n[modules.spare]\nmodule_type = \"echo\"\n",
+            ECHO_ROUTE
+        ))
+        .unwrap();
+        let report = configuration.validate();
+
+        assert!(report.is_ok());
+        assert_eq!(
+            report.to_string(),
+            "warning: modules.spare: module is not used by any route, it will not be run\n"
+        );
+    }
+
+    #[test]
+    fn every_problem_is_reported_at_once() {
+        let problems = validate(
+            r#"
+            [modules.input]
+            module_type = "nope"
+
+            [modules.output]
+            module_type = "udpwriter"
+            port = 9000
+
+            [modules.spare]
+            module_type = "echo"
+
+            [routes.main]
+            from = { Single = "input" }
+            to = { Multiple = ["output", "missing", "archive@core"] }
+            "#,
+        );
+
+        assert_eq!(
+            problems,
+            vec![
+                "error: modules.input.module_type: unknown module type \"nope\"",
+                "error: modules.output: missing field `address`",
+                "error: routes.main.to: module \"missing\" is not defined",
+                "error: routes.main.to: node \"core\" is not defined in node.peers",
+                "warning: modules.spare: module is not used by any route, it will not be run",
+            ]
+        );
+    }
+
+    #[test]
+    fn files_the_settings_point_to_are_loaded() {
+        let problems = validate(
+            r#"
+            [modules.input]
+            module_type = "tcpsocketlistener"
+            address = "127.0.0.1"
+            port = 9000
+            buffer_size = 1024
+            tls = { certificate = "/nonexistent/cert.pem", key = "/nonexistent/key.pem" }
+
+            [modules.output]
+            module_type = "tcpwriter"
+            address = "localhost"
+            port = 9001
+            tls = { ca = "/nonexistent/ca.pem" }
+
+            [modules.script]
+            module_type = "hulang"
+            script_file = "/nonexistent/script.hl"
+
+            [routes.main]
+            from = { Single = "input" }
+            to = { Multiple = ["output", "script"] }
+            "#,
+        );
+
+        let paths: Vec<&str> = problems
+            .iter()
+            .map(|problem| problem.split(": ").nth(1).unwrap())
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                "modules.input.tls.certificate",
+                "modules.output.tls.ca",
+                "modules.script.script_file",
+            ],
+            "{:?}",
+            problems
+        );
+    }
+}
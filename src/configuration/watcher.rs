@@ -0,0 +1,87 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{bail, Result};
+use futures::StreamExt;
+use inotify::{Inotify, WatchMask};
+use tokio::{sync::mpsc::UnboundedSender, task::JoinHandle};
+
+use crate::plugins;
+
+use super::global_configuration::GlobalConfiguration;
+
+// Editors often save a file in several steps, so changes are only picked up
+// once the file has been left alone for this long.
+const SETTLE_DELAY: Duration = Duration::from_millis(200);
+
+/// Watches a configuration file, and sends every valid new version of it to
+/// `reloads`. Invalid versions are reported and skipped, so the configuration
+/// that is already running is kept.
+pub fn watch(
+    path: PathBuf,
+    reloads: UnboundedSender<GlobalConfiguration>,
+) -> Result<JoinHandle<()>> {
+    let Some(file_name) = path.file_name().map(|name| name.to_os_string()) else {
+        bail!("{} is not a file", path.display());
+    };
+
+    // The directory is watched rather than the file itself, as many editors
+    // replace the file instead of writing to it.
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+
+    let inotify = Inotify::init()?;
+    inotify
+        .watches()
+        .add(&directory, WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO)?;
+    let mut events = inotify.into_event_stream([0u8; 4096])?;
+
+    Ok(tokio::spawn(async move {
+        while let Some(event) = events.next().await {
+            let event = match event {
+                Ok(event) => event,
+                Err(e) => {
+                    println!("Stopped watching {}: {:?}", path.display(), e);
+                    break;
+                }
+            };
+
+            if event.name.as_ref() != Some(&file_name) {
+                continue;
+            }
+
+            while let Ok(Some(_)) = tokio::time::timeout(SETTLE_DELAY, events.next()).await {}
+
+            match reload(&path) {
+                Ok(configuration) => {
+                    println!("Reloading {}", path.display());
+                    if reloads.send(configuration).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => println!("Not reloading {}: {:#}", path.display(), e),
+            }
+        }
+    }))
+}
+
+fn reload(path: &Path) -> Result<GlobalConfiguration> {
+    let configuration = GlobalConfiguration::load(path)?;
+    plugins::load_configured(&configuration)?;
+
+    let report = configuration.validate();
+    eprint!("{}", report);
+
+    if !report.is_ok() {
+        bail!(
+            "{} is not valid, keeping the running configuration",
+            path.display()
+        );
+    }
+
+    Ok(configuration)
+}
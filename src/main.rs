@@ -1,47 +1,162 @@
-use std::fs::read_to_string;
+use std::{path::PathBuf, time::Duration};
 
 use anyhow::Result;
 use clap::Parser;
-use configuration::global_configuration::GlobalConfiguration;
-use modules::manager::Manager;
+use configuration::{global_configuration::GlobalConfiguration, watcher};
+use modules::{manager::Manager, registry::ModulesRegistry};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::mpsc,
+};
+use tokio_util::sync::CancellationToken;
 mod configuration;
 mod messaging;
 mod modules;
+mod node;
+mod plugins;
 
 #[derive(clap::Parser, Debug)]
+#[command(name = "hulaak")]
 struct Configuration {
     #[clap(
         short = 'c',
         long = "config",
         default_value = "config.toml",
+        global = true,
         help = "Path to the configuration file"
     )]
+    configuration_file: PathBuf,
 
-    configuration_file: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Validate the configuration file without starting anything.
+    Check,
+
+    /// Inspect the available modules.
+    Modules {
+        #[command(subcommand)]
+        command: ModulesCommand,
+    },
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum ModulesCommand {
+    /// List every module type, with what it can do and its settings.
+    List,
 }
 
 fn main() -> Result<()> {
-    let configuration = Configuration::parse();
-    let config_contents =
-        read_to_string(configuration.configuration_file).expect("error reading configuration toml");
+    let arguments = Configuration::parse();
+
+    if let Some(Command::Modules {
+        command: ModulesCommand::List,
+    }) = arguments.command
+    {
+        // Module types from plugins are listed too, if a configuration
+        // loading them is available.
+        if let Ok(configuration) = GlobalConfiguration::load(&arguments.configuration_file) {
+            plugins::load_configured(&configuration)?;
+        }
+
+        list_modules();
+        return Ok(());
+    }
 
     // parse the global configuration.
-    let configuration: GlobalConfiguration = toml::from_str(&config_contents)?;
+    let configuration = GlobalConfiguration::load(&arguments.configuration_file)?;
+
+    // Plugins have to be loaded first, as they provide module types.
+    plugins::load_configured(&configuration)?;
+
+    // Report every problem in the configuration before doing anything else.
+    let report = configuration.validate();
+    eprint!("{}", report);
+
+    if let Some(Command::Check) = arguments.command {
+        if !report.is_ok() {
+            std::process::exit(1);
+        }
+
+        println!("{} is valid", arguments.configuration_file.display());
+        return Ok(());
+    }
+
+    if !report.is_ok() {
+        anyhow::bail!("{} is not valid", arguments.configuration_file.display());
+    }
 
     // Start an executor for our "manager" module, and block on it.
-    tokio::runtime::Builder::new_multi_thread()
+    let runtime = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
-        .build()?
-        .block_on(async {
-            match Manager::new(configuration).run().await {
-                Ok(_) => {
-                    println!("Running manager")
-                }
-                Err(_) => {
-                    panic!("Could  not run manager module")
-                }
+        .build()?;
+
+    runtime.block_on(async {
+        let shutdown = CancellationToken::new();
+        tokio::spawn(wait_for_signal(shutdown.clone()));
+
+        // Changes to the configuration file are applied without restarting.
+        let (reloads, reloaded) = mpsc::unbounded_channel();
+        if let Err(e) = watcher::watch(arguments.configuration_file.clone(), reloads) {
+            println!(
+                "Not watching {} for changes: {:#}",
+                arguments.configuration_file.display(),
+                e
+            );
+        }
+
+        match Manager::new(configuration).run(shutdown, reloaded).await {
+            Ok(_) => {
+                println!("Running manager")
+            }
+            Err(_) => {
+                panic!("Could  not run manager module")
             }
-        });
+        }
+    });
+
+    // Modules blocking on a thread of their own cannot be aborted, so don't
+    // wait for them forever.
+    runtime.shutdown_timeout(Duration::from_secs(1));
 
     Ok(())
 }
+
+/// Cancels `shutdown` on SIGINT or SIGTERM. A second signal exits right away,
+/// without waiting for messages to drain.
+async fn wait_for_signal(shutdown: CancellationToken) {
+    let mut terminate = signal(SignalKind::terminate()).expect("Error listening for SIGTERM");
+
+    for _ in 0..2 {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+
+        if shutdown.is_cancelled() {
+            println!("Exiting without draining");
+            std::process::exit(1);
+        }
+
+        shutdown.cancel();
+    }
+}
+
+fn list_modules() {
+    for registration in ModulesRegistry::list() {
+        let capabilities = registration.capabilities;
+        let role = match (capabilities.outbox, capabilities.inbox) {
+            (true, true) => "source, sink",
+            (true, false) => "source",
+            (false, true) => "sink",
+            (false, false) => "-",
+        };
+
+        println!("{} ({})", registration.name, role);
+        println!("    {}", registration.description);
+        println!("    settings: {}", registration.settings);
+    }
+}
@@ -0,0 +1,449 @@
+use std::{
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use async_channel::{Receiver, SendError, Sender, TrySendError};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncBufReadExt, AsyncSeekExt, AsyncWriteExt, BufReader},
+    sync::{Mutex, Notify},
+};
+
+use uuid::Uuid;
+
+use crate::configuration::global_configuration::{OverflowPolicy, RouteConfiguration};
+
+use super::message::{Message, MessageSource};
+
+// Number of spilled messages read back from disk at once.
+const REFILL_BATCH: usize = 1024;
+
+/// The sending half of a route channel. It applies the route's overflow
+/// policy when the channel is full, and fills in the envelope of the messages
+/// sent through it.
+#[derive(Debug, Clone)]
+pub struct Outbox {
+    sender: Sender<Message>,
+    policy: OverflowPolicy,
+    spill: Option<Arc<Spill>>,
+    route: Option<Arc<str>>,
+    source: Option<Arc<MessageSource>>,
+}
+
+/// Creates a channel following the capacity and overflow policy of a route.
+/// `name` identifies the channel within the stage `stage_id`. Its spill file
+/// is named after both, so that stages sharing a spill directory do not
+/// overwrite each other's files.
+pub fn channel(
+    stage_id: Uuid,
+    name: &str,
+    route: &RouteConfiguration,
+) -> (Outbox, Receiver<Message>) {
+    let (sender, receiver) = match route.capacity {
+        Some(capacity) => async_channel::bounded(capacity.max(1)),
+        None => async_channel::unbounded(),
+    };
+
+    let spill = match (route.capacity, route.overflow) {
+        (Some(_), OverflowPolicy::SpillToDisk) => {
+            let directory = route.spill_path.clone().unwrap_or_else(std::env::temp_dir);
+            let file = format!("hulaak-{}-{}.spill", stage_id, name);
+            let spill = Arc::new(Spill::new(directory.join(file)));
+            tokio::spawn(Spill::refill_loop(spill.clone(), sender.clone()));
+            Some(spill)
+        }
+        _ => None,
+    };
+
+    let outbox = Outbox {
+        sender,
+        policy: route.overflow,
+        spill,
+        route: None,
+        source: None,
+    };
+
+    (outbox, receiver)
+}
+
+impl Outbox {
+    pub fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
+
+    /// Returns an outbox recording `route` in every message sent through it.
+    pub fn with_route(mut self, route: &str) -> Self {
+        self.route = Some(route.into());
+        self
+    }
+
+    /// Returns an outbox recording `module` as the source of the messages sent
+    /// through it, unless they already have one.
+    pub fn with_source(&self, module: &str, uuid: Uuid) -> Self {
+        Outbox {
+            source: Some(Arc::new(MessageSource {
+                module: module.to_string(),
+                uuid,
+            })),
+            ..self.clone()
+        }
+    }
+
+    /// Sends a message on the route. Messages dropped because of the overflow
+    /// policy still count as sent; this only fails if the route is closed.
+    pub async fn send(&self, mut message: Message) -> Result<(), SendError<Message>> {
+        if message.source.is_none() {
+            message.source = self.source.as_deref().cloned();
+        }
+
+        if let Some(route) = &self.route {
+            message.routes.push(route.to_string());
+            message.hops += 1;
+        }
+
+        match self.policy {
+            OverflowPolicy::Block => self.sender.send(message).await,
+            OverflowPolicy::DropNewest => match self.sender.try_send(message) {
+                Ok(_) | Err(TrySendError::Full(_)) => Ok(()),
+                Err(TrySendError::Closed(message)) => Err(SendError(message)),
+            },
+            OverflowPolicy::DropOldest => self.sender.force_send(message).map(|_| ()),
+            OverflowPolicy::SpillToDisk => match &self.spill {
+                Some(spill) => spill.send(&self.sender, message).await,
+                None => self.sender.send(message).await,
+            },
+        }
+    }
+}
+
+/// Messages that did not fit on a route, kept in a file on disk until the
+/// route has room for them again.
+#[derive(Debug)]
+struct Spill {
+    path: PathBuf,
+    state: Mutex<SpillState>,
+    notify: Notify,
+}
+
+#[derive(Debug, Default)]
+struct SpillState {
+    writer: Option<File>,
+    // Messages written to the file that have not been fed back yet.
+    pending: usize,
+    // Position of the first message that has not been read back yet.
+    offset: u64,
+}
+
+impl Spill {
+    fn new(path: PathBuf) -> Self {
+        Spill {
+            path,
+            state: Mutex::new(SpillState::default()),
+            notify: Notify::new(),
+        }
+    }
+
+    async fn send(
+        &self,
+        sender: &Sender<Message>,
+        message: Message,
+    ) -> Result<(), SendError<Message>> {
+        let mut state = self.state.lock().await;
+
+        // Once something has been spilled, everything after it is spilled too
+        // so that messages stay in order.
+        if state.pending == 0 {
+            match sender.try_send(message) {
+                Ok(_) => return Ok(()),
+                Err(TrySendError::Closed(message)) => return Err(SendError(message)),
+                Err(TrySendError::Full(returned)) => {
+                    return match self.write(&mut state, &returned).await {
+                        Ok(_) => Ok(()),
+                        Err(e) => {
+                            println!("Error spilling message to {:?}: {:?}", self.path, e);
+                            drop(state);
+                            sender.send(returned).await
+                        }
+                    };
+                }
+            }
+        }
+
+        if let Err(e) = self.write(&mut state, &message).await {
+            println!("Error spilling message to {:?}: {:?}", self.path, e);
+            drop(state);
+            return sender.send(message).await;
+        }
+
+        Ok(())
+    }
+
+    async fn write(&self, state: &mut SpillState, message: &Message) -> std::io::Result<()> {
+        if state.writer.is_none() {
+            state.writer = Some(open_spill_file(&self.path).await?);
+        }
+
+        let mut line = serde_json::to_vec(message)?;
+        line.push(b'\n');
+
+        // SAFETY: safe to unwrap - opened above.
+        let writer = state.writer.as_mut().unwrap();
+        writer.write_all(&line).await?;
+        writer.flush().await?;
+
+        state.pending += 1;
+        self.notify.notify_one();
+        Ok(())
+    }
+
+    /// Feeds spilled messages back into the route. Keeps going until every
+    /// outbox for the route is gone and nothing is left on disk.
+    async fn refill_loop(spill: Arc<Spill>, sender: Sender<Message>) {
+        loop {
+            let _ = tokio::time::timeout(Duration::from_millis(250), spill.notify.notified()).await;
+
+            match spill.refill(&sender).await {
+                Ok(true) if Arc::strong_count(&spill) == 1 => break,
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => {
+                    println!(
+                        "Error reading spilled messages from {:?}: {:?}",
+                        spill.path, e
+                    );
+                    break;
+                }
+            }
+        }
+
+        let _ = tokio::fs::remove_file(&spill.path).await;
+    }
+
+    /// Sends spilled messages until the file is drained. Returns true if
+    /// nothing is left on disk, and false if the route closed first.
+    async fn refill(&self, sender: &Sender<Message>) -> std::io::Result<bool> {
+        loop {
+            let batch = {
+                let mut state = self.state.lock().await;
+                if state.pending == 0 {
+                    if let Some(writer) = state.writer.as_mut() {
+                        writer.set_len(0).await?;
+                        writer.seek(SeekFrom::Start(0)).await?;
+                    }
+                    state.offset = 0;
+                    return Ok(true);
+                }
+
+                let batch = self.read(&mut state).await?;
+                if batch.is_empty() && state.pending > 0 {
+                    println!(
+                        "Lost {} spilled messages from {:?}",
+                        state.pending, self.path
+                    );
+                    state.pending = 0;
+                }
+
+                batch
+            };
+
+            for message in batch {
+                if sender.send(message).await.is_err() {
+                    return Ok(false);
+                }
+                self.state.lock().await.pending -= 1;
+            }
+        }
+    }
+
+    async fn read(&self, state: &mut SpillState) -> std::io::Result<Vec<Message>> {
+        let mut file = File::open(&self.path).await?;
+        file.seek(SeekFrom::Start(state.offset)).await?;
+
+        let mut reader = BufReader::new(file);
+        let mut batch = vec![];
+        let mut line = String::new();
+
+        while batch.len() < REFILL_BATCH.min(state.pending) {
+            line.clear();
+            let size = reader.read_line(&mut line).await?;
+            if size == 0 {
+                break;
+            }
+
+            state.offset += size as u64;
+            match serde_json::from_str(&line) {
+                Ok(message) => batch.push(message),
+                Err(e) => {
+                    println!("Discarding unreadable spilled message: {:?}", e);
+                    state.pending -= 1;
+                }
+            }
+        }
+
+        Ok(batch)
+    }
+}
+
+async fn open_spill_file(path: &Path) -> std::io::Result<File> {
+    if let Some(directory) = path.parent() {
+        tokio::fs::create_dir_all(directory).await?;
+    }
+
+    OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .write(true)
+        .open(path)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use tokio::time::{sleep, timeout};
+
+    use super::*;
+
+    fn route(overflow: &str, spill_path: &Path) -> RouteConfiguration {
+        toml::from_str(&format!(
+            "from = {{ Single = \"a\" }}\nto = {{ Single = \"b\" }}\ncapacity = 2\noverflow = {:?}\nspill_path = {:?}",
+            overflow, spill_path
+        ))
+        .unwrap()
+    }
+
+    fn message(n: usize) -> Message {
+        Message::new(HashMap::from([("n".to_string(), n.into())]))
+    }
+
+    fn n(message: Message) -> usize {
+        message.fields["n"].as_u64().unwrap() as usize
+    }
+
+    // A channel for `overflow`, along with the directory it spills to.
+    fn test_channel(overflow: &str) -> (Outbox, Receiver<Message>, PathBuf) {
+        let directory = std::env::temp_dir().join(format!("hulaak-test-{}", Uuid::new_v4()));
+        let (outbox, receiver) = channel(Uuid::new_v4(), "test", &route(overflow, &directory));
+        (outbox, receiver, directory)
+    }
+
+    // Waits up to a few seconds for `condition` to hold.
+    async fn eventually(condition: impl Fn() -> bool) {
+        timeout(Duration::from_secs(5), async {
+            while !condition() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("condition never held");
+    }
+
+    #[tokio::test]
+    async fn block_waits_for_room() {
+        let (outbox, receiver, _) = test_channel("block");
+        outbox.send(message(0)).await.unwrap();
+        outbox.send(message(1)).await.unwrap();
+
+        let blocked = outbox.send(message(2));
+        tokio::pin!(blocked);
+        assert!(timeout(Duration::from_millis(50), &mut blocked)
+            .await
+            .is_err());
+
+        assert_eq!(n(receiver.recv().await.unwrap()), 0);
+        blocked.await.unwrap();
+        assert_eq!(n(receiver.recv().await.unwrap()), 1);
+        assert_eq!(n(receiver.recv().await.unwrap()), 2);
+    }
+
+    #[tokio::test]
+    async fn drop_newest_discards_what_does_not_fit() {
+        let (outbox, receiver, _) = test_channel("drop_newest");
+        for i in 0..4 {
+            outbox.send(message(i)).await.unwrap();
+        }
+
+        assert_eq!(n(receiver.recv().await.unwrap()), 0);
+        assert_eq!(n(receiver.recv().await.unwrap()), 1);
+        assert!(receiver.is_empty());
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_makes_room_for_the_newest() {
+        let (outbox, receiver, _) = test_channel("drop_oldest");
+        for i in 0..4 {
+            outbox.send(message(i)).await.unwrap();
+        }
+
+        assert_eq!(n(receiver.recv().await.unwrap()), 2);
+        assert_eq!(n(receiver.recv().await.unwrap()), 3);
+        assert!(receiver.is_empty());
+    }
+
+    #[tokio::test]
+    async fn sending_fails_once_the_route_is_closed() {
+        for overflow in ["block", "drop_newest", "drop_oldest", "spill_to_disk"] {
+            let (outbox, receiver, _) = test_channel(overflow);
+            drop(receiver);
+
+            assert!(outbox.send(message(0)).await.is_err(), "{}", overflow);
+        }
+    }
+
+    #[tokio::test]
+    async fn spill_to_disk_keeps_messages_in_order() {
+        let (outbox, receiver, directory) = test_channel("spill_to_disk");
+
+        for i in 0..5 {
+            outbox.send(message(i)).await.unwrap();
+        }
+        let mut received = vec![];
+        for _ in 0..3 {
+            received.push(n(receiver.recv().await.unwrap()));
+        }
+        for i in 5..10 {
+            outbox.send(message(i)).await.unwrap();
+        }
+        while received.len() < 10 {
+            received.push(n(receiver.recv().await.unwrap()));
+        }
+
+        assert_eq!(received, (0..10).collect::<Vec<usize>>());
+
+        drop(outbox);
+        let _ = std::fs::remove_dir_all(directory);
+    }
+
+    #[tokio::test]
+    async fn spill_file_is_emptied_once_drained_and_removed_once_the_route_is_gone() {
+        let (outbox, receiver, directory) = test_channel("spill_to_disk");
+
+        for i in 0..10 {
+            outbox.send(message(i)).await.unwrap();
+        }
+        let file = std::fs::read_dir(&directory)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        assert!(std::fs::metadata(&file).unwrap().len() > 0);
+
+        for i in 0..10 {
+            assert_eq!(n(receiver.recv().await.unwrap()), i);
+        }
+        eventually(|| std::fs::metadata(&file).is_ok_and(|metadata| metadata.len() == 0)).await;
+
+        // The refill loop holds the last sender, so the route only closes once
+        // it is done.
+        drop(outbox);
+        eventually(|| !file.exists()).await;
+        let closed = timeout(Duration::from_secs(5), receiver.recv()).await;
+        assert!(closed.unwrap().is_err());
+        let _ = std::fs::remove_dir_all(directory);
+    }
+}
@@ -0,0 +1,492 @@
+use std::io;
+
+use serde::{Deserialize, Serialize};
+use tokio_util::{
+    bytes::{Buf, BytesMut},
+    codec::Decoder,
+};
+
+/// How messages are told apart from each other on a byte stream. Shared by
+/// every module reading or writing streams, so that what one writes the
+/// other can read.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Framing {
+    /// Every message is followed by a newline. A carriage return before the
+    /// newline is dropped when reading.
+    #[default]
+    Newline,
+
+    /// Every message is preceded by its length, as a big endian u16.
+    LengthPrefixedU16,
+
+    /// Every message is preceded by its length, as a big endian u32.
+    LengthPrefixedU32,
+
+    /// Every message is followed by the given bytes.
+    Delimiter(Vec<u8>),
+
+    /// Syslog octet counting (RFC 6587): every message is preceded by its
+    /// length in ASCII digits and a space. Messages that do not start with a
+    /// digit are read up to the next newline instead, as many senders mix
+    /// both.
+    OctetCounted,
+
+    /// Messages are written one after the other, as they are. When reading,
+    /// whatever arrives at once is a message.
+    Raw,
+}
+
+impl TryFrom<String> for Framing {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.as_str() {
+            "newline" => Ok(Framing::Newline),
+            "length_prefixed_u16" => Ok(Framing::LengthPrefixedU16),
+            "length_prefixed_u32" => Ok(Framing::LengthPrefixedU32),
+            "octet_counted" => Ok(Framing::OctetCounted),
+            "raw" => Ok(Framing::Raw),
+            _ => match value
+                .strip_prefix("delimiter(")
+                .and_then(|rest| rest.strip_suffix(')'))
+            {
+                Some(delimiter) if !delimiter.is_empty() => {
+                    Ok(Framing::Delimiter(delimiter.as_bytes().to_vec()))
+                }
+                _ => Err(format!(
+                    "unknown framing {:?}, expected one of \"newline\", \"length_prefixed_u16\", \"length_prefixed_u32\", \"delimiter(...)\", \"octet_counted\" or \"raw\"",
+                    value
+                )),
+            },
+        }
+    }
+}
+
+impl From<Framing> for String {
+    fn from(value: Framing) -> Self {
+        match value {
+            Framing::Newline => "newline".into(),
+            Framing::LengthPrefixedU16 => "length_prefixed_u16".into(),
+            Framing::LengthPrefixedU32 => "length_prefixed_u32".into(),
+            Framing::Delimiter(delimiter) => {
+                format!("delimiter({})", String::from_utf8_lossy(&delimiter))
+            }
+            Framing::OctetCounted => "octet_counted".into(),
+            Framing::Raw => "raw".into(),
+        }
+    }
+}
+
+impl Framing {
+    /// Appends `payload` to `frame`, framed. Fails if the payload is too
+    /// long for the length prefix.
+    pub fn encode(&self, payload: &[u8], frame: &mut Vec<u8>) -> Result<(), String> {
+        match self {
+            Framing::Newline => {
+                frame.extend_from_slice(payload);
+                frame.push(b'\n');
+            }
+            Framing::LengthPrefixedU16 => {
+                let length = u16::try_from(payload.len())
+                    .map_err(|_| too_long(payload.len(), u16::MAX as usize))?;
+                frame.extend_from_slice(&length.to_be_bytes());
+                frame.extend_from_slice(payload);
+            }
+            Framing::LengthPrefixedU32 => {
+                let length = u32::try_from(payload.len())
+                    .map_err(|_| too_long(payload.len(), u32::MAX as usize))?;
+                frame.extend_from_slice(&length.to_be_bytes());
+                frame.extend_from_slice(payload);
+            }
+            Framing::Delimiter(delimiter) => {
+                frame.extend_from_slice(payload);
+                frame.extend_from_slice(delimiter);
+            }
+            Framing::OctetCounted => {
+                frame.extend_from_slice(format!("{} ", payload.len()).as_bytes());
+                frame.extend_from_slice(payload);
+            }
+            Framing::Raw => frame.extend_from_slice(payload),
+        }
+
+        Ok(())
+    }
+
+    /// A decoder splitting a stream into frames no longer than `max_length`.
+    pub fn decoder(&self, max_length: usize) -> FrameDecoder {
+        FrameDecoder {
+            framing: self.clone(),
+            max_length,
+            discarding: false,
+        }
+    }
+}
+
+fn too_long(length: usize, limit: usize) -> String {
+    format!(
+        "message of {} bytes does not fit in a frame of at most {} bytes",
+        length, limit
+    )
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Splits a byte stream into frames, keeping partial frames until the rest
+/// of them arrives.
+///
+/// Delimited frames that are too long are skipped up to the next delimiter.
+/// Length prefixed frames that are too long leave no way to find the next
+/// frame, so they end the stream with an error.
+#[derive(Debug)]
+pub struct FrameDecoder {
+    framing: Framing,
+    max_length: usize,
+    // Skipping the rest of a delimited frame that was too long.
+    discarding: bool,
+}
+
+impl FrameDecoder {
+    fn decode_delimited(&mut self, buffer: &mut BytesMut, delimiter: &[u8]) -> Option<Vec<u8>> {
+        loop {
+            if let Some(frame) = self.next_delimited(buffer, delimiter)? {
+                return Some(frame);
+            }
+        }
+    }
+
+    // Takes the next frame up to `delimiter`, if it is complete. A frame that
+    // was too long comes out as `Some(None)` once it has been skipped.
+    fn next_delimited(
+        &mut self,
+        buffer: &mut BytesMut,
+        delimiter: &[u8],
+    ) -> Option<Option<Vec<u8>>> {
+        let Some(start) = buffer
+            .windows(delimiter.len())
+            .position(|window| window == delimiter)
+        else {
+            if buffer.len() > self.max_length {
+                if !self.discarding {
+                    println!("Skipping a frame longer than {} bytes", self.max_length);
+                }
+
+                // Keep what could be the start of the delimiter.
+                let keep = delimiter.len() - 1;
+                buffer.advance(buffer.len().saturating_sub(keep));
+                self.discarding = true;
+            }
+
+            return None;
+        };
+
+        let frame = buffer.split_to(start).to_vec();
+        buffer.advance(delimiter.len());
+
+        if self.discarding || frame.len() > self.max_length {
+            self.discarding = false;
+            return Some(None);
+        }
+
+        Some(Some(frame))
+    }
+
+    fn decode_length_prefixed(
+        &self,
+        buffer: &mut BytesMut,
+        prefix: usize,
+    ) -> io::Result<Option<Vec<u8>>> {
+        if buffer.len() < prefix {
+            return Ok(None);
+        }
+
+        let length = match prefix {
+            2 => u16::from_be_bytes([buffer[0], buffer[1]]) as usize,
+            _ => u32::from_be_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]) as usize,
+        };
+
+        if length > self.max_length {
+            return Err(invalid_data(too_long(length, self.max_length)));
+        }
+
+        if buffer.len() < prefix + length {
+            buffer.reserve(prefix + length - buffer.len());
+            return Ok(None);
+        }
+
+        buffer.advance(prefix);
+        Ok(Some(buffer.split_to(length).to_vec()))
+    }
+
+    fn decode_octet_counted(&mut self, buffer: &mut BytesMut) -> io::Result<Option<Vec<u8>>> {
+        // Some senders end octet counted frames with a newline anyway.
+        while !self.discarding && matches!(buffer.first(), Some(b'\r' | b'\n')) {
+            buffer.advance(1);
+        }
+
+        if self.discarding || buffer.first().is_some_and(|byte| !byte.is_ascii_digit()) {
+            // What follows a line that was skipped may be counted again.
+            return match self.next_delimited(buffer, b"\n") {
+                Some(None) => self.decode_octet_counted(buffer),
+                frame => Ok(frame.flatten()),
+            };
+        }
+
+        let Some(space) = buffer.iter().position(|byte| *byte == b' ') else {
+            if buffer.len() > 10 {
+                return Err(invalid_data("octet count is missing its space".into()));
+            }
+            return Ok(None);
+        };
+
+        let length: usize = std::str::from_utf8(&buffer[..space])
+            .ok()
+            .and_then(|digits| digits.parse().ok())
+            .ok_or_else(|| invalid_data("invalid octet count".into()))?;
+
+        if length > self.max_length {
+            return Err(invalid_data(too_long(length, self.max_length)));
+        }
+
+        if buffer.len() < space + 1 + length {
+            return Ok(None);
+        }
+
+        buffer.advance(space + 1);
+        Ok(Some(buffer.split_to(length).to_vec()))
+    }
+}
+
+impl Decoder for FrameDecoder {
+    type Item = Vec<u8>;
+    type Error = io::Error;
+
+    fn decode(&mut self, buffer: &mut BytesMut) -> io::Result<Option<Vec<u8>>> {
+        match self.framing.clone() {
+            Framing::Newline => {
+                let mut frame = self.decode_delimited(buffer, b"\n");
+                if let Some(frame) = frame.as_mut() {
+                    if frame.last() == Some(&b'\r') {
+                        frame.pop();
+                    }
+                }
+                Ok(frame)
+            }
+            Framing::Delimiter(delimiter) => Ok(self.decode_delimited(buffer, &delimiter)),
+            Framing::LengthPrefixedU16 => self.decode_length_prefixed(buffer, 2),
+            Framing::LengthPrefixedU32 => self.decode_length_prefixed(buffer, 4),
+            Framing::OctetCounted => self.decode_octet_counted(buffer),
+            Framing::Raw => match buffer.is_empty() {
+                true => Ok(None),
+                false => {
+                    let length = buffer.len().min(self.max_length);
+                    Ok(Some(buffer.split_to(length).to_vec()))
+                }
+            },
+        }
+    }
+
+    fn decode_eof(&mut self, buffer: &mut BytesMut) -> io::Result<Option<Vec<u8>>> {
+        if let Some(frame) = self.decode(buffer)? {
+            return Ok(Some(frame));
+        }
+
+        if buffer.is_empty() {
+            return Ok(None);
+        }
+
+        // The last delimited frame does not need its delimiter. Anything else
+        // left over is an incomplete frame.
+        let delimited = matches!(self.framing, Framing::Newline | Framing::Delimiter(_))
+            || (self.framing == Framing::OctetCounted && !buffer[0].is_ascii_digit());
+
+        if delimited && !self.discarding && buffer.len() <= self.max_length {
+            let mut frame = buffer.split().to_vec();
+            if self.framing == Framing::Newline && frame.last() == Some(&b'\r') {
+                frame.pop();
+            }
+            return Ok(Some(frame));
+        }
+
+        println!(
+            "Discarding {} bytes of an incomplete frame at the end of the stream",
+            buffer.len()
+        );
+        buffer.clear();
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Feeds `chunks` to a decoder one after the other, as if each was read
+    // separately, then ends the stream.
+    fn decode(framing: &Framing, max_length: usize, chunks: &[&[u8]]) -> io::Result<Vec<String>> {
+        let mut decoder = framing.decoder(max_length);
+        let mut buffer = BytesMut::new();
+        let mut frames = vec![];
+
+        for chunk in chunks {
+            buffer.extend_from_slice(chunk);
+            while let Some(frame) = decoder.decode(&mut buffer)? {
+                frames.push(String::from_utf8(frame).unwrap());
+            }
+        }
+
+        while let Some(frame) = decoder.decode_eof(&mut buffer)? {
+            frames.push(String::from_utf8(frame).unwrap());
+        }
+
+        Ok(frames)
+    }
+
+    fn framings() -> Vec<Framing> {
+        vec![
+            Framing::Newline,
+            Framing::LengthPrefixedU16,
+            Framing::LengthPrefixedU32,
+            Framing::Delimiter(b"||".to_vec()),
+            Framing::OctetCounted,
+        ]
+    }
+
+    #[test]
+    fn frames_split_over_several_reads_are_put_back_together() {
+        for framing in framings() {
+            let mut stream = vec![];
+            for payload in ["hello", "", "world"] {
+                framing.encode(payload.as_bytes(), &mut stream).unwrap();
+            }
+            let chunks: Vec<&[u8]> = stream.chunks(1).collect();
+
+            assert_eq!(
+                decode(&framing, 1024, &chunks).unwrap(),
+                vec!["hello", "", "world"],
+                "{:?}",
+                framing
+            );
+        }
+    }
+
+    #[test]
+    fn delimited_frames_that_are_too_long_are_skipped_up_to_the_next_delimiter() {
+        assert_eq!(
+            decode(&Framing::Newline, 4, &[b"ok\nway too", b" long\nnext\n"]).unwrap(),
+            vec!["ok", "next"]
+        );
+
+        // The delimiter may itself be split between reads while skipping.
+        let delimiter = Framing::Delimiter(b"||".to_vec());
+        assert_eq!(
+            decode(&delimiter, 4, &[b"too long|", b"|next||"]).unwrap(),
+            vec!["next"]
+        );
+
+        // Lines mixed into an octet counted stream are skipped the same way.
+        assert_eq!(
+            decode(&Framing::OctetCounted, 4, &[b"a long line\n4 next"]).unwrap(),
+            vec!["next"]
+        );
+    }
+
+    #[test]
+    fn carriage_returns_before_newlines_are_dropped() {
+        assert_eq!(
+            decode(&Framing::Newline, 1024, &[b"a\r", b"\nb\rc\r\nlast\r"]).unwrap(),
+            vec!["a", "b\rc", "last"]
+        );
+
+        // Other delimiters keep them.
+        let delimiter = Framing::Delimiter(b";".to_vec());
+        assert_eq!(
+            decode(&delimiter, 1024, &[b"a\r;b\r\n;"]).unwrap(),
+            vec!["a\r", "b\r\n"]
+        );
+
+        // Octet counted frames may be followed by a newline anyway.
+        assert_eq!(
+            decode(&Framing::OctetCounted, 1024, &[b"3 abc\r\n", b"\n3 def"]).unwrap(),
+            vec!["abc", "def"]
+        );
+    }
+
+    #[test]
+    fn counted_frames_that_are_too_long_end_the_stream() {
+        for (framing, stream) in [
+            (Framing::LengthPrefixedU16, &b"\x00\x05hello"[..]),
+            (Framing::LengthPrefixedU32, &b"\x00\x00\x00\x05hello"[..]),
+            (Framing::OctetCounted, &b"5 hello"[..]),
+        ] {
+            let error = decode(&framing, 4, &[stream]).unwrap_err();
+
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{:?}", framing);
+            assert_eq!(
+                error.to_string(),
+                "message of 5 bytes does not fit in a frame of at most 4 bytes"
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_octet_counts_end_the_stream() {
+        let error = decode(&Framing::OctetCounted, 1024, &[b"12a hello"]).unwrap_err();
+        assert_eq!(error.to_string(), "invalid octet count");
+
+        let error = decode(&Framing::OctetCounted, 1024, &[b"12345678901"]).unwrap_err();
+        assert_eq!(error.to_string(), "octet count is missing its space");
+    }
+
+    #[test]
+    fn the_last_delimited_frame_does_not_need_its_delimiter() {
+        assert_eq!(
+            decode(&Framing::Newline, 1024, &[b"a\nlast"]).unwrap(),
+            vec!["a", "last"]
+        );
+        assert_eq!(
+            decode(&Framing::Delimiter(b"||".to_vec()), 1024, &[b"a||last|"]).unwrap(),
+            vec!["a", "last|"]
+        );
+        assert_eq!(
+            decode(&Framing::OctetCounted, 1024, &[b"1 a", b"last"]).unwrap(),
+            vec!["a", "last"]
+        );
+
+        // Unless it is too long.
+        assert_eq!(
+            decode(&Framing::Newline, 4, &[b"a\ntoo long"]).unwrap(),
+            vec!["a"]
+        );
+    }
+
+    #[test]
+    fn incomplete_counted_frames_at_the_end_are_discarded() {
+        for (framing, stream) in [
+            (Framing::LengthPrefixedU16, &b"\x00\x01a\x00\x05he"[..]),
+            (
+                Framing::LengthPrefixedU32,
+                &b"\x00\x00\x00\x01a\x00\x00"[..],
+            ),
+            (Framing::OctetCounted, &b"1 a5 he"[..]),
+            (Framing::OctetCounted, &b"1 a12"[..]),
+        ] {
+            assert_eq!(
+                decode(&framing, 1024, &[stream]).unwrap(),
+                vec!["a"],
+                "{:?}",
+                framing
+            );
+        }
+    }
+
+    #[test]
+    fn raw_frames_are_whatever_arrives_at_once() {
+        assert_eq!(
+            decode(&Framing::Raw, 4, &[b"abc", b"defghi"]).unwrap(),
+            vec!["abc", "defg", "hi"]
+        );
+    }
+}
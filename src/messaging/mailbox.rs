@@ -0,0 +1,163 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use async_channel::{Receiver, RecvError, SendError, TryRecvError};
+use futures::future::select_all;
+
+use super::{channel::Outbox, message::Message};
+
+/// The outputs of a module, keyed by the name of the route they feed.
+#[derive(Debug, Default, Clone)]
+pub struct Outboxes {
+    senders: HashMap<String, Outbox>,
+}
+
+impl Outboxes {
+    pub fn insert(&mut self, name: String, sender: Outbox) {
+        self.senders.insert(name, sender);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.senders.is_empty()
+    }
+
+    /// Sends a copy of the message to every outbox. Fails only if there are
+    /// outboxes and all of them are closed.
+    pub async fn send(&self, message: Message) -> Result<(), SendError<Message>> {
+        if self.senders.is_empty() {
+            return Ok(());
+        }
+
+        let mut delivered = false;
+        for sender in self.senders.values() {
+            if sender.send(message.clone()).await.is_ok() {
+                delivered = true;
+            }
+        }
+
+        if delivered {
+            Ok(())
+        } else {
+            Err(SendError(message))
+        }
+    }
+}
+
+/// The inputs of a module, keyed by the name of the route they are fed by.
+#[derive(Debug, Default, Clone)]
+pub struct Inboxes {
+    receivers: HashMap<String, Receiver<Message>>,
+
+    // Inbox to look at first on the next receive. It moves on every time,
+    // so that a busy route cannot starve the others.
+    next: Arc<AtomicUsize>,
+}
+
+impl Inboxes {
+    pub fn insert(&mut self, name: String, receiver: Receiver<Message>) {
+        self.receivers.insert(name, receiver);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.receivers.is_empty()
+    }
+
+    // The inboxes, starting with the one whose turn it is to go first.
+    fn in_turn(&self) -> Vec<&Receiver<Message>> {
+        let mut receivers: Vec<&Receiver<Message>> = self.receivers.values().collect();
+        if !receivers.is_empty() {
+            let first = self.next.fetch_add(1, Ordering::Relaxed) % receivers.len();
+            receivers.rotate_left(first);
+        }
+        receivers
+    }
+
+    /// Receives the next message from any of the inboxes, taking turns
+    /// between those that have one ready. Fails once all of them are closed
+    /// and drained.
+    pub async fn recv(&self) -> Result<Message, RecvError> {
+        let mut open = self.in_turn();
+
+        while !open.is_empty() {
+            let (result, index, _) =
+                select_all(open.iter().map(|receiver| Box::pin(receiver.recv()))).await;
+
+            match result {
+                Ok(message) => return Ok(message),
+                Err(RecvError) => {
+                    open.remove(index);
+                }
+            }
+        }
+
+        Err(RecvError)
+    }
+
+    /// Takes a message from an inbox that has one ready, taking turns like
+    /// `recv`.
+    pub fn try_recv(&self) -> Result<Message, TryRecvError> {
+        let mut result = Err(TryRecvError::Closed);
+        for receiver in self.in_turn() {
+            match receiver.try_recv() {
+                Ok(message) => return Ok(message),
+                Err(TryRecvError::Empty) => result = Err(TryRecvError::Empty),
+                Err(TryRecvError::Closed) => {}
+            }
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(inboxes: &mut Inboxes, name: &str) -> async_channel::Sender<Message> {
+        let (sender, receiver) = async_channel::unbounded();
+        inboxes.insert(name.to_string(), receiver);
+        sender
+    }
+
+    fn message(route: &str) -> Message {
+        Message::new(HashMap::from([("route".to_string(), route.into())]))
+    }
+
+    #[tokio::test]
+    async fn busy_inbox_does_not_starve_the_others() {
+        let mut inboxes = Inboxes::default();
+        let busy = route(&mut inboxes, "busy");
+        let quiet = route(&mut inboxes, "quiet");
+
+        for _ in 0..100 {
+            busy.send(message("busy")).await.unwrap();
+        }
+        quiet.send(message("quiet")).await.unwrap();
+
+        let mut received = vec![];
+        for _ in 0..2 {
+            received.push(inboxes.recv().await.unwrap().fields["route"].clone());
+        }
+        assert!(received.contains(&"quiet".into()));
+    }
+
+    #[tokio::test]
+    async fn recv_fails_once_all_inboxes_are_closed_and_drained() {
+        let mut inboxes = Inboxes::default();
+        let first = route(&mut inboxes, "first");
+        let second = route(&mut inboxes, "second");
+
+        first.send(message("first")).await.unwrap();
+        drop(first);
+        drop(second);
+
+        assert!(inboxes.recv().await.is_ok());
+        assert!(inboxes.recv().await.is_err());
+        assert!(matches!(inboxes.try_recv(), Err(TryRecvError::Closed)));
+    }
+}
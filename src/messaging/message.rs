@@ -1,15 +1,73 @@
 #![allow(unused)]
 
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::collections::HashMap;
+use uuid::Uuid;
 
-#[derive(Debug)]
+/// A message travelling through hulaak. The payload is in `fields`; the rest
+/// is an envelope describing where the message comes from and where it has
+/// been. Everything but the payload has a default, so messages from plugins
+/// only need to carry `fields`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message {
+    #[serde(default = "Uuid::new_v4")]
+    pub id: Uuid,
+
+    // When the message entered hulaak.
+    #[serde(default = "Utc::now")]
+    pub ingested_at: DateTime<Utc>,
+
+    // The module that produced the message, filled in when it is first sent.
+    #[serde(default)]
+    pub source: Option<MessageSource>,
+
+    // Names of the routes the message went through, in order.
+    #[serde(default)]
+    pub routes: Vec<String>,
+
+    // Metadata about the message, kept apart from its payload.
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+
+    // Number of routes the message went through.
+    #[serde(default)]
+    pub hops: u32,
+
     pub(crate) fields: HashMap<String, Value>,
+
+    // Raw payload, for data that is not JSON. Sinks that write bytes out use
+    // it as is when it is set.
+    #[serde(default, with = "serde_bytes", skip_serializing_if = "Option::is_none")]
+    pub body: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessageSource {
+    pub module: String,
+    pub uuid: Uuid,
 }
 
 impl Message {
     pub fn new(fields: HashMap<String, Value>) -> Self {
-        Message { fields }
+        Message {
+            id: Uuid::new_v4(),
+            ingested_at: Utc::now(),
+            source: None,
+            routes: vec![],
+            headers: HashMap::new(),
+            hops: 0,
+            fields,
+            body: None,
+        }
+    }
+
+    /// Creates a message carrying raw bytes, with no fields.
+    pub fn from_body(body: Vec<u8>) -> Self {
+        Message {
+            body: Some(body),
+            ..Message::new(HashMap::new())
+        }
     }
 }
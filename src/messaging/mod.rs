@@ -1 +1,6 @@
+pub mod channel;
+pub mod framing;
+pub mod mailbox;
 pub mod message;
+pub mod parser;
+pub mod serialization;
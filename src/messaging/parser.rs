@@ -0,0 +1,312 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::message::Message;
+
+/// Turns raw bytes read by a source into message fields.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Parser {
+    /// One message per line, with the line in `data`.
+    Lines,
+
+    /// The bytes are a JSON document. An object becomes the message fields,
+    /// anything else ends up in `data`.
+    Json,
+
+    /// A syslog message, in either RFC 5424 or the older RFC 3164 format.
+    Syslog,
+}
+
+impl Parser {
+    /// Parses `bytes` into messages. Bytes that cannot be parsed are sent on
+    /// as the body of a single message, so nothing is lost.
+    pub fn parse(&self, bytes: &[u8]) -> Vec<Message> {
+        let parsed = match self {
+            Parser::Lines => Some(parse_lines(bytes)),
+            Parser::Json => parse_json(bytes).map(|fields| vec![fields]),
+            Parser::Syslog => std::str::from_utf8(bytes)
+                .ok()
+                .and_then(parse_syslog)
+                .map(|fields| vec![fields]),
+        };
+
+        match parsed {
+            Some(messages) => messages.into_iter().map(Message::new).collect(),
+            None => {
+                println!("Could not parse message as {:?}, sending it as is", self);
+                vec![Message::from_body(bytes.to_vec())]
+            }
+        }
+    }
+}
+
+fn parse_lines(bytes: &[u8]) -> Vec<HashMap<String, Value>> {
+    String::from_utf8_lossy(bytes)
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| HashMap::from([("data".to_string(), Value::from(line))]))
+        .collect()
+}
+
+fn parse_json(bytes: &[u8]) -> Option<HashMap<String, Value>> {
+    match serde_json::from_slice(bytes).ok()? {
+        Value::Object(object) => Some(object.into_iter().collect()),
+        value => Some(HashMap::from([("data".to_string(), value)])),
+    }
+}
+
+/// Parses a syslog message into `facility`, `severity`, `timestamp`,
+/// `hostname`, `app_name`, `procid`, `msgid`, `structured_data` and
+/// `message` fields. Fields missing from the message are left out.
+fn parse_syslog(text: &str) -> Option<HashMap<String, Value>> {
+    let text = text.trim_end_matches(['\r', '\n', '\0']);
+    let rest = text.strip_prefix('<')?;
+    let end = rest.find('>')?;
+    let priority: u8 = rest[..end].parse().ok()?;
+    if priority > 191 {
+        return None;
+    }
+
+    let mut fields = HashMap::new();
+    fields.insert("facility".to_string(), Value::from(priority / 8));
+    fields.insert("severity".to_string(), Value::from(priority % 8));
+
+    let rest = &rest[end + 1..];
+    match rest.strip_prefix("1 ") {
+        Some(rest) => parse_rfc5424(rest, &mut fields)?,
+        None => parse_rfc3164(rest, &mut fields),
+    }
+
+    Some(fields)
+}
+
+// TIMESTAMP HOSTNAME APP-NAME PROCID MSGID STRUCTURED-DATA MSG
+fn parse_rfc5424(text: &str, fields: &mut HashMap<String, Value>) -> Option<()> {
+    let mut rest = text;
+    for name in ["timestamp", "hostname", "app_name", "procid", "msgid"] {
+        let (value, remaining) = rest.split_once(' ')?;
+        if value != "-" {
+            fields.insert(name.to_string(), Value::from(value));
+        }
+        rest = remaining;
+    }
+
+    let (structured_data, message) = split_structured_data(rest)?;
+    if structured_data != "-" {
+        fields.insert("structured_data".to_string(), Value::from(structured_data));
+    }
+
+    let message = message.trim_start_matches('\u{feff}');
+    if !message.is_empty() {
+        fields.insert("message".to_string(), Value::from(message));
+    }
+
+    Some(())
+}
+
+// Splits off the structured data, either "-" or a run of "[...]" elements
+// that may contain escaped "]" characters.
+fn split_structured_data(text: &str) -> Option<(&str, &str)> {
+    if let Some(message) = text.strip_prefix('-') {
+        return Some(("-", message.strip_prefix(' ').unwrap_or(message)));
+    }
+
+    let mut end = 0;
+    let bytes = text.as_bytes();
+    while bytes.get(end) == Some(&b'[') {
+        let mut escaped = false;
+        loop {
+            end += 1;
+            match bytes.get(end)? {
+                b'\\' if !escaped => escaped = true,
+                b']' if !escaped => break,
+                _ => escaped = false,
+            }
+        }
+        end += 1;
+    }
+
+    if end == 0 {
+        return None;
+    }
+
+    let message = &text[end..];
+    Some((&text[..end], message.strip_prefix(' ').unwrap_or(message)))
+}
+
+// Mmm dd hh:mm:ss HOSTNAME TAG[PID]: MSG, where everything but the message
+// is optional in practice.
+fn parse_rfc3164(text: &str, fields: &mut HashMap<String, Value>) {
+    let mut rest = text;
+
+    let bytes = rest.as_bytes();
+    if bytes.len() > 16 && bytes[3] == b' ' && bytes[6] == b' ' && bytes[15] == b' ' {
+        fields.insert("timestamp".to_string(), Value::from(&rest[..15]));
+        rest = &rest[16..];
+
+        if let Some((hostname, remaining)) = rest.split_once(' ') {
+            fields.insert("hostname".to_string(), Value::from(hostname));
+            rest = remaining;
+        }
+    }
+
+    if let Some((tag, message)) = rest.split_once(": ") {
+        if !tag.is_empty() && !tag.contains(' ') {
+            let (app_name, procid) = match tag.split_once('[') {
+                Some((app_name, procid)) => (app_name, procid.strip_suffix(']')),
+                None => (tag, None),
+            };
+
+            fields.insert("app_name".to_string(), Value::from(app_name));
+            if let Some(procid) = procid {
+                fields.insert("procid".to_string(), Value::from(procid));
+            }
+            rest = message;
+        }
+    }
+
+    fields.insert("message".to_string(), Value::from(rest));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn syslog(text: &str) -> HashMap<String, Value> {
+        let mut messages = Parser::Syslog.parse(text.as_bytes());
+        assert_eq!(messages.len(), 1);
+        messages.remove(0).fields
+    }
+
+    fn fields(pairs: &[(&str, Value)]) -> HashMap<String, Value> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn rfc5424_messages_are_split_into_fields() {
+        let parsed = syslog(
+            "<165>1 2003-10-11T22:14:15.003Z mymachine.example.com evntslog - ID47 \
+             [exampleSDID@32473 iut=\"3\" eventID=\"1011\"] An application event\n",
+        );
+
+        assert_eq!(
+            parsed,
+            fields(&[
+                ("facility", 20.into()),
+                ("severity", 5.into()),
+                ("timestamp", "2003-10-11T22:14:15.003Z".into()),
+                ("hostname", "mymachine.example.com".into()),
+                ("app_name", "evntslog".into()),
+                ("msgid", "ID47".into()),
+                (
+                    "structured_data",
+                    "[exampleSDID@32473 iut=\"3\" eventID=\"1011\"]".into()
+                ),
+                ("message", "An application event".into()),
+            ])
+        );
+    }
+
+    #[test]
+    fn rfc5424_structured_data_may_contain_escaped_brackets() {
+        let parsed = syslog("<34>1 - - - - - [a x=\"\\]\"][b] \u{feff}text");
+
+        assert_eq!(parsed["structured_data"], "[a x=\"\\]\"][b]");
+        assert_eq!(parsed["message"], "text");
+        assert!(!parsed.contains_key("timestamp"));
+        assert!(!parsed.contains_key("hostname"));
+    }
+
+    #[test]
+    fn rfc5424_messages_may_have_no_text() {
+        let parsed = syslog("<34>1 2003-10-11T22:14:15Z host app 42 - -");
+
+        assert_eq!(parsed["procid"], "42");
+        assert!(!parsed.contains_key("structured_data"));
+        assert!(!parsed.contains_key("message"));
+    }
+
+    #[test]
+    fn rfc3164_messages_are_split_into_fields() {
+        let parsed = syslog("<34>Oct 11 22:14:15 mymachine su[230]: 'su root' failed\r\n");
+
+        assert_eq!(
+            parsed,
+            fields(&[
+                ("facility", 4.into()),
+                ("severity", 2.into()),
+                ("timestamp", "Oct 11 22:14:15".into()),
+                ("hostname", "mymachine".into()),
+                ("app_name", "su".into()),
+                ("procid", "230".into()),
+                ("message", "'su root' failed".into()),
+            ])
+        );
+    }
+
+    #[test]
+    fn rfc3164_messages_without_header_keep_the_text() {
+        assert_eq!(
+            syslog("<13>just some text"),
+            fields(&[
+                ("facility", 1.into()),
+                ("severity", 5.into()),
+                ("message", "just some text".into()),
+            ])
+        );
+
+        let parsed = syslog("<13>cron: job done");
+        assert_eq!(parsed["app_name"], "cron");
+        assert!(!parsed.contains_key("procid"));
+        assert_eq!(parsed["message"], "job done");
+    }
+
+    #[test]
+    fn priorities_are_split_into_facility_and_severity() {
+        for (priority, facility, severity) in [(0, 0, 0), (7, 0, 7), (8, 1, 0), (191, 23, 7)] {
+            let parsed = syslog(&format!("<{}>text", priority));
+
+            assert_eq!(parsed["facility"], facility, "<{}>", priority);
+            assert_eq!(parsed["severity"], severity, "<{}>", priority);
+        }
+    }
+
+    #[test]
+    fn malformed_messages_are_sent_as_is() {
+        for text in [
+            "no priority",
+            "<192>priority too high",
+            "<abc>not a number",
+            "<13 unterminated",
+            "<34>1 2003-10-11T22:14:15Z too few",
+            "<34>1 - - - - [unterminated",
+            "<34>1 - - - - not structured data",
+        ] {
+            let messages = Parser::Syslog.parse(text.as_bytes());
+
+            assert_eq!(messages.len(), 1, "{}", text);
+            assert!(messages[0].fields.is_empty(), "{}", text);
+            assert_eq!(
+                messages[0].body.as_deref(),
+                Some(text.as_bytes()),
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn messages_that_are_not_utf8_are_sent_as_is() {
+        let bytes = b"<13>\xff\xfe";
+        let messages = Parser::Syslog.parse(bytes);
+
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].body.as_deref(), Some(&bytes[..]));
+    }
+}
@@ -0,0 +1,127 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::message::Message;
+
+/// What sinks writing bytes out send for each message.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Serialization {
+    /// The raw body, or else the `data` field. Strings are written as is,
+    /// other values as JSON.
+    #[default]
+    Body,
+
+    /// The message fields, as a JSON object. Sinks may narrow it down to
+    /// some fields only.
+    Fields,
+
+    /// The whole message including its envelope, as JSON.
+    Message,
+}
+
+impl Serialization {
+    /// Serializes a message. `fields` narrows the fields written with
+    /// `Serialization::Fields` down to the ones listed.
+    pub fn serialize(&self, message: &Message, fields: Option<&[String]>) -> Vec<u8> {
+        let serialized = match self {
+            Serialization::Body => match (&message.body, message.fields.get("data")) {
+                (Some(body), _) => return body.clone(),
+                (None, Some(Value::String(data))) => return data.clone().into_bytes(),
+                (None, Some(data)) => serde_json::to_vec(data),
+                (None, None) => return vec![],
+            },
+            Serialization::Fields => match fields {
+                Some(fields) => serde_json::to_vec(
+                    &fields
+                        .iter()
+                        .filter_map(|name| Some((name, message.fields.get(name)?)))
+                        .collect::<BTreeMap<_, _>>(),
+                ),
+                None => serde_json::to_vec(&message.fields),
+            },
+            Serialization::Message => serde_json::to_vec(message),
+        };
+
+        serialized.unwrap_or_else(|e| {
+            println!("Error serializing message {}: {:?}", message.id, e);
+            vec![]
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn message() -> Message {
+        Message::new(HashMap::from([
+            ("a".to_string(), 1.into()),
+            ("b".to_string(), "two".into()),
+        ]))
+    }
+
+    fn json(bytes: Vec<u8>) -> Value {
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[test]
+    fn fields_are_written_as_an_object() {
+        let serialized = Serialization::Fields.serialize(&message(), None);
+
+        assert_eq!(json(serialized), serde_json::json!({"a": 1, "b": "two"}));
+    }
+
+    #[test]
+    fn fields_can_be_narrowed_down() {
+        let fields = ["b".to_string(), "missing".to_string()];
+        let serialized = Serialization::Fields.serialize(&message(), Some(&fields));
+
+        assert_eq!(json(serialized), serde_json::json!({"b": "two"}));
+    }
+
+    #[test]
+    fn whole_messages_carry_their_envelope() {
+        let mut message = message();
+        message.routes.push("main".into());
+        message.hops = 1;
+
+        // Narrowing down fields only applies to the fields serialization.
+        let fields = ["b".to_string()];
+        let serialized = json(Serialization::Message.serialize(&message, Some(&fields)));
+
+        assert_eq!(serialized["id"], message.id.to_string());
+        assert_eq!(serialized["routes"], serde_json::json!(["main"]));
+        assert_eq!(serialized["hops"], 1);
+        assert_eq!(
+            serialized["fields"],
+            serde_json::json!({"a": 1, "b": "two"})
+        );
+        assert!(serialized.get("body").is_none());
+
+        let parsed: Message = serde_json::from_value(serialized).unwrap();
+        assert_eq!(parsed.id, message.id);
+        assert_eq!(parsed.fields, message.fields);
+    }
+
+    #[test]
+    fn bodies_are_written_as_is() {
+        let mut message = Message::from_body(b"raw".to_vec());
+        message.fields.insert("data".into(), "ignored".into());
+        assert_eq!(Serialization::Body.serialize(&message, None), b"raw");
+
+        let text = Message::new(HashMap::from([("data".to_string(), "text".into())]));
+        assert_eq!(Serialization::Body.serialize(&text, None), b"text");
+
+        let value = Message::new(HashMap::from([("data".to_string(), 3.into())]));
+        assert_eq!(Serialization::Body.serialize(&value, None), b"3");
+
+        assert!(Serialization::Body
+            .serialize(&Message::new(HashMap::new()), None)
+            .is_empty());
+    }
+}
@@ -1,7 +1,11 @@
 use tokio::task::JoinHandle;
 
 use crate::{
-    configuration::module_properties::ModuleProperties, modules::module::ModuleTrait,
+    configuration::{error::ConfigurationError, module_properties::ModuleProperties},
+    modules::{
+        module::ModuleTrait,
+        registry::{self, Capabilities, ModuleRegistration},
+    },
 };
 
 pub struct EchoModule {
@@ -9,33 +13,46 @@ pub struct EchoModule {
 }
 
 impl ModuleTrait for EchoModule {
-    fn new(configuration: ModuleProperties) -> Self {
-        EchoModule { configuration }
+    fn new(configuration: ModuleProperties) -> Result<Self, ConfigurationError> {
+        Ok(EchoModule { configuration })
     }
 
     fn run(self: Box<Self>) -> JoinHandle<()> {
-        tokio::spawn(async {
-            if let Some(inbox) = self.configuration.inbox {
-                loop {
-                    let message = inbox.recv().await;
-                    match message {
-                        Ok(message) => {
-                            println!("Echoing message: {:?}", message.fields.get("data"));
-                        }
-                        Err(e) => {
-                            println!("Error receiving message: {:?}", e);
-                            break;
+        tokio::spawn(async move {
+            loop {
+                let message = self.configuration.inboxes.recv().await;
+                match message {
+                    Ok(message) => match (message.fields.get("data"), &message.body) {
+                        (None, Some(body)) => {
+                            println!("Echoing message body: {:?}", String::from_utf8_lossy(body))
                         }
+                        (data, _) => println!("Echoing message: {:?}", data),
+                    },
+                    Err(e) => {
+                        println!("Error receiving message: {:?}", e);
+                        break;
                     }
                 }
             }
         })
     }
 
-    fn set_inbox(
+    fn add_inbox(
         &mut self,
-        inbox: Option<async_channel::Receiver<crate::messaging::message::Message>>,
+        name: String,
+        inbox: async_channel::Receiver<crate::messaging::message::Message>,
     ) {
-        self.configuration.inbox = inbox;
+        self.configuration.inboxes.insert(name, inbox);
+    }
+}
+
+inventory::submit! {
+    ModuleRegistration {
+        name: "echo",
+        description: "Prints the data field of every message it receives",
+        constructor: registry::construct::<EchoModule>,
+        validate: registry::no_validation,
+        settings: registry::no_settings,
+        capabilities: Capabilities::SINK,
     }
 }
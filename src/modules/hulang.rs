@@ -0,0 +1,259 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    configuration::{error::ConfigurationError, module_properties::ModuleProperties},
+    messaging::{channel::Outbox, message::Message},
+    modules::{
+        lang::Script,
+        module::ModuleTrait,
+        registry::{self, Capabilities, ModuleRegistration},
+    },
+};
+
+/// What happens to a message the script fails on.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnError {
+    /// Sent on as it was before the script ran.
+    #[default]
+    Forward,
+    Drop,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct HulangConfiguration {
+    // The script itself, or the file it is in. Exactly one must be set.
+    #[serde(default)]
+    script: Option<String>,
+    #[serde(default)]
+    script_file: Option<PathBuf>,
+
+    #[serde(default)]
+    on_error: OnError,
+}
+
+impl HulangConfiguration {
+    // Compiles the script, inline or read from its file.
+    fn script(&self) -> Result<Script, ConfigurationError> {
+        match (&self.script, &self.script_file) {
+            (Some(script), None) => compile("script", script),
+            (None, Some(file)) => {
+                let source = std::fs::read_to_string(file).map_err(|e| {
+                    ConfigurationError::InvalidSettings {
+                        path: "script_file".into(),
+                        message: format!("{:?}: {}", file, e),
+                    }
+                })?;
+                compile("script_file", &source)
+            }
+            _ => Err(not_exactly_one_script()),
+        }
+    }
+}
+
+pub struct HulangModule {
+    pub(crate) properties: ModuleProperties,
+    configuration: HulangConfiguration,
+    script: Script,
+}
+
+impl ModuleTrait for HulangModule {
+    fn new(configuration: ModuleProperties) -> Result<Self, ConfigurationError>
+    where
+        Self: Sized,
+    {
+        let module_config: HulangConfiguration = configuration.settings()?;
+        let script = module_config.script()?;
+
+        Ok(Self {
+            properties: configuration,
+            configuration: module_config,
+            script,
+        })
+    }
+
+    fn add_outbox(&mut self, name: String, outbox: Outbox) {
+        self.properties.outboxes.insert(name, outbox);
+    }
+
+    fn add_inbox(&mut self, name: String, inbox: async_channel::Receiver<Message>) {
+        self.properties.inboxes.insert(name, inbox);
+    }
+
+    fn run(self: Box<Self>) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let HulangModule {
+                properties,
+                configuration,
+                script,
+            } = *self;
+
+            // Runs until every route feeding the module is closed, so that
+            // messages already on their way are still processed on shutdown.
+            while let Ok(message) = properties.inboxes.recv().await {
+                let id = message.id;
+                if let Some(message) = transform(&script, configuration.on_error, message) {
+                    if properties.outboxes.send(message).await.is_err() {
+                        println!("Dropping message {}, every route out is closed", id);
+                    }
+                }
+            }
+        })
+    }
+}
+
+impl HulangModule {
+    /// Checks the settings, and compiles the script, reading it from its
+    /// file if need be.
+    pub(crate) fn validate(configuration: &ModuleProperties) -> Result<(), ConfigurationError> {
+        let module_config: HulangConfiguration = configuration.settings()?;
+        module_config.script().map(|_| ())
+    }
+
+    pub(crate) fn get_settings() -> serde_json::Value {
+        let default_settings = HulangConfiguration::default();
+        serde_json::to_value(default_settings).expect("Failed to serialize default settings")
+    }
+}
+
+// Runs the script on a message, giving what to send on.
+fn transform(script: &Script, on_error: OnError, mut message: Message) -> Option<Message> {
+    match script.apply(&mut message) {
+        Ok(kept) => kept.then_some(message),
+        Err(e) => {
+            println!("Error running script on message {}: {}", message.id, e);
+            (on_error == OnError::Forward).then_some(message)
+        }
+    }
+}
+
+// Parses a script, reporting errors against the `path` setting it came from.
+fn compile(path: &str, source: &str) -> Result<Script, ConfigurationError> {
+    Script::new(source).map_err(|e| ConfigurationError::InvalidSettings {
+        path: path.into(),
+        message: e.to_string(),
+    })
+}
+
+fn not_exactly_one_script() -> ConfigurationError {
+    ConfigurationError::InvalidSettings {
+        path: ".".into(),
+        message: "exactly one of script and script_file must be set".into(),
+    }
+}
+
+inventory::submit! {
+    ModuleRegistration {
+        name: "hulang",
+        description: "Runs a Hulang script on every message it receives, and sends on what the script does not drop",
+        constructor: registry::construct::<HulangModule>,
+        validate: HulangModule::validate,
+        settings: HulangModule::get_settings,
+        capabilities: Capabilities::PROCESSOR,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn properties(settings: &str) -> ModuleProperties {
+        toml::from_str(&format!("module_type = \"hulang\"\n{}", settings)).unwrap()
+    }
+
+    fn message() -> Message {
+        Message::new(HashMap::from([("a".to_string(), 1.into())]))
+    }
+
+    #[test]
+    fn exactly_one_of_script_and_script_file_is_set() {
+        let both = properties("script = \"msg.b = 2\"\nscript_file = \"script.hl\"");
+        let neither = properties("on_error = \"drop\"");
+
+        for configuration in [both, neither] {
+            assert!(matches!(
+                HulangModule::validate(&configuration),
+                Err(ConfigurationError::InvalidSettings { message, .. })
+                    if message == "exactly one of script and script_file must be set"
+            ));
+        }
+    }
+
+    #[test]
+    fn script_errors_are_reported_against_the_script_setting() {
+        assert!(matches!(
+            HulangModule::validate(&properties("script = \"msg.b = \"")),
+            Err(ConfigurationError::InvalidSettings { path, .. }) if path == "script"
+        ));
+    }
+
+    #[test]
+    fn script_files_are_read_and_compiled_when_validating() {
+        let directory = std::env::temp_dir().join(format!("hulaak-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let file = |name: &str, source: &str| {
+            let path = directory.join(name);
+            std::fs::write(&path, source).unwrap();
+            properties(&format!("script_file = {:?}", path))
+        };
+
+        let valid = file("valid.hl", "msg.b = msg.a");
+        let broken = file("broken.hl", "msg.b = ");
+        let missing = properties(&format!("script_file = {:?}", directory.join("missing.hl")));
+
+        assert!(HulangModule::validate(&valid).is_ok());
+        for configuration in [broken, missing] {
+            assert!(matches!(
+                HulangModule::validate(&configuration),
+                Err(ConfigurationError::InvalidSettings { path, .. }) if path == "script_file"
+            ));
+        }
+
+        let _ = std::fs::remove_dir_all(directory);
+    }
+
+    #[test]
+    fn messages_are_sent_on_as_the_script_leaves_them() {
+        let script = Script::new("msg.b = msg.a").unwrap();
+
+        let transformed = transform(&script, OnError::Drop, message()).unwrap();
+        assert_eq!(transformed.fields["b"], 1);
+    }
+
+    #[test]
+    fn failed_messages_are_forwarded_as_they_were() {
+        let script = Script::new("msg.b = 2; msg.c = div(1, 0)").unwrap();
+        let message = message();
+
+        let forwarded = transform(&script, OnError::Forward, message.clone()).unwrap();
+        assert_eq!(forwarded.id, message.id);
+        assert_eq!(forwarded.fields, message.fields);
+    }
+
+    #[test]
+    fn every_change_is_undone_when_the_script_fails() {
+        let script = Script::new(
+            "msg.a = 2; msg.n.x = 1; drop(msg.n.y); add(msg, \"c\", 3); drop(msg, \"d\"); msg.e = div(1, 0)",
+        )
+        .unwrap();
+        let mut message = message();
+        message
+            .fields
+            .insert("n".into(), serde_json::json!({"y": [1, 2]}));
+        message.fields.insert("d".into(), "kept".into());
+
+        let forwarded = transform(&script, OnError::Forward, message.clone()).unwrap();
+        assert_eq!(forwarded.fields, message.fields);
+    }
+
+    #[test]
+    fn failed_messages_are_dropped_with_on_error_drop() {
+        let script = Script::new("msg.b = 2; msg.c = div(1, 0)").unwrap();
+
+        assert!(transform(&script, OnError::Drop, message()).is_none());
+    }
+}
@@ -1,34 +1,39 @@
 use std::collections::HashMap;
 
-use crate::{configuration::module_properties::ModuleProperties, messaging::message::Message};
+use crate::{
+    configuration::{error::ConfigurationError, module_properties::ModuleProperties},
+    messaging::message::Message,
+};
 
-use super::module::ModuleTrait;
+use super::{
+    module::ModuleTrait,
+    registry::{self, Capabilities, ModuleRegistration},
+};
 
 pub struct InfiniteSender {
     pub(crate) properties: ModuleProperties,
 }
 
 impl ModuleTrait for InfiniteSender {
-    fn new(configuration: ModuleProperties) -> Self
+    fn new(configuration: ModuleProperties) -> Result<Self, ConfigurationError>
     where
         Self: Sized,
     {
-        Self {
+        Ok(Self {
             properties: configuration,
-        }
+        })
     }
 
     fn run(self: Box<Self>) -> tokio::task::JoinHandle<()> {
         tokio::spawn(async move {
             let mut counter = 1_000_000;
 
-            while counter > 0 {
-                let message = Message {
-                    fields: HashMap::new(),
-                };
+            while counter > 0 && !self.properties.shutdown.is_cancelled() {
+                let message = Message::new(HashMap::new());
 
-                if let Some(outbox) = &self.properties.outbox {
-                    outbox.send(message).await.unwrap();
+                if self.properties.outboxes.send(message).await.is_err() {
+                    println!("InfiniteSender: Every route out is closed, stopping");
+                    break;
                 }
                 counter -= 1;
             }
@@ -37,10 +42,18 @@ impl ModuleTrait for InfiniteSender {
         })
     }
 
-    fn set_outbox(
-        &mut self,
-        _outbox: Option<async_channel::Sender<crate::messaging::message::Message>>,
-    ) {
-        self.properties.outbox = _outbox;
+    fn add_outbox(&mut self, name: String, outbox: crate::messaging::channel::Outbox) {
+        self.properties.outboxes.insert(name, outbox);
+    }
+}
+
+inventory::submit! {
+    ModuleRegistration {
+        name: "infinitesender",
+        description: "Sends a million empty messages as fast as it can, for load testing",
+        constructor: registry::construct::<InfiniteSender>,
+        validate: registry::no_validation,
+        settings: registry::no_settings,
+        capabilities: Capabilities::SOURCE,
     }
 }
@@ -0,0 +1,125 @@
+use std::sync::Arc;
+
+use super::error::Position;
+
+/// Name scripts refer to the message they run on by.
+pub const MESSAGE: &str = "msg";
+
+/// A parsed script: statements run one after the other on every message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    pub statements: Vec<Expression>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expression {
+    pub kind: ExpressionKind,
+    pub position: Position,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExpressionKind {
+    Literal(Literal),
+
+    /// A name, such as `msg` for the message the script runs on, a variable,
+    /// or a function to pass to another.
+    Variable(String),
+
+    Array(Vec<Expression>),
+    Object(Vec<(String, Expression)>),
+
+    /// `target[index]`, or `target.name` for `target["name"]`. Indexing nil,
+    /// or with a key or index that is not there, gives nil.
+    Index {
+        target: Box<Expression>,
+        index: Box<Expression>,
+    },
+
+    /// `msg.path = value`, setting a field of the message, and creating the
+    /// objects on the way as needed, or `name = value`, setting a variable.
+    Assign {
+        target: Box<Expression>,
+        value: Box<Expression>,
+    },
+
+    /// `drop(msg.path)`, removing a field of the message.
+    Delete(Box<Expression>),
+
+    /// A call to a builtin or user-defined function, arguments evaluated
+    /// left to right.
+    Call {
+        function: String,
+        arguments: Vec<Expression>,
+    },
+
+    /// `let name = value`, defining a variable in the current block.
+    /// `fn name(...) { ... }` defines a function the same way.
+    Let {
+        name: String,
+        value: Box<Expression>,
+    },
+
+    /// `if condition { ... } else { ... }`, giving the value of the branch
+    /// taken, or nil. `else if` is an `If` alone in the `otherwise` block.
+    If {
+        condition: Box<Expression>,
+        then: Vec<Expression>,
+        otherwise: Vec<Expression>,
+    },
+
+    /// `!value`, true if the value is not truthy.
+    Not(Box<Expression>),
+
+    Binary {
+        operator: Operator,
+        left: Box<Expression>,
+        right: Box<Expression>,
+    },
+
+    /// `fn(parameters) { ... }`, a function giving the value of the last
+    /// statement of its body. It sees the variables where it was defined.
+    Function {
+        name: Option<String>,
+        parameters: Vec<String>,
+        body: Arc<Vec<Expression>>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operator {
+    /// `&&` and `||` only evaluate their right side when needed, and give a
+    /// bool.
+    And,
+    Or,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Literal {
+    String(String),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Nil,
+}
+
+impl Expression {
+    /// The keys of a path into the message, such as `msg.a[0]`, or None if
+    /// the expression is not one. The message itself has no keys.
+    pub fn message_path(&self) -> Option<Vec<&Expression>> {
+        match &self.kind {
+            ExpressionKind::Variable(name) if name == MESSAGE => Some(vec![]),
+            ExpressionKind::Index { target, index } => {
+                let mut keys = target.message_path()?;
+                keys.push(index);
+                Some(keys)
+            }
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,40 @@
+use std::fmt::Display;
+
+/// Where something is in a script. Lines and columns start at 1, and columns
+/// count characters rather than bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Display for Position {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
+
+/// An error in a script, either found while parsing it or raised while
+/// running it on a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HulangError {
+    pub position: Position,
+    pub message: String,
+}
+
+impl HulangError {
+    pub fn new(position: Position, message: impl Into<String>) -> Self {
+        HulangError {
+            position,
+            message: message.into(),
+        }
+    }
+}
+
+impl Display for HulangError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.position, self.message)
+    }
+}
+
+impl std::error::Error for HulangError {}
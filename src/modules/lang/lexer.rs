@@ -0,0 +1,326 @@
+use std::{fmt::Display, iter::Peekable, str::Chars};
+
+use super::error::{HulangError, Position};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    Identifier(String),
+    String(String),
+    Int(i64),
+    Float(f64),
+    True,
+    False,
+    Nil,
+    Let,
+    If,
+    Else,
+    Fn,
+    LeftParen,
+    RightParen,
+    LeftBracket,
+    RightBracket,
+    LeftBrace,
+    RightBrace,
+    Comma,
+    Colon,
+    Semicolon,
+    Dot,
+    Assign,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    And,
+    Or,
+    Not,
+    End,
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Identifier(name) => write!(f, "{}", name),
+            Token::String(value) => write!(f, "{:?}", value),
+            Token::Int(value) => write!(f, "{}", value),
+            Token::Float(value) => write!(f, "{:?}", value),
+            Token::True => write!(f, "true"),
+            Token::False => write!(f, "false"),
+            Token::Nil => write!(f, "nil"),
+            Token::Let => write!(f, "let"),
+            Token::If => write!(f, "if"),
+            Token::Else => write!(f, "else"),
+            Token::Fn => write!(f, "fn"),
+            Token::LeftParen => write!(f, "'('"),
+            Token::RightParen => write!(f, "')'"),
+            Token::LeftBracket => write!(f, "'['"),
+            Token::RightBracket => write!(f, "']'"),
+            Token::LeftBrace => write!(f, "'{{'"),
+            Token::RightBrace => write!(f, "'}}'"),
+            Token::Comma => write!(f, "','"),
+            Token::Colon => write!(f, "':'"),
+            Token::Semicolon => write!(f, "';'"),
+            Token::Dot => write!(f, "'.'"),
+            Token::Assign => write!(f, "'='"),
+            Token::Equal => write!(f, "'=='"),
+            Token::NotEqual => write!(f, "'!='"),
+            Token::Less => write!(f, "'<'"),
+            Token::LessEqual => write!(f, "'<='"),
+            Token::Greater => write!(f, "'>'"),
+            Token::GreaterEqual => write!(f, "'>='"),
+            Token::And => write!(f, "'&&'"),
+            Token::Or => write!(f, "'||'"),
+            Token::Not => write!(f, "'!'"),
+            Token::End => write!(f, "the end of the script"),
+        }
+    }
+}
+
+/// Splits a script into tokens, each with where it starts. The last token is
+/// always `Token::End`. Comments run from `#` to the end of the line.
+pub fn tokenize(source: &str) -> Result<Vec<(Token, Position)>, HulangError> {
+    let mut lexer = Lexer {
+        chars: source.chars().peekable(),
+        line: 1,
+        column: 1,
+    };
+
+    let mut tokens = vec![];
+    loop {
+        let (token, position) = lexer.next_token()?;
+        let end = token == Token::End;
+        tokens.push((token, position));
+
+        if end {
+            return Ok(tokens);
+        }
+    }
+}
+
+struct Lexer<'a> {
+    chars: Peekable<Chars<'a>>,
+    line: usize,
+    column: usize,
+}
+
+impl Lexer<'_> {
+    fn position(&self) -> Position {
+        Position {
+            line: self.line,
+            column: self.column,
+        }
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.chars.next()?;
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    // Takes the next character if it is `expected`.
+    fn eat(&mut self, expected: char) -> bool {
+        if self.chars.peek() == Some(&expected) {
+            self.bump();
+            return true;
+        }
+        false
+    }
+
+    fn skip_blanks(&mut self) {
+        while let Some(&c) = self.chars.peek() {
+            if c == '#' {
+                while self.chars.peek().is_some_and(|&c| c != '\n') {
+                    self.bump();
+                }
+            } else if c.is_whitespace() {
+                self.bump();
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn next_token(&mut self) -> Result<(Token, Position), HulangError> {
+        self.skip_blanks();
+        let start = self.position();
+
+        let Some(c) = self.bump() else {
+            return Ok((Token::End, start));
+        };
+
+        let token = match c {
+            '(' => Token::LeftParen,
+            ')' => Token::RightParen,
+            '[' => Token::LeftBracket,
+            ']' => Token::RightBracket,
+            '{' => Token::LeftBrace,
+            '}' => Token::RightBrace,
+            ',' => Token::Comma,
+            ':' => Token::Colon,
+            ';' => Token::Semicolon,
+            '.' => Token::Dot,
+            '=' if self.eat('=') => Token::Equal,
+            '=' => Token::Assign,
+            '!' if self.eat('=') => Token::NotEqual,
+            '!' => Token::Not,
+            '<' if self.eat('=') => Token::LessEqual,
+            '<' => Token::Less,
+            '>' if self.eat('=') => Token::GreaterEqual,
+            '>' => Token::Greater,
+            '&' if self.eat('&') => Token::And,
+            '|' if self.eat('|') => Token::Or,
+            '"' => self.string(start)?,
+            '-' if self.chars.peek().is_some_and(char::is_ascii_digit) => {
+                self.number('-', start)?
+            }
+            c if c.is_ascii_digit() => self.number(c, start)?,
+            c if c.is_alphabetic() || c == '_' => self.word(c),
+            c => {
+                return Err(HulangError::new(
+                    start,
+                    format!("unexpected character {:?}", c),
+                ))
+            }
+        };
+
+        Ok((token, start))
+    }
+
+    fn string(&mut self, start: Position) -> Result<Token, HulangError> {
+        let mut value = String::new();
+
+        loop {
+            let position = self.position();
+            match self.bump() {
+                None => return Err(HulangError::new(start, "unterminated string")),
+                Some('"') => return Ok(Token::String(value)),
+                Some('\\') => match self.bump() {
+                    Some('"') => value.push('"'),
+                    Some('\\') => value.push('\\'),
+                    Some('n') => value.push('\n'),
+                    Some('r') => value.push('\r'),
+                    Some('t') => value.push('\t'),
+                    Some(c) => {
+                        return Err(HulangError::new(
+                            position,
+                            format!("unknown escape sequence \\{}", c),
+                        ))
+                    }
+                    None => return Err(HulangError::new(start, "unterminated string")),
+                },
+                Some(c) => value.push(c),
+            }
+        }
+    }
+
+    fn number(&mut self, first: char, start: Position) -> Result<Token, HulangError> {
+        let mut text = String::from(first);
+        let mut float = false;
+
+        while let Some(&c) = self.chars.peek() {
+            if c.is_ascii_digit() {
+                text.push(c);
+            } else if c == '.' && !float {
+                float = true;
+                text.push(c);
+            } else {
+                break;
+            }
+            self.bump();
+        }
+
+        let token = if float {
+            text.parse().map(Token::Float).ok()
+        } else {
+            text.parse().map(Token::Int).ok()
+        };
+
+        token.ok_or_else(|| HulangError::new(start, format!("invalid number {}", text)))
+    }
+
+    fn word(&mut self, first: char) -> Token {
+        let mut word = String::from(first);
+        while let Some(&c) = self.chars.peek() {
+            if !(c.is_alphanumeric() || c == '_') {
+                break;
+            }
+            word.push(c);
+            self.bump();
+        }
+
+        match word.as_str() {
+            "true" => Token::True,
+            "false" => Token::False,
+            "nil" => Token::Nil,
+            "let" => Token::Let,
+            "if" => Token::If,
+            "else" => Token::Else,
+            "fn" => Token::Fn,
+            _ => Token::Identifier(word),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(source: &str) -> (usize, usize, String) {
+        let e = tokenize(source).unwrap_err();
+        (e.position.line, e.position.column, e.message)
+    }
+
+    #[test]
+    fn strings_unescape_quotes_backslashes_and_whitespace() {
+        let tokens = tokenize(r#""a\"b\\c\nd\re\tf""#).unwrap();
+
+        assert_eq!(tokens[0].0, Token::String("a\"b\\c\nd\re\tf".into()));
+        assert_eq!(tokens[1].0, Token::End);
+    }
+
+    #[test]
+    fn unknown_escape_is_reported_where_it_is() {
+        assert_eq!(
+            error(r#"msg.a = "ab\q""#),
+            (1, 12, "unknown escape sequence \\q".into())
+        );
+    }
+
+    #[test]
+    fn unterminated_string_is_reported_where_it_starts() {
+        assert_eq!(
+            error("msg.a = 1;\nmsg.b = \"abc"),
+            (2, 9, "unterminated string".into())
+        );
+        assert_eq!(error("\"abc\\"), (1, 1, "unterminated string".into()));
+    }
+
+    #[test]
+    fn positions_count_lines_and_characters() {
+        let positions: Vec<(usize, usize)> = tokenize("é = 1;\n  msg.a")
+            .unwrap()
+            .into_iter()
+            .map(|(_, position)| (position.line, position.column))
+            .collect();
+
+        assert_eq!(
+            positions,
+            vec![
+                (1, 1),
+                (1, 3),
+                (1, 5),
+                (1, 6),
+                (2, 3),
+                (2, 6),
+                (2, 7),
+                (2, 8)
+            ]
+        );
+    }
+}
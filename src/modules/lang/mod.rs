@@ -1,11 +1,35 @@
-use std::{collections::HashMap, fmt::Debug};
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::Debug,
+    mem,
+    sync::{Arc, Weak},
+};
 
-use anyhow::Error;
+use anyhow::{anyhow, bail, Error};
 
 use crate::messaging::message::Message;
 
+use self::{
+    ast::{Expression, ExpressionKind, Literal, Operator, Program, MESSAGE},
+    error::HulangError,
+    path::Key,
+    scope::Scope,
+};
+
+pub mod ast;
+pub mod error;
+pub mod lexer;
+pub mod parser;
+mod path;
+mod scope;
+mod stdlib;
+
+type Builtin = dyn Fn(&mut Context, Vec<Param>) -> Result<Param, Error> + Send + Sync;
+
+#[derive(Clone)]
 struct FunctionCall {
-    pub inner: Box<dyn FnOnce(Context, Vec<Param>) -> Result<Param, Error>>,
+    pub inner: Arc<Builtin>,
 }
 
 impl Debug for FunctionCall {
@@ -17,22 +41,26 @@ impl Debug for FunctionCall {
 impl FunctionCall {
     fn new<T>(function: T) -> Self
     where
-        T: FnOnce(Context, Vec<Param>) -> Result<Param, Error> + 'static,
+        T: Fn(&mut Context, Vec<Param>) -> Result<Param, Error> + Send + Sync + 'static,
     {
         FunctionCall {
-            inner: Box::new(function),
+            inner: Arc::new(function),
         }
     }
 }
 
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Clone)]
 enum Param {
     String(String),
     Int(i64),
     Float(f64),
     Bool(bool),
+    Array(Vec<Param>),
+    Object(BTreeMap<String, Param>),
     Function(FunctionCall),
-    Message(Message),
+
+    // The message the script runs on, which the context holds.
+    Message,
 
     #[default]
     Nil,
@@ -43,8 +71,18 @@ impl From<Param> for serde_json::Value {
         match value {
             Param::String(val) => serde_json::value::Value::String(val),
             Param::Int(val) => serde_json::value::Value::Number(serde_json::Number::from(val)),
-            Param::Float(val) => serde_json::value::Value::Number(serde_json::Number::from_f64(val).unwrap()),
+            Param::Float(val) => serde_json::Number::from_f64(val)
+                .map(serde_json::Value::Number)
+                .unwrap_or_default(),
             Param::Bool(val) => serde_json::value::Value::Bool(val),
+            Param::Array(val) => {
+                serde_json::Value::Array(val.into_iter().map(Into::into).collect())
+            }
+            Param::Object(val) => serde_json::Value::Object(
+                val.into_iter()
+                    .map(|(key, value)| (key, value.into()))
+                    .collect(),
+            ),
 
             // Nulls and functions are encoded as nulls.
             _ => serde_json::Value::Null,
@@ -52,62 +90,744 @@ impl From<Param> for serde_json::Value {
     }
 }
 
+impl From<serde_json::Value> for Param {
+    fn from(value: serde_json::Value) -> Self {
+        match value {
+            serde_json::Value::Null => Param::Nil,
+            serde_json::Value::Bool(val) => Param::Bool(val),
+            serde_json::Value::Number(val) => match val.as_i64() {
+                Some(val) => Param::Int(val),
+                None => Param::Float(val.as_f64().unwrap_or_default()),
+            },
+            serde_json::Value::String(val) => Param::String(val),
+            serde_json::Value::Array(val) => {
+                Param::Array(val.into_iter().map(Into::into).collect())
+            }
+            serde_json::Value::Object(val) => Param::Object(
+                val.into_iter()
+                    .map(|(key, value)| (key, value.into()))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl From<&Literal> for Param {
+    fn from(literal: &Literal) -> Self {
+        match literal {
+            Literal::String(val) => Param::String(val.clone()),
+            Literal::Int(val) => Param::Int(*val),
+            Literal::Float(val) => Param::Float(*val),
+            Literal::Bool(val) => Param::Bool(*val),
+            Literal::Nil => Param::Nil,
+        }
+    }
+}
+
 impl Param {
-    pub fn eval(self) -> Result<Param, Error> {
+    /// Calls the value with `arguments`, if it is a function.
+    pub fn call(&self, ctx: &mut Context, arguments: Vec<Param>) -> Result<Param, Error> {
         match self {
-            Param::Function(f) => (f.inner)(Context::default(), vec![]),
-            _ => Ok(self),
+            Param::Function(f) => (f.inner)(ctx, arguments),
+            other => Err(anyhow!("expected a function, got {}", other.type_name())),
+        }
+    }
+
+    /// Nil, false, zero, empty strings, arrays and objects, and "false" are
+    /// false. Everything else is true.
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            Param::Bool(b) => *b,
+            Param::Int(i) => *i != 0,
+            Param::Float(f) => *f != 0.0,
+            Param::String(s) => !(s.is_empty() || s.eq_ignore_ascii_case("false")),
+            Param::Array(values) => !values.is_empty(),
+            Param::Object(entries) => !entries.is_empty(),
+            Param::Nil => false,
+            Param::Function(_) | Param::Message => true,
         }
     }
 
     pub fn add(self, other: Param) -> Result<Param, Error> {
         match (&self, &other) {
-            (Param::Int(a), Param::Int(b)) => Ok(Param::Int(a + b)),
+            (Param::Int(a), Param::Int(b)) => a
+                .checked_add(*b)
+                .map(Param::Int)
+                .ok_or_else(|| anyhow!("{} + {} overflows", a, b)),
+            (Param::Int(a), Param::Float(b)) => Ok(Param::Float(*a as f64 + b)),
+            (Param::Float(a), Param::Int(b)) => Ok(Param::Float(a + *b as f64)),
             (Param::Float(a), Param::Float(b)) => Ok(Param::Float(a + b)),
             (Param::String(a), Param::String(b)) => Ok(Param::String(a.clone() + b)),
-            _ => Err(anyhow::anyhow!("Cannot add {:?} and {:?}", self, other)),
+            _ => Err(anyhow::anyhow!(
+                "cannot add {} and {}",
+                self.type_name(),
+                other.type_name()
+            )),
+        }
+    }
+
+    /// True if both are the same value. Ints and floats are compared by
+    /// value, and functions are never equal.
+    pub fn equals(&self, other: &Param) -> bool {
+        match (self, other) {
+            (Param::String(a), Param::String(b)) => a == b,
+            (Param::Int(a), Param::Int(b)) => a == b,
+            (Param::Int(a), Param::Float(b)) | (Param::Float(b), Param::Int(a)) => *a as f64 == *b,
+            (Param::Float(a), Param::Float(b)) => a == b,
+            (Param::Bool(a), Param::Bool(b)) => a == b,
+            (Param::Array(a), Param::Array(b)) => {
+                a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.equals(b))
+            }
+            (Param::Object(a), Param::Object(b)) => {
+                a.len() == b.len()
+                    && a.iter()
+                        .zip(b)
+                        .all(|((ka, va), (kb, vb))| ka == kb && va.equals(vb))
+            }
+            (Param::Message, Param::Message) | (Param::Nil, Param::Nil) => true,
+            _ => false,
+        }
+    }
+
+    /// Orders numbers and strings. Other values cannot be ordered.
+    pub fn compare(&self, other: &Param) -> Result<Ordering, Error> {
+        let ordering = match (self, other) {
+            (Param::Int(a), Param::Int(b)) => Some(a.cmp(b)),
+            (Param::Int(a), Param::Float(b)) => (*a as f64).partial_cmp(b),
+            (Param::Float(a), Param::Int(b)) => a.partial_cmp(&(*b as f64)),
+            (Param::Float(a), Param::Float(b)) => a.partial_cmp(b),
+            (Param::String(a), Param::String(b)) => Some(a.cmp(b)),
+            _ => None,
+        };
+
+        ordering.ok_or_else(|| {
+            anyhow!(
+                "cannot compare {} and {}",
+                self.type_name(),
+                other.type_name()
+            )
+        })
+    }
+
+    /// The name of the type of the value, as `type_of` returns it.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Param::String(_) => "string",
+            Param::Int(_) => "int",
+            Param::Float(_) => "float",
+            Param::Bool(_) => "bool",
+            Param::Array(_) => "array",
+            Param::Object(_) => "object",
+            Param::Function(_) => "function",
+            Param::Message => "message",
+            Param::Nil => "nil",
+        }
+    }
+}
+
+/// Calls nested deeper than this fail, rather than overflowing the stack.
+const MAX_CALL_DEPTH: usize = 100;
+
+/// What a script runs with: the message it is applied to, and the variables
+/// it can see.
+struct Context<'a> {
+    scope: Arc<Scope>,
+    message: &'a mut Message,
+
+    // Set once the script drops the message, which ends the script.
+    dropped: bool,
+
+    // Scopes functions were defined in. Functions only hold weak references
+    // to them, so that a function defined in a scope does not keep it alive
+    // forever; they are kept here until the script is done instead.
+    captured: Vec<Arc<Scope>>,
+
+    depth: usize,
+
+    // Top level fields as they were before the script first changed them,
+    // so that the message can be put back as it was should the script fail.
+    changed: HashMap<String, Option<serde_json::Value>>,
+}
+
+impl Context<'_> {
+    // Remembers the top level field `name` before the script changes it.
+    fn changing(&mut self, name: &str) {
+        if !self.changed.contains_key(name) {
+            let field = self.message.fields.get(name).cloned();
+            self.changed.insert(name.to_string(), field);
         }
     }
 }
 
-#[derive(Default)]
-struct Context {
-    symbols: HashMap<String, Param>,
+impl Context<'_> {
+    // Runs `f` with `scope` as the current scope.
+    fn with_scope<T>(&mut self, scope: Arc<Scope>, f: impl FnOnce(&mut Self) -> T) -> T {
+        let outer = mem::replace(&mut self.scope, scope);
+        let result = f(self);
+        self.scope = outer;
+        result
+    }
 }
 
-pub struct HulangModule {
-    compiled_funcs: HashMap<String, FunctionCall>,
+/// A parsed script, along with the builtin functions it can call.
+pub struct Script {
+    globals: Arc<Scope>,
+    program: Program,
 }
 
-impl HulangModule {
-    pub fn new() -> Self {
+impl Script {
+    /// Parses `source`, and checks that every variable and function it uses
+    /// is defined.
+    pub fn new(source: &str) -> Result<Self, HulangError> {
         let mut compiled_funcs: HashMap<String, FunctionCall> = Default::default();
 
         compiled_funcs.insert("drop".into(), FunctionCall::new(drop_key));
         compiled_funcs.insert("add".into(), FunctionCall::new(add_key_value));
+        compiled_funcs.insert("get".into(), FunctionCall::new(get_key));
+        compiled_funcs.insert("has".into(), FunctionCall::new(has_key));
+        stdlib::register(&mut compiled_funcs);
+
+        let program = parser::parse(source)?;
+        let mut scopes = vec![compiled_funcs.keys().cloned().collect()];
+        check_block(&program.statements, &mut scopes)?;
+
+        let globals = Scope::new(None);
+        for (name, function) in compiled_funcs {
+            globals.define(&name, Param::Function(function));
+        }
+
+        Ok(Script { globals, program })
+    }
+
+    /// Runs the script on `message`, stopping at the first error. Returns
+    /// false if the script dropped the message.
+    ///
+    /// Should the script fail, the message is put back as it was before it
+    /// ran. Only the fields the script changed are copied to do so, once it
+    /// changes them.
+    pub fn apply(&self, message: &mut Message) -> Result<bool, HulangError> {
+        let mut context = Context {
+            scope: Scope::new(Some(self.globals.clone())),
+            message,
+            dropped: false,
+            captured: vec![],
+            depth: 0,
+            changed: HashMap::new(),
+        };
+
+        if let Err(e) = evaluate_block(&self.program.statements, &mut context) {
+            for (name, field) in mem::take(&mut context.changed) {
+                match field {
+                    Some(field) => context.message.fields.insert(name, field),
+                    None => context.message.fields.remove(&name),
+                };
+            }
+            return Err(e);
+        }
 
-        HulangModule { compiled_funcs }
+        Ok(!context.dropped)
     }
 }
 
-fn drop_key(ctx: Context, params: Vec<Param>) -> Result<Param, Error> {
-    assert!(matches!(params[0], Param::Message(_)));
+// Runs `statements` in the current scope, giving the value of the last one.
+fn evaluate_block(statements: &[Expression], ctx: &mut Context) -> Result<Param, HulangError> {
+    let mut value = Param::Nil;
 
-    Ok(Param::default())
+    for statement in statements {
+        value = evaluate(statement, ctx)?;
+        if ctx.dropped {
+            return Ok(Param::Nil);
+        }
+    }
+
+    Ok(value)
+}
+
+fn evaluate(expression: &Expression, ctx: &mut Context) -> Result<Param, HulangError> {
+    let error = |e: Error| HulangError::new(expression.position, e.to_string());
+
+    match &expression.kind {
+        ExpressionKind::Literal(literal) => Ok(literal.into()),
+        ExpressionKind::Variable(name) if name == MESSAGE => Ok(Param::Message),
+        ExpressionKind::Variable(name) => ctx.scope.get(name).ok_or_else(|| {
+            HulangError::new(expression.position, format!("unknown variable {}", name))
+        }),
+        ExpressionKind::Array(values) => Ok(Param::Array(
+            values
+                .iter()
+                .map(|value| evaluate(value, ctx))
+                .collect::<Result<Vec<Param>, HulangError>>()?,
+        )),
+        ExpressionKind::Object(entries) => Ok(Param::Object(
+            entries
+                .iter()
+                .map(|(key, value)| Ok((key.clone(), evaluate(value, ctx)?)))
+                .collect::<Result<BTreeMap<String, Param>, HulangError>>()?,
+        )),
+        ExpressionKind::Index { target, index } => {
+            // Paths into the message are followed in place, rather than
+            // copying every field on the way.
+            if let Some(keys) = expression.message_path() {
+                let keys = evaluate_keys(&keys, ctx)?;
+                return path::get(&ctx.message.fields, &keys)
+                    .map(|value| value.cloned().map(Param::from).unwrap_or_default())
+                    .map_err(error);
+            }
+
+            let target = evaluate(target, ctx)?;
+            let key = evaluate_keys(&[index], ctx)?.remove(0);
+            path::index(target, key).map_err(error)
+        }
+        ExpressionKind::Assign { target, value } => {
+            if let ExpressionKind::Variable(name) = &target.kind {
+                let value = evaluate(value, ctx)?;
+                if !ctx.scope.assign(name, value) {
+                    return Err(error(anyhow!("unknown variable {}", name)));
+                }
+                return Ok(Param::Nil);
+            }
+
+            let keys = evaluate_keys(&target.message_path().unwrap_or_default(), ctx)?;
+            let value = evaluate(value, ctx)?;
+
+            if let Some(Key::Field(name)) = keys.first() {
+                ctx.changing(name);
+            }
+            path::set(&mut ctx.message.fields, &keys, value.into()).map_err(error)?;
+            Ok(Param::Nil)
+        }
+        ExpressionKind::Delete(target) => {
+            let keys = evaluate_keys(&target.message_path().unwrap_or_default(), ctx)?;
+
+            if let Some(Key::Field(name)) = keys.first() {
+                ctx.changing(name);
+            }
+            path::delete(&mut ctx.message.fields, &keys).map_err(error)?;
+            Ok(Param::Nil)
+        }
+        ExpressionKind::Call {
+            function,
+            arguments,
+        } => {
+            let callee = ctx.scope.get(function).ok_or_else(|| {
+                HulangError::new(
+                    expression.position,
+                    format!("unknown function {}", function),
+                )
+            })?;
+            let params = arguments
+                .iter()
+                .map(|argument| evaluate(argument, ctx))
+                .collect::<Result<Vec<Param>, HulangError>>()?;
+
+            if ctx.depth >= MAX_CALL_DEPTH {
+                return Err(error(anyhow!("calls are nested too deeply")));
+            }
+
+            ctx.depth += 1;
+            let result = callee.call(ctx, params);
+            ctx.depth -= 1;
+
+            // Errors in user-defined functions already say where they are.
+            result.map_err(|e| match e.downcast::<HulangError>() {
+                Ok(e) => e,
+                Err(e) => HulangError::new(expression.position, format!("{}: {}", function, e)),
+            })
+        }
+        ExpressionKind::Let { name, value } => {
+            let value = evaluate(value, ctx)?;
+            ctx.scope.define(name, value);
+            Ok(Param::Nil)
+        }
+        ExpressionKind::If {
+            condition,
+            then,
+            otherwise,
+        } => {
+            let branch = match evaluate(condition, ctx)?.is_truthy() {
+                true => then,
+                false => otherwise,
+            };
+
+            let scope = Scope::new(Some(ctx.scope.clone()));
+            ctx.with_scope(scope, |ctx| evaluate_block(branch, ctx))
+        }
+        ExpressionKind::Not(value) => Ok(Param::Bool(!evaluate(value, ctx)?.is_truthy())),
+        ExpressionKind::Binary {
+            operator: operator @ (Operator::And | Operator::Or),
+            left,
+            right,
+        } => {
+            let left = evaluate(left, ctx)?.is_truthy();
+            if left == (*operator == Operator::Or) {
+                return Ok(Param::Bool(left));
+            }
+
+            Ok(Param::Bool(evaluate(right, ctx)?.is_truthy()))
+        }
+        ExpressionKind::Binary {
+            operator,
+            left,
+            right,
+        } => {
+            let left = evaluate(left, ctx)?;
+            let right = evaluate(right, ctx)?;
+
+            let result = match operator {
+                Operator::Equal => left.equals(&right),
+                Operator::NotEqual => !left.equals(&right),
+                Operator::Less => left.compare(&right).map_err(error)?.is_lt(),
+                Operator::LessEqual => left.compare(&right).map_err(error)?.is_le(),
+                Operator::Greater => left.compare(&right).map_err(error)?.is_gt(),
+                Operator::GreaterEqual => left.compare(&right).map_err(error)?.is_ge(),
+                Operator::And | Operator::Or => unreachable!(),
+            };
+            Ok(Param::Bool(result))
+        }
+        ExpressionKind::Function {
+            parameters, body, ..
+        } => {
+            ctx.captured.push(ctx.scope.clone());
+            Ok(Param::Function(function(
+                parameters.clone(),
+                body.clone(),
+                Arc::downgrade(&ctx.scope),
+            )))
+        }
+    }
 }
 
-fn add_key_value(ctx: Context, params: Vec<Param>) -> Result<Param, Error> {
-    assert!(params.len() == 3);
-    assert!(matches!(params[0], Param::Message(_)));
+// A user-defined function, running `body` in a new scope inside `scope`,
+// where it was defined.
+fn function(
+    parameters: Vec<String>,
+    body: Arc<Vec<Expression>>,
+    scope: Weak<Scope>,
+) -> FunctionCall {
+    FunctionCall::new(move |ctx: &mut Context, arguments: Vec<Param>| {
+        expect_arguments(&arguments, &[parameters.len()])?;
 
-    let key = params[1].eval()?;
-    let value = params[2].eval()?;
+        let scope = Scope::new(Some(
+            scope
+                .upgrade()
+                .ok_or_else(|| anyhow!("called after the script ended"))?,
+        ));
+        for (parameter, argument) in parameters.iter().zip(arguments) {
+            scope.define(parameter, argument);
+        }
+
+        Ok(ctx.with_scope(scope, |ctx| evaluate_block(&body, ctx))?)
+    })
+}
+
+fn evaluate_keys(keys: &[&Expression], ctx: &mut Context) -> Result<Vec<Key>, HulangError> {
+    keys.iter()
+        .map(|key| {
+            Key::try_from(evaluate(key, ctx)?)
+                .map_err(|e| HulangError::new(key.position, e.to_string()))
+        })
+        .collect()
+}
+
+// Checks that the statements of a block only use variables and functions
+// defined before them, in the block or around it. `scopes` are the names
+// defined in each scope, from the outermost.
+fn check_block(
+    statements: &[Expression],
+    scopes: &mut Vec<HashSet<String>>,
+) -> Result<(), HulangError> {
+    scopes.push(HashSet::new());
+    let result = statements
+        .iter()
+        .try_for_each(|statement| check(statement, scopes));
+    scopes.pop();
+    result
+}
+
+fn check(expression: &Expression, scopes: &mut Vec<HashSet<String>>) -> Result<(), HulangError> {
+    let defined = |name: &String| scopes.iter().any(|scope| scope.contains(name));
+
+    match &expression.kind {
+        ExpressionKind::Literal(_) => Ok(()),
+        ExpressionKind::Variable(name) if name == MESSAGE || defined(name) => Ok(()),
+        ExpressionKind::Variable(name) => Err(HulangError::new(
+            expression.position,
+            format!("unknown variable {}", name),
+        )),
+        ExpressionKind::Array(values) => values.iter().try_for_each(|value| check(value, scopes)),
+        ExpressionKind::Object(entries) => entries
+            .iter()
+            .try_for_each(|(_, value)| check(value, scopes)),
+        ExpressionKind::Index { target, index } => {
+            check(target, scopes)?;
+            check(index, scopes)
+        }
+        ExpressionKind::Assign { target, value } => {
+            check(target, scopes)?;
+            check(value, scopes)
+        }
+        ExpressionKind::Delete(target) | ExpressionKind::Not(target) => check(target, scopes),
+        ExpressionKind::Call {
+            function,
+            arguments,
+        } => {
+            if !defined(function) {
+                return Err(HulangError::new(
+                    expression.position,
+                    format!("unknown function {}", function),
+                ));
+            }
+
+            arguments
+                .iter()
+                .try_for_each(|argument| check(argument, scopes))
+        }
+        ExpressionKind::Let { name, value } => {
+            // A function can call itself, but a variable's value cannot use
+            // the variable it defines.
+            let recursive = matches!(&value.kind, ExpressionKind::Function { name: Some(_), .. });
+            if !recursive {
+                check(value, scopes)?;
+            }
+
+            scopes.last_mut().unwrap().insert(name.clone());
+            if recursive {
+                check(value, scopes)?;
+            }
+            Ok(())
+        }
+        ExpressionKind::If {
+            condition,
+            then,
+            otherwise,
+        } => {
+            check(condition, scopes)?;
+            check_block(then, scopes)?;
+            check_block(otherwise, scopes)
+        }
+        ExpressionKind::Binary { left, right, .. } => {
+            check(left, scopes)?;
+            check(right, scopes)
+        }
+        ExpressionKind::Function {
+            parameters, body, ..
+        } => {
+            scopes.push(parameters.iter().cloned().collect());
+            let result = check_block(body, scopes);
+            scopes.pop();
+            result
+        }
+    }
+}
+
+fn expect_arguments(params: &[Param], counts: &[usize]) -> Result<(), Error> {
+    if !counts.contains(&params.len()) {
+        let counts: Vec<String> = counts.iter().map(|count| count.to_string()).collect();
+        bail!(
+            "expected {} arguments, got {}",
+            counts.join(" or "),
+            params.len()
+        );
+    }
+    Ok(())
+}
+
+fn expect_message(param: &Param) -> Result<(), Error> {
+    match param {
+        Param::Message => Ok(()),
+        other => Err(anyhow!("expected msg, got {}", other.type_name())),
+    }
+}
+
+fn expect_key(param: Param) -> Result<String, Error> {
+    match param {
+        Param::String(key) => Ok(key),
+        other => Err(anyhow!("expected a field name, got {}", other.type_name())),
+    }
+}
+
+// drop(msg, key) removes a field from the message, and drop(msg) drops the
+// whole message.
+fn drop_key(ctx: &mut Context, params: Vec<Param>) -> Result<Param, Error> {
+    expect_arguments(&params, &[1, 2])?;
+    expect_message(&params[0])?;
+
+    match params.into_iter().nth(1) {
+        Some(key) => {
+            let key = expect_key(key)?;
+            ctx.changing(&key);
+            ctx.message.fields.remove(&key);
+        }
+        None => ctx.dropped = true,
+    }
+
+    Ok(Param::default())
+}
+
+// add(msg, key, value) sets a field of the message.
+fn add_key_value(ctx: &mut Context, params: Vec<Param>) -> Result<Param, Error> {
+    expect_arguments(&params, &[3])?;
+    expect_message(&params[0])?;
 
-    let mut message = match params[0] {
-        Param::Message(m) => m,
-        _ => unreachable!("Should be a message"),
-    };
+    let mut params = params.into_iter().skip(1);
+    let key = expect_key(params.next().unwrap_or_default())?;
+    let value = params.next().unwrap_or_default();
 
-    message.fields.insert(key.eval().unwrap().into(), value.eval().unwrap().into());
+    ctx.changing(&key);
+    ctx.message.fields.insert(key, value.into());
     Ok(Param::default())
 }
+
+// get(msg, key) is the value of a field of the message, or nil if it has no
+// such field.
+fn get_key(ctx: &mut Context, params: Vec<Param>) -> Result<Param, Error> {
+    expect_arguments(&params, &[2])?;
+    expect_message(&params[0])?;
+
+    let key = expect_key(params.into_iter().nth(1).unwrap_or_default())?;
+    Ok(ctx
+        .message
+        .fields
+        .get(&key)
+        .cloned()
+        .map(Param::from)
+        .unwrap_or_default())
+}
+
+// has(msg, key) tells whether the message has a field.
+fn has_key(ctx: &mut Context, params: Vec<Param>) -> Result<Param, Error> {
+    expect_arguments(&params, &[2])?;
+    expect_message(&params[0])?;
+
+    let key = expect_key(params.into_iter().nth(1).unwrap_or_default())?;
+    Ok(Param::Bool(ctx.message.fields.contains_key(&key)))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    // Runs `source` on a message with an `n` field, giving the fields it
+    // leaves.
+    fn run(source: &str, n: i64) -> Result<HashMap<String, Value>, HulangError> {
+        let mut message = Message::new(HashMap::from([("n".to_string(), json!(n))]));
+        assert!(Script::new(source)?.apply(&mut message)?);
+        Ok(message.fields)
+    }
+
+    fn error(source: &str) -> (usize, usize, String) {
+        let e = Script::new(source).err().unwrap();
+        (e.position.line, e.position.column, e.message)
+    }
+
+    #[test]
+    fn unknown_functions_are_rejected_before_running() {
+        assert_eq!(
+            error("msg.a = 1;\nmsg.b = nope(msg.a)"),
+            (2, 9, "unknown function nope".into())
+        );
+        assert_eq!(
+            error("if true { fn f() { 1 } }; f()"),
+            (1, 27, "unknown function f".into())
+        );
+        assert_eq!(
+            error("f(); fn f() { 1 }"),
+            (1, 1, "unknown function f".into())
+        );
+    }
+
+    #[test]
+    fn unknown_variables_are_rejected_before_running() {
+        assert_eq!(error("let a = a"), (1, 9, "unknown variable a".into()));
+        assert_eq!(error("fn f(a) { b }"), (1, 11, "unknown variable b".into()));
+    }
+
+    #[test]
+    fn functions_can_call_themselves_and_builtins() {
+        assert!(Script::new("fn f(n) { if n > 0 { f(n) } else { lower(\"A\") } }").is_ok());
+    }
+
+    #[test]
+    fn let_shadows_variables_of_outer_blocks() {
+        let fields = run(
+            "let a = 1; if true { let a = 2; msg.inner = a }; msg.outer = a;\
+             let a = \"again\"; msg.again = a;\
+             fn f(a) { a }; msg.parameter = f(3); msg.after = a",
+            0,
+        )
+        .unwrap();
+
+        assert_eq!(fields["inner"], json!(2));
+        assert_eq!(fields["outer"], json!(1));
+        assert_eq!(fields["again"], json!("again"));
+        assert_eq!(fields["parameter"], json!(3));
+        assert_eq!(fields["after"], json!("again"));
+    }
+
+    #[test]
+    fn assignments_change_the_variable_they_name() {
+        let fields = run(
+            "let a = 1; let b = 1; if true { a = 2; let b = 3; b = 4 }; msg.a = a; msg.b = b",
+            0,
+        )
+        .unwrap();
+
+        assert_eq!(fields["a"], json!(2));
+        assert_eq!(fields["b"], json!(1));
+    }
+
+    #[test]
+    fn if_takes_the_first_branch_that_holds() {
+        let script = "msg.size = if msg.n > 5 { \"big\" } else if msg.n > 2 { \"medium\" } else { \"small\" };\
+                      if msg.n == 3 { msg.three = true }";
+
+        let sizes: Vec<Value> = [1, 3, 7]
+            .into_iter()
+            .map(|n| run(script, n).unwrap()["size"].clone())
+            .collect();
+        assert_eq!(sizes, vec![json!("small"), json!("medium"), json!("big")]);
+
+        assert_eq!(run(script, 3).unwrap()["three"], json!(true));
+        assert!(!run(script, 1).unwrap().contains_key("three"));
+        assert_eq!(run("msg.x = if false { 1 }", 0).unwrap()["x"], json!(null));
+    }
+
+    #[test]
+    fn and_and_or_only_evaluate_what_they_need() {
+        let fields = run(
+            "let calls = 0; fn f(x) { calls = sum(calls, 1); x };\
+             msg.and = false && f(true); msg.or = true || f(false);\
+             msg.skipped = calls;\
+             msg.both = true && f(1); msg.either = false || f(0);\
+             msg.calls = calls;\
+             msg.safe = msg.n != 0 && div(1, msg.n) > 0",
+            0,
+        )
+        .unwrap();
+
+        assert_eq!(fields["and"], json!(false));
+        assert_eq!(fields["or"], json!(true));
+        assert_eq!(fields["skipped"], json!(0));
+        assert_eq!(fields["both"], json!(true));
+        assert_eq!(fields["either"], json!(false));
+        assert_eq!(fields["calls"], json!(2));
+        assert_eq!(fields["safe"], json!(false));
+    }
+
+    #[test]
+    fn recursion_is_limited_rather_than_overflowing_the_stack() {
+        let countdown =
+            "fn down(n) { if n > 0 { down(sub(n, 1)) } else { \"done\" } }; msg.x = down(msg.n)";
+
+        assert_eq!(run(countdown, 50).unwrap()["x"], json!("done"));
+
+        let error = run(countdown, 1_000_000).unwrap_err();
+        assert_eq!(error.message, "calls are nested too deeply");
+
+        let error = run("fn forever() { forever() }; forever()", 0).unwrap_err();
+        assert_eq!(error.message, "calls are nested too deeply");
+        assert_eq!(error.position.column, 16);
+    }
+}
@@ -0,0 +1,557 @@
+use std::sync::Arc;
+
+use super::{
+    ast::{Expression, ExpressionKind, Literal, Operator, Program, MESSAGE},
+    error::{HulangError, Position},
+    lexer::{self, Token},
+};
+
+/// Parses a script. Statements are separated by `;`, and a trailing `;` is
+/// allowed. The `;` can be left out after an `if` or a function definition.
+pub fn parse(source: &str) -> Result<Program, HulangError> {
+    let mut parser = Parser {
+        tokens: lexer::tokenize(source)?,
+        next: 0,
+    };
+
+    Ok(Program {
+        statements: parser.statements(Token::End)?,
+    })
+}
+
+struct Parser {
+    tokens: Vec<(Token, Position)>,
+    next: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.next].0
+    }
+
+    // Takes the next token. The final `Token::End` is never consumed.
+    fn advance(&mut self) -> (Token, Position) {
+        let token = self.tokens[self.next].clone();
+        if self.next + 1 < self.tokens.len() {
+            self.next += 1;
+        }
+        token
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), HulangError> {
+        match self.advance() {
+            (token, _) if token == expected => Ok(()),
+            (token, position) => Err(HulangError::new(
+                position,
+                format!("expected {} but found {}", expected, token),
+            )),
+        }
+    }
+
+    // Statements up to `end`, which is consumed.
+    fn statements(&mut self, end: Token) -> Result<Vec<Expression>, HulangError> {
+        let mut statements = vec![];
+
+        loop {
+            if self.peek() == &end {
+                self.advance();
+                return Ok(statements);
+            }
+
+            let statement = self.statement()?;
+            let after_block = ends_in_block(&statement);
+            statements.push(statement);
+
+            match self.peek() {
+                Token::Semicolon => {
+                    self.advance();
+                }
+                token if token == &end || after_block => {}
+                _ => {
+                    let (token, position) = self.tokens[self.next].clone();
+                    return Err(HulangError::new(
+                        position,
+                        format!("expected ';' but found {}", token),
+                    ));
+                }
+            }
+        }
+    }
+
+    // A `{ ... }` block of statements.
+    fn block(&mut self) -> Result<Vec<Expression>, HulangError> {
+        self.expect(Token::LeftBrace)?;
+        self.statements(Token::RightBrace)
+    }
+
+    // A definition, an expression, or an assignment to a field of the
+    // message or to a variable.
+    fn statement(&mut self) -> Result<Expression, HulangError> {
+        match (self.peek(), &self.tokens[self.next + 1..]) {
+            (Token::Let, _) => {
+                let (_, position) = self.advance();
+                let name = self.variable_name()?;
+                self.expect(Token::Assign)?;
+
+                return Ok(Expression {
+                    kind: ExpressionKind::Let {
+                        name,
+                        value: Box::new(self.expression()?),
+                    },
+                    position,
+                });
+            }
+            (Token::Fn, [(Token::Identifier(_), _), ..]) => {
+                let (_, position) = self.advance();
+                let name = self.variable_name()?;
+
+                return Ok(Expression {
+                    kind: ExpressionKind::Let {
+                        name: name.clone(),
+                        value: Box::new(self.function(Some(name), position)?),
+                    },
+                    position,
+                });
+            }
+            _ => {}
+        }
+
+        let target = self.expression()?;
+        if self.peek() != &Token::Assign {
+            return Ok(target);
+        }
+
+        let (_, position) = self.advance();
+        let is_variable = matches!(&target.kind, ExpressionKind::Variable(name) if name != MESSAGE);
+        if !is_variable && !is_field(&target) {
+            return Err(HulangError::new(
+                position,
+                "only variables and fields of msg can be assigned to",
+            ));
+        }
+
+        Ok(Expression {
+            position: target.position,
+            kind: ExpressionKind::Assign {
+                target: Box::new(target),
+                value: Box::new(self.expression()?),
+            },
+        })
+    }
+
+    // Operators from the loosest: `||`, then `&&`, then comparisons, which
+    // do not chain.
+    fn expression(&mut self) -> Result<Expression, HulangError> {
+        self.binary(0)
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expression, HulangError> {
+        const LEVELS: [&[(Token, Operator)]; 3] = [
+            &[(Token::Or, Operator::Or)],
+            &[(Token::And, Operator::And)],
+            &[
+                (Token::Equal, Operator::Equal),
+                (Token::NotEqual, Operator::NotEqual),
+                (Token::Less, Operator::Less),
+                (Token::LessEqual, Operator::LessEqual),
+                (Token::Greater, Operator::Greater),
+                (Token::GreaterEqual, Operator::GreaterEqual),
+            ],
+        ];
+
+        let Some(operators) = LEVELS.get(level) else {
+            return self.unary();
+        };
+
+        let mut left = self.binary(level + 1)?;
+        while let Some((_, operator)) = operators.iter().find(|(token, _)| token == self.peek()) {
+            let (_, position) = self.advance();
+            left = Expression {
+                kind: ExpressionKind::Binary {
+                    operator: *operator,
+                    left: Box::new(left),
+                    right: Box::new(self.binary(level + 1)?),
+                },
+                position,
+            };
+
+            if level + 1 == LEVELS.len() {
+                break;
+            }
+        }
+
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expression, HulangError> {
+        if self.peek() != &Token::Not {
+            return self.postfix();
+        }
+
+        let (_, position) = self.advance();
+        Ok(Expression {
+            kind: ExpressionKind::Not(Box::new(self.unary()?)),
+            position,
+        })
+    }
+
+    // A value, followed by any number of `.name` and `[index]`.
+    fn postfix(&mut self) -> Result<Expression, HulangError> {
+        let mut expression = self.primary()?;
+
+        loop {
+            let index = match self.peek() {
+                Token::Dot => {
+                    self.advance();
+                    let (token, position) = self.advance();
+                    let name = field_name(token).map_err(|token| {
+                        HulangError::new(
+                            position,
+                            format!("expected a field name but found {}", token),
+                        )
+                    })?;
+
+                    Expression {
+                        kind: ExpressionKind::Literal(Literal::String(name)),
+                        position,
+                    }
+                }
+                Token::LeftBracket => {
+                    self.advance();
+                    let index = self.expression()?;
+                    self.expect(Token::RightBracket)?;
+                    index
+                }
+                _ => return Ok(expression),
+            };
+
+            expression = Expression {
+                position: expression.position,
+                kind: ExpressionKind::Index {
+                    target: Box::new(expression),
+                    index: Box::new(index),
+                },
+            };
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expression, HulangError> {
+        let (token, position) = self.advance();
+
+        let kind = match token {
+            Token::String(value) => ExpressionKind::Literal(Literal::String(value)),
+            Token::Int(value) => ExpressionKind::Literal(Literal::Int(value)),
+            Token::Float(value) => ExpressionKind::Literal(Literal::Float(value)),
+            Token::True => ExpressionKind::Literal(Literal::Bool(true)),
+            Token::False => ExpressionKind::Literal(Literal::Bool(false)),
+            Token::Nil => ExpressionKind::Literal(Literal::Nil),
+            Token::LeftBracket => ExpressionKind::Array(self.list(Token::RightBracket)?),
+            Token::LeftBrace => ExpressionKind::Object(self.entries()?),
+            Token::LeftParen => {
+                let expression = self.expression()?;
+                self.expect(Token::RightParen)?;
+                return Ok(expression);
+            }
+            Token::If => self.condition()?,
+            Token::Fn => return self.function(None, position),
+            Token::Identifier(name) if self.peek() == &Token::LeftParen => {
+                self.advance();
+                let arguments = self.list(Token::RightParen)?;
+
+                // drop(msg.path) removes a field rather than calling drop.
+                match arguments.as_slice() {
+                    [argument] if name == "drop" && is_field(argument) => {
+                        let argument = arguments.into_iter().next().unwrap();
+                        ExpressionKind::Delete(Box::new(argument))
+                    }
+                    _ => ExpressionKind::Call {
+                        function: name,
+                        arguments,
+                    },
+                }
+            }
+            Token::Identifier(name) => ExpressionKind::Variable(name),
+            token => {
+                return Err(HulangError::new(
+                    position,
+                    format!("expected an expression but found {}", token),
+                ))
+            }
+        };
+
+        Ok(Expression { kind, position })
+    }
+
+    // The rest of an `if`, past the keyword.
+    fn condition(&mut self) -> Result<ExpressionKind, HulangError> {
+        let condition = self.expression()?;
+        let then = self.block()?;
+
+        let otherwise = match self.peek() {
+            Token::Else => {
+                self.advance();
+                if self.peek() == &Token::If {
+                    let (_, position) = self.advance();
+                    vec![Expression {
+                        kind: self.condition()?,
+                        position,
+                    }]
+                } else {
+                    self.block()?
+                }
+            }
+            _ => vec![],
+        };
+
+        Ok(ExpressionKind::If {
+            condition: Box::new(condition),
+            then,
+            otherwise,
+        })
+    }
+
+    // The parameters and body of a function, past `fn` and its name.
+    fn function(
+        &mut self,
+        name: Option<String>,
+        position: Position,
+    ) -> Result<Expression, HulangError> {
+        self.expect(Token::LeftParen)?;
+
+        let mut parameters = vec![];
+        loop {
+            if self.peek() == &Token::RightParen {
+                self.advance();
+                break;
+            }
+
+            let (_, position) = self.tokens[self.next].clone();
+            let parameter = self.variable_name()?;
+            if parameters.contains(&parameter) {
+                return Err(HulangError::new(
+                    position,
+                    format!("duplicate parameter {}", parameter),
+                ));
+            }
+            parameters.push(parameter);
+
+            match self.advance() {
+                (Token::Comma, _) => {}
+                (Token::RightParen, _) => break,
+                (token, position) => {
+                    return Err(HulangError::new(
+                        position,
+                        format!("expected ',' or ')' but found {}", token),
+                    ))
+                }
+            }
+        }
+
+        Ok(Expression {
+            kind: ExpressionKind::Function {
+                name,
+                parameters,
+                body: Arc::new(self.block()?),
+            },
+            position,
+        })
+    }
+
+    // The name of a variable, function or parameter being defined.
+    fn variable_name(&mut self) -> Result<String, HulangError> {
+        match self.advance() {
+            (Token::Identifier(name), position) if name == MESSAGE => Err(HulangError::new(
+                position,
+                format!("{} cannot be redefined", MESSAGE),
+            )),
+            (Token::Identifier(name), _) => Ok(name),
+            (token, position) => Err(HulangError::new(
+                position,
+                format!("expected a name but found {}", token),
+            )),
+        }
+    }
+
+    // Expressions separated by commas up to `end`, past the token opening
+    // them. A trailing comma is allowed.
+    fn list(&mut self, end: Token) -> Result<Vec<Expression>, HulangError> {
+        let mut expressions = vec![];
+
+        loop {
+            if self.peek() == &end {
+                self.advance();
+                return Ok(expressions);
+            }
+
+            expressions.push(self.expression()?);
+
+            match self.advance() {
+                (Token::Comma, _) => {}
+                (token, _) if token == end => return Ok(expressions),
+                (token, position) => {
+                    return Err(HulangError::new(
+                        position,
+                        format!("expected ',' or {} but found {}", end, token),
+                    ))
+                }
+            }
+        }
+    }
+
+    // The `key: value` entries of an object, past its opening brace. Keys
+    // are strings, or names.
+    fn entries(&mut self) -> Result<Vec<(String, Expression)>, HulangError> {
+        let mut entries = vec![];
+
+        loop {
+            let key = match self.advance() {
+                (Token::RightBrace, _) => return Ok(entries),
+                (Token::String(key), _) => key,
+                (token, position) => field_name(token).map_err(|token| {
+                    HulangError::new(position, format!("expected a key but found {}", token))
+                })?,
+            };
+
+            self.expect(Token::Colon)?;
+            entries.push((key, self.expression()?));
+
+            match self.advance() {
+                (Token::Comma, _) => {}
+                (Token::RightBrace, _) => return Ok(entries),
+                (token, position) => {
+                    return Err(HulangError::new(
+                        position,
+                        format!("expected ',' or '}}' but found {}", token),
+                    ))
+                }
+            }
+        }
+    }
+}
+
+// Names, including keywords, can be used as field names and keys.
+fn field_name(token: Token) -> Result<String, Token> {
+    match token {
+        Token::Identifier(name) => Ok(name),
+        Token::True
+        | Token::False
+        | Token::Nil
+        | Token::Let
+        | Token::If
+        | Token::Else
+        | Token::Fn => Ok(token.to_string()),
+        token => Err(token),
+    }
+}
+
+// Whether a statement ends in a block of its own, which is enough to tell
+// where it ends. Other statements ending with `}`, like an object, need a `;`.
+fn ends_in_block(statement: &Expression) -> bool {
+    match &statement.kind {
+        ExpressionKind::If { .. } => true,
+        ExpressionKind::Let { value, .. } => {
+            matches!(value.kind, ExpressionKind::Function { name: Some(_), .. })
+        }
+        _ => false,
+    }
+}
+
+// A field of the message, as opposed to the message itself or another value.
+fn is_field(expression: &Expression) -> bool {
+    expression
+        .message_path()
+        .is_some_and(|keys| !keys.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(source: &str) -> (usize, usize, String) {
+        let e = parse(source).unwrap_err();
+        (e.position.line, e.position.column, e.message)
+    }
+
+    #[test]
+    fn trailing_commas_are_allowed() {
+        assert_eq!(parse("[1, 2,]").unwrap(), parse("[1, 2]").unwrap());
+        assert_eq!(
+            parse("{a: 1, b: 2,}").unwrap(),
+            parse("{a: 1, b: 2}").unwrap()
+        );
+        assert_eq!(parse("f(1, 2,)").unwrap(), parse("f(1, 2)").unwrap());
+        assert_eq!(
+            parse("fn f(a, b,) { a }").unwrap(),
+            parse("fn f(a, b ) { a }").unwrap()
+        );
+    }
+
+    #[test]
+    fn a_comma_alone_is_not_a_list() {
+        assert_eq!(
+            error("[,]"),
+            (1, 2, "expected an expression but found ','".into())
+        );
+        assert_eq!(
+            error("f(1,,)"),
+            (1, 5, "expected an expression but found ','".into())
+        );
+    }
+
+    #[test]
+    fn ifs_and_function_definitions_need_no_semicolon() {
+        let program = parse("if msg.a { 1 } else { 2 } fn f() { 1 } msg.b = f()").unwrap();
+        assert_eq!(program.statements.len(), 3);
+    }
+
+    #[test]
+    fn errors_say_where_they_are() {
+        assert_eq!(
+            error("msg.a = 1;\nmsg.b = ;"),
+            (2, 9, "expected an expression but found ';'".into())
+        );
+        assert_eq!(
+            error("msg.a = 1\nmsg.b = 2"),
+            (2, 1, "expected ';' but found msg".into())
+        );
+        assert_eq!(
+            error("msg.a = {} msg.b = 1"),
+            (1, 12, "expected ';' but found msg".into())
+        );
+        assert_eq!(
+            error("let f = fn() { 1 } msg.b = 1"),
+            (1, 20, "expected ';' but found msg".into())
+        );
+        assert_eq!(
+            error("let msg = 1"),
+            (1, 5, "msg cannot be redefined".into())
+        );
+        assert_eq!(
+            error("fn f(a, a) { a }"),
+            (1, 9, "duplicate parameter a".into())
+        );
+    }
+
+    #[test]
+    fn lexer_errors_keep_their_position() {
+        assert_eq!(
+            error("msg.a = 1;\n  msg.b = \"abc"),
+            (2, 11, "unterminated string".into())
+        );
+    }
+
+    #[test]
+    fn paths_into_the_message_are_indexes() {
+        let program = parse("msg.a[0]").unwrap();
+        let path = program.statements[0].message_path().unwrap();
+
+        let keys: Vec<&ExpressionKind> = path.iter().map(|key| &key.kind).collect();
+        assert_eq!(
+            keys,
+            vec![
+                &ExpressionKind::Literal(Literal::String("a".into())),
+                &ExpressionKind::Literal(Literal::Int(0)),
+            ]
+        );
+    }
+}
@@ -0,0 +1,358 @@
+use std::{collections::HashMap, fmt::Display};
+
+use anyhow::{anyhow, bail, Error};
+use serde_json::Value;
+
+use super::Param;
+
+/// One step of a path: a key into an object, or a position in an array.
+/// Negative positions count from the end.
+#[derive(Debug, Clone)]
+pub enum Key {
+    Field(String),
+    Index(i64),
+}
+
+impl TryFrom<Param> for Key {
+    type Error = Error;
+
+    fn try_from(param: Param) -> Result<Self, Self::Error> {
+        match param {
+            Param::String(name) => Ok(Key::Field(name)),
+            Param::Int(index) => Ok(Key::Index(index)),
+            other => Err(anyhow!("cannot index with {}", other.type_name())),
+        }
+    }
+}
+
+impl Display for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Key::Field(name) => write!(f, "{:?}", name),
+            Key::Index(index) => write!(f, "{}", index),
+        }
+    }
+}
+
+// The position `index` refers to in an array of `len` values, if any.
+fn position(index: i64, len: usize) -> Option<usize> {
+    let position = if index < 0 { len as i64 + index } else { index };
+
+    (0..len as i64)
+        .contains(&position)
+        .then_some(position as usize)
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "nil",
+        Value::Bool(_) => "bool",
+        Value::Number(number) if number.is_i64() => "int",
+        Value::Number(_) => "float",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+// The first key into the message fields, which must be a field name.
+fn split_fields(keys: &[Key]) -> Result<(&String, &[Key]), Error> {
+    match keys.split_first() {
+        Some((Key::Field(name), rest)) => Ok((name, rest)),
+        Some((key, _)) => bail!("cannot index msg with {}", key),
+        None => bail!("expected a field of msg"),
+    }
+}
+
+/// The value at `keys` in the message fields, or None if there is nothing
+/// there.
+pub fn get<'a>(
+    fields: &'a HashMap<String, Value>,
+    keys: &[Key],
+) -> Result<Option<&'a Value>, Error> {
+    let (name, rest) = split_fields(keys)?;
+    let mut value = fields.get(name);
+
+    for key in rest {
+        value = match (value, key) {
+            (None | Some(Value::Null), _) => return Ok(None),
+            (Some(Value::Object(entries)), Key::Field(name)) => entries.get(name),
+            (Some(Value::Array(values)), Key::Index(index)) => {
+                position(*index, values.len()).map(|position| &values[position])
+            }
+            (Some(other), key) => bail!("cannot index {} with {}", type_name(other), key),
+        };
+    }
+
+    Ok(value)
+}
+
+/// Sets the value at `keys` in the message fields. Missing objects on the
+/// way are created, but arrays must already have the positions indexed.
+/// Nothing changes if the value cannot be set.
+pub fn set(fields: &mut HashMap<String, Value>, keys: &[Key], value: Value) -> Result<(), Error> {
+    let (name, rest) = split_fields(keys)?;
+    match fields.get_mut(name) {
+        Some(slot) => set_in(slot, rest, value),
+        None => {
+            let mut slot = Value::Null;
+            set_in(&mut slot, rest, value)?;
+            fields.insert(name.clone(), slot);
+            Ok(())
+        }
+    }
+}
+
+fn set_in(slot: &mut Value, keys: &[Key], value: Value) -> Result<(), Error> {
+    let Some((key, rest)) = keys.split_first() else {
+        *slot = value;
+        return Ok(());
+    };
+
+    // Missing values on the way are only added once the rest of the path has
+    // been set, so that nothing is left behind should it fail.
+    if slot.is_null() && matches!(key, Key::Field(_)) {
+        let mut object = Value::Object(Default::default());
+        set_in(&mut object, keys, value)?;
+        *slot = object;
+        return Ok(());
+    }
+
+    let next = match (slot, key) {
+        (Value::Object(entries), Key::Field(name)) => {
+            if !entries.contains_key(name) {
+                let mut next = Value::Null;
+                set_in(&mut next, rest, value)?;
+                entries.insert(name.clone(), next);
+                return Ok(());
+            }
+            &mut entries[name]
+        }
+        (Value::Array(values), Key::Index(index)) => {
+            let len = values.len();
+            let position = position(*index, len)
+                .ok_or_else(|| anyhow!("index {} is out of range for {} values", index, len))?;
+            &mut values[position]
+        }
+        (other, key) => bail!("cannot index {} with {}", type_name(other), key),
+    };
+
+    set_in(next, rest, value)
+}
+
+/// Removes the value at `keys` from the message fields. Removing something
+/// that is not there does nothing.
+pub fn delete(fields: &mut HashMap<String, Value>, keys: &[Key]) -> Result<(), Error> {
+    let (name, rest) = split_fields(keys)?;
+    let Some((last, parents)) = rest.split_last() else {
+        fields.remove(name);
+        return Ok(());
+    };
+
+    let mut value = fields.get_mut(name);
+    for key in parents {
+        value = match (value, key) {
+            (None | Some(Value::Null), _) => return Ok(()),
+            (Some(Value::Object(entries)), Key::Field(name)) => entries.get_mut(name),
+            (Some(Value::Array(values)), Key::Index(index)) => {
+                position(*index, values.len()).map(|position| &mut values[position])
+            }
+            (Some(other), key) => bail!("cannot index {} with {}", type_name(other), key),
+        };
+    }
+
+    match (value, last) {
+        (None | Some(Value::Null), _) => {}
+        (Some(Value::Object(entries)), Key::Field(name)) => {
+            entries.remove(name);
+        }
+        (Some(Value::Array(values)), Key::Index(index)) => {
+            if let Some(position) = position(*index, values.len()) {
+                values.remove(position);
+            }
+        }
+        (Some(other), key) => bail!("cannot index {} with {}", type_name(other), key),
+    }
+
+    Ok(())
+}
+
+/// The value at `key` in `param`, or nil if there is nothing there.
+pub fn index(param: Param, key: Key) -> Result<Param, Error> {
+    match (param, key) {
+        (Param::Nil, _) => Ok(Param::Nil),
+        (Param::Object(mut entries), Key::Field(name)) => {
+            Ok(entries.remove(&name).unwrap_or_default())
+        }
+        (Param::Array(mut values), Key::Index(index)) => Ok(position(index, values.len())
+            .map(|position| values.swap_remove(position))
+            .unwrap_or_default()),
+        (other, key) => bail!("cannot index {} with {}", other.type_name(), key),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::messaging::message::Message;
+
+    use super::super::Script;
+    use super::*;
+
+    // Keys for a dotted path, numbers being positions.
+    fn keys(path: &str) -> Vec<Key> {
+        path.split('.')
+            .map(|key| match key.parse() {
+                Ok(index) => Key::Index(index),
+                Err(_) => Key::Field(key.to_string()),
+            })
+            .collect()
+    }
+
+    fn fields() -> HashMap<String, Value> {
+        serde_json::from_value(json!({
+            "a": {
+                "b": [{"c": 0}, {"c": 1}, {"c": 2}],
+                "s": "text",
+            },
+            "n": null,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn get_follows_objects_and_arrays() {
+        let fields = fields();
+
+        assert_eq!(get(&fields, &keys("a.b.2.c")).unwrap(), Some(&json!(2)));
+        assert_eq!(get(&fields, &keys("a.b.-1.c")).unwrap(), Some(&json!(2)));
+        assert_eq!(get(&fields, &keys("a.s")).unwrap(), Some(&json!("text")));
+    }
+
+    #[test]
+    fn get_gives_nothing_for_missing_values() {
+        let fields = fields();
+
+        for path in [
+            "z.b.2.c", "a.z.2.c", "a.b.3.c", "a.b.-4.c", "a.b.2.z", "n.b.2.c",
+        ] {
+            assert_eq!(get(&fields, &keys(path)).unwrap(), None, "{}", path);
+        }
+    }
+
+    #[test]
+    fn get_fails_through_values_that_cannot_be_indexed() {
+        let fields = fields();
+        let error = |path| get(&fields, &keys(path)).unwrap_err().to_string();
+
+        assert_eq!(error("a.s.2.c"), "cannot index string with 2");
+        assert_eq!(error("a.b.c"), "cannot index array with \"c\"");
+        assert_eq!(error("a.b.0.c.d"), "cannot index int with \"d\"");
+        assert_eq!(error("0"), "cannot index msg with 0");
+    }
+
+    #[test]
+    fn set_creates_missing_objects() {
+        let mut fields = fields();
+
+        set(&mut fields, &keys("z.y.x"), json!(1)).unwrap();
+        set(&mut fields, &keys("n.y"), json!(2)).unwrap();
+        set(&mut fields, &keys("a.b.1.d.e"), json!(3)).unwrap();
+
+        assert_eq!(fields["z"], json!({"y": {"x": 1}}));
+        assert_eq!(fields["n"], json!({"y": 2}));
+        assert_eq!(fields["a"]["b"][1], json!({"c": 1, "d": {"e": 3}}));
+    }
+
+    #[test]
+    fn set_does_not_create_array_positions() {
+        let mut fields = fields();
+        let mut error = |path| {
+            set(&mut fields, &keys(path), json!(1))
+                .unwrap_err()
+                .to_string()
+        };
+
+        assert_eq!(error("a.b.3.c"), "index 3 is out of range for 3 values");
+        assert_eq!(error("z.0"), "cannot index nil with 0");
+        assert_eq!(error("a.s.x"), "cannot index string with \"x\"");
+        assert_eq!(error("a.q.0"), "cannot index nil with 0");
+        assert_eq!(error("z.x.y.0"), "cannot index nil with 0");
+        assert_eq!(error("n.x.0"), "cannot index nil with 0");
+        assert_eq!(fields, self::fields());
+    }
+
+    #[test]
+    fn delete_removes_values() {
+        let mut fields = fields();
+
+        delete(&mut fields, &keys("a.b.-1")).unwrap();
+        delete(&mut fields, &keys("a.b.0.c")).unwrap();
+        delete(&mut fields, &keys("n")).unwrap();
+
+        assert_eq!(fields["a"]["b"], json!([{}, {"c": 1}]));
+        assert!(!fields.contains_key("n"));
+    }
+
+    #[test]
+    fn delete_ignores_what_is_not_there() {
+        let mut fields = fields();
+
+        for path in ["a.b.3", "a.b.-4", "a.b.3.c", "a.z.y", "z", "n.y"] {
+            delete(&mut fields, &keys(path)).unwrap();
+        }
+
+        assert_eq!(fields, self::fields());
+        assert_eq!(
+            delete(&mut fields, &keys("a.s.x")).unwrap_err().to_string(),
+            "cannot index string with \"x\""
+        );
+    }
+
+    #[test]
+    fn index_gives_nil_for_missing_values() {
+        let array = Param::Array(vec![Param::Int(1), Param::Int(2)]);
+
+        assert!(index(array.clone(), Key::Index(-1))
+            .unwrap()
+            .equals(&Param::Int(2)));
+        assert!(index(array.clone(), Key::Index(2))
+            .unwrap()
+            .equals(&Param::Nil));
+        assert!(index(Param::Nil, Key::Field("a".into()))
+            .unwrap()
+            .equals(&Param::Nil));
+        assert_eq!(
+            index(array, Key::Field("a".into()))
+                .unwrap_err()
+                .to_string(),
+            "cannot index array with \"a\""
+        );
+    }
+
+    #[test]
+    fn scripts_follow_paths_into_the_message() {
+        let script = Script::new(
+            "msg.x = msg.a.b[2].c; msg.y = msg.z.b[2].c; msg.a.d.e = 1; drop(msg.a.b[7])",
+        )
+        .unwrap();
+        let mut message = Message::new(fields());
+        assert!(script.apply(&mut message).unwrap());
+        let fields = message.fields;
+
+        assert_eq!(fields["x"], json!(2));
+        assert_eq!(fields["y"], json!(null));
+        assert_eq!(fields["a"]["d"], json!({"e": 1}));
+        assert_eq!(fields["a"]["b"], self::fields()["a"]["b"]);
+
+        let error = Script::new("msg.x = 1;\nmsg.y = msg.a.s[2].c")
+            .unwrap()
+            .apply(&mut Message::new(fields))
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "line 2, column 9: cannot index string with 2"
+        );
+    }
+}
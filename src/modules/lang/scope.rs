@@ -0,0 +1,60 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use super::Param;
+
+/// Variables defined in a block, in a function call, or at the top of a
+/// script, along with the scope around it. The builtin functions are in the
+/// outermost scope.
+#[derive(Default)]
+pub struct Scope {
+    variables: Mutex<HashMap<String, Param>>,
+    parent: Option<Arc<Scope>>,
+}
+
+impl Scope {
+    pub fn new(parent: Option<Arc<Scope>>) -> Arc<Scope> {
+        Arc::new(Scope {
+            variables: Default::default(),
+            parent,
+        })
+    }
+
+    /// The value of the variable `name` in this scope or the closest scope
+    /// around it defining it.
+    pub fn get(&self, name: &str) -> Option<Param> {
+        match self.variables.lock().unwrap().get(name) {
+            Some(value) => Some(value.clone()),
+            None => self.parent.as_ref()?.get(name),
+        }
+    }
+
+    /// Defines the variable `name` in this scope, replacing any variable of
+    /// the same name in it.
+    pub fn define(&self, name: &str, value: Param) {
+        self.variables
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), value);
+    }
+
+    /// Sets the variable `name` where it is defined. Returns false if it is
+    /// not defined.
+    pub fn assign(&self, name: &str, value: Param) -> bool {
+        let mut variables = self.variables.lock().unwrap();
+        match variables.get_mut(name) {
+            Some(variable) => {
+                *variable = value;
+                true
+            }
+            None => {
+                drop(variables);
+                self.parent
+                    .as_ref()
+                    .is_some_and(|parent| parent.assign(name, value))
+            }
+        }
+    }
+}
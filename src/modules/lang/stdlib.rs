@@ -0,0 +1,760 @@
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, bail, Error};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, SecondsFormat, Utc};
+use md5::Md5;
+use regex::Regex;
+use sha1::{Digest, Sha1};
+use sha2::{Sha256, Sha512};
+
+use super::{expect_arguments, Context, FunctionCall, Param};
+
+// Compiled regular expressions kept around, by pattern.
+const MAX_CACHED_REGEXES: usize = 256;
+
+/// Registers the builtin functions that do not deal with the message itself.
+pub(super) fn register(functions: &mut HashMap<String, FunctionCall>) {
+    let mut add = |name: &str, function: FunctionCall| {
+        functions.insert(name.to_string(), function);
+    };
+
+    // Strings.
+    add(
+        "lower",
+        pure(|[s]| Ok(Param::String(string(s)?.to_lowercase()))),
+    );
+    add(
+        "upper",
+        pure(|[s]| Ok(Param::String(string(s)?.to_uppercase()))),
+    );
+    add(
+        "trim",
+        pure(|[s]| Ok(Param::String(string(s)?.trim().to_string()))),
+    );
+    add("split", pure(split));
+    add("join", pure(join));
+    add("replace", pure(replace));
+    add("contains", pure(contains));
+    add(
+        "starts_with",
+        pure(|[s, prefix]| Ok(Param::Bool(string(s)?.starts_with(&string(prefix)?)))),
+    );
+    add(
+        "ends_with",
+        pure(|[s, suffix]| Ok(Param::Bool(string(s)?.ends_with(&string(suffix)?)))),
+    );
+    add("len", pure(len));
+
+    // Arrays and objects.
+    add("map", FunctionCall::new(map));
+    add("filter", FunctionCall::new(filter));
+    add("push", pure(push));
+    add("keys", pure(keys));
+    add("values", pure(values));
+
+    // Regular expressions.
+    let regexes = Arc::new(Regexes::default());
+    add("matches", regex_function(&regexes, matches));
+    add("capture", regex_function(&regexes, capture));
+    add("replace_regex", regex_function(&regexes, replace_regex));
+
+    // Arithmetic.
+    add("sum", FunctionCall::new(|_, params| sum(params)));
+    add(
+        "sub",
+        pure(|[a, b]| arithmetic(a, b, "-", i64::checked_sub, |a, b| a - b)),
+    );
+    add(
+        "mul",
+        pure(|[a, b]| arithmetic(a, b, "*", i64::checked_mul, |a, b| a * b)),
+    );
+    add("div", pure(divide));
+    add("mod", pure(remainder));
+    add("abs", pure(absolute));
+    add("round", pure(|[x]| Ok(Param::Int(rounded(x, f64::round)?))));
+    add("floor", pure(|[x]| Ok(Param::Int(rounded(x, f64::floor)?))));
+    add("ceil", pure(|[x]| Ok(Param::Int(rounded(x, f64::ceil)?))));
+    add(
+        "min",
+        FunctionCall::new(|_, params| extreme(params, Ordering::Less)),
+    );
+    add(
+        "max",
+        FunctionCall::new(|_, params| extreme(params, Ordering::Greater)),
+    );
+
+    // Comparisons.
+    add("eq", pure(|[a, b]| Ok(Param::Bool(a.equals(&b)))));
+    add("ne", pure(|[a, b]| Ok(Param::Bool(!a.equals(&b)))));
+    add("lt", pure(|[a, b]| Ok(Param::Bool(a.compare(&b)?.is_lt()))));
+    add("le", pure(|[a, b]| Ok(Param::Bool(a.compare(&b)?.is_le()))));
+    add("gt", pure(|[a, b]| Ok(Param::Bool(a.compare(&b)?.is_gt()))));
+    add("ge", pure(|[a, b]| Ok(Param::Bool(a.compare(&b)?.is_ge()))));
+
+    // Casts.
+    add("string", pure(|[x]| Ok(Param::String(text(x)))));
+    add("int", pure(to_int));
+    add("float", pure(to_float));
+    add("bool", pure(to_bool));
+    add(
+        "type_of",
+        pure(|[x]| Ok(Param::String(x.type_name().to_string()))),
+    );
+
+    // Time, as milliseconds since the Unix epoch.
+    add(
+        "now",
+        pure(|[]| Ok(Param::Int(Utc::now().timestamp_millis()))),
+    );
+    add(
+        "parse_time",
+        FunctionCall::new(|_, params| parse_time(params)),
+    );
+    add(
+        "format_time",
+        FunctionCall::new(|_, params| format_time(params)),
+    );
+
+    // JSON.
+    add("parse_json", pure(parse_json));
+    add(
+        "to_json",
+        pure(|[x]| Ok(Param::String(serde_json::Value::from(x).to_string()))),
+    );
+
+    // Hashing, as lowercase hex.
+    add("md5", pure(|[x]| Ok(hash::<Md5>(x))));
+    add("sha1", pure(|[x]| Ok(hash::<Sha1>(x))));
+    add("sha256", pure(|[x]| Ok(hash::<Sha256>(x))));
+    add("sha512", pure(|[x]| Ok(hash::<Sha512>(x))));
+}
+
+// A function of exactly N arguments that does not touch the context.
+fn pure<const N: usize>(
+    function: impl Fn([Param; N]) -> Result<Param, Error> + Send + Sync + 'static,
+) -> FunctionCall {
+    FunctionCall::new(move |_, params| function(arguments(params)?))
+}
+
+fn arguments<const N: usize>(params: Vec<Param>) -> Result<[Param; N], Error> {
+    let count = params.len();
+    params
+        .try_into()
+        .map_err(|_| anyhow!("expected {} arguments, got {}", N, count))
+}
+
+fn array(param: Param) -> Result<Vec<Param>, Error> {
+    match param {
+        Param::Array(values) => Ok(values),
+        other => Err(anyhow!("expected an array, got {}", other.type_name())),
+    }
+}
+
+fn object(param: Param) -> Result<BTreeMap<String, Param>, Error> {
+    match param {
+        Param::Object(entries) => Ok(entries),
+        other => Err(anyhow!("expected an object, got {}", other.type_name())),
+    }
+}
+
+fn string(param: Param) -> Result<String, Error> {
+    match param {
+        Param::String(s) => Ok(s),
+        other => Err(anyhow!("expected a string, got {}", other.type_name())),
+    }
+}
+
+fn int(param: Param) -> Result<i64, Error> {
+    match param {
+        Param::Int(i) => Ok(i),
+        other => Err(anyhow!("expected an int, got {}", other.type_name())),
+    }
+}
+
+fn float(param: Param) -> Result<f64, Error> {
+    match param {
+        Param::Int(i) => Ok(i as f64),
+        Param::Float(f) => Ok(f),
+        other => Err(anyhow!("expected a number, got {}", other.type_name())),
+    }
+}
+
+// Strings as they are, anything else as JSON.
+fn text(param: Param) -> String {
+    match param {
+        Param::String(s) => s,
+        other => serde_json::Value::from(other).to_string(),
+    }
+}
+
+fn split([s, separator]: [Param; 2]) -> Result<Param, Error> {
+    let s = string(s)?;
+    let separator = string(separator)?;
+
+    Ok(Param::Array(
+        s.split(separator.as_str())
+            .map(|part| Param::String(part.to_string()))
+            .collect(),
+    ))
+}
+
+fn join([parts, separator]: [Param; 2]) -> Result<Param, Error> {
+    let parts: Vec<String> = array(parts)?.into_iter().map(text).collect();
+    Ok(Param::String(parts.join(&string(separator)?)))
+}
+
+fn replace([s, from, to]: [Param; 3]) -> Result<Param, Error> {
+    Ok(Param::String(
+        string(s)?.replace(&string(from)?, &string(to)?),
+    ))
+}
+
+// contains(string, part), or contains(array, value).
+fn contains([haystack, needle]: [Param; 2]) -> Result<Param, Error> {
+    match haystack {
+        Param::String(s) => Ok(Param::Bool(s.contains(&string(needle)?))),
+        Param::Array(values) => Ok(Param::Bool(
+            values.iter().any(|value| value.equals(&needle)),
+        )),
+        other => bail!("expected a string or an array, got {}", other.type_name()),
+    }
+}
+
+// Characters in a string, values in an array, or keys in an object.
+fn len([x]: [Param; 1]) -> Result<Param, Error> {
+    let len = match x {
+        Param::String(s) => s.chars().count(),
+        Param::Array(values) => values.len(),
+        Param::Object(entries) => entries.len(),
+        other => bail!("{} has no length", other.type_name()),
+    };
+
+    Ok(Param::Int(len as i64))
+}
+
+// map(array, function) calls the function with every value, and gives an
+// array of what it returns.
+fn map(ctx: &mut Context, params: Vec<Param>) -> Result<Param, Error> {
+    let [values, function] = arguments(params)?;
+
+    let values = array(values)?
+        .into_iter()
+        .map(|value| function.call(ctx, vec![value]))
+        .collect::<Result<Vec<Param>, Error>>()?;
+
+    Ok(Param::Array(values))
+}
+
+// filter(array, function) keeps the values the function returns true for.
+fn filter(ctx: &mut Context, params: Vec<Param>) -> Result<Param, Error> {
+    let [values, function] = arguments(params)?;
+
+    let mut kept = vec![];
+    for value in array(values)? {
+        if function.call(ctx, vec![value.clone()])?.is_truthy() {
+            kept.push(value);
+        }
+    }
+
+    Ok(Param::Array(kept))
+}
+
+// push(array, value) is the array with the value added at the end. Nil is
+// taken for an empty array.
+fn push([values, value]: [Param; 2]) -> Result<Param, Error> {
+    let mut values = match values {
+        Param::Nil => vec![],
+        values => array(values)?,
+    };
+
+    values.push(value);
+    Ok(Param::Array(values))
+}
+
+fn keys([entries]: [Param; 1]) -> Result<Param, Error> {
+    Ok(Param::Array(
+        object(entries)?.into_keys().map(Param::String).collect(),
+    ))
+}
+
+fn values([entries]: [Param; 1]) -> Result<Param, Error> {
+    Ok(Param::Array(object(entries)?.into_values().collect()))
+}
+
+#[derive(Default)]
+struct Regexes(Mutex<HashMap<String, Regex>>);
+
+impl Regexes {
+    fn get(&self, pattern: &str) -> Result<Regex, Error> {
+        let mut regexes = self.0.lock().unwrap();
+        if let Some(regex) = regexes.get(pattern) {
+            return Ok(regex.clone());
+        }
+
+        let regex = Regex::new(pattern)?;
+        if regexes.len() >= MAX_CACHED_REGEXES {
+            regexes.clear();
+        }
+        regexes.insert(pattern.to_string(), regex.clone());
+
+        Ok(regex)
+    }
+}
+
+// A function taking a string, a pattern and N more arguments.
+fn regex_function<const N: usize>(
+    regexes: &Arc<Regexes>,
+    function: fn(&str, &Regex, [Param; N]) -> Result<Param, Error>,
+) -> FunctionCall {
+    let regexes = regexes.clone();
+    FunctionCall::new(move |_: &mut Context, params| {
+        expect_arguments(&params, &[N + 2])?;
+
+        let mut params = params.into_iter();
+        let s = string(params.next().unwrap_or_default())?;
+        let regex = regexes.get(&string(params.next().unwrap_or_default())?)?;
+        let rest: [Param; N] = params
+            .collect::<Vec<Param>>()
+            .try_into()
+            .map_err(|_| anyhow!("expected {} arguments", N + 2))?;
+
+        function(&s, &regex, rest)
+    })
+}
+
+fn matches(s: &str, regex: &Regex, _: [Param; 0]) -> Result<Param, Error> {
+    Ok(Param::Bool(regex.is_match(s)))
+}
+
+// The groups of the first match, the whole match first, or nil without a
+// match. Groups that did not take part in the match are nil.
+fn capture(s: &str, regex: &Regex, _: [Param; 0]) -> Result<Param, Error> {
+    let Some(captures) = regex.captures(s) else {
+        return Ok(Param::Nil);
+    };
+
+    Ok(Param::Array(
+        captures
+            .iter()
+            .map(|group| {
+                group
+                    .map(|group| Param::String(group.as_str().to_string()))
+                    .unwrap_or_default()
+            })
+            .collect(),
+    ))
+}
+
+// Replaces every match. The replacement can refer to groups as $1 or $name.
+fn replace_regex(s: &str, regex: &Regex, [replacement]: [Param; 1]) -> Result<Param, Error> {
+    Ok(Param::String(
+        regex
+            .replace_all(s, string(replacement)?.as_str())
+            .into_owned(),
+    ))
+}
+
+fn sum(params: Vec<Param>) -> Result<Param, Error> {
+    let mut params = params.into_iter();
+    let Some(first) = params.next() else {
+        bail!("expected at least 1 argument");
+    };
+
+    params.try_fold(first, Param::add)
+}
+
+// Ints stay ints, unless the result does not fit. Anything else with a float
+// gives a float.
+fn arithmetic(
+    a: Param,
+    b: Param,
+    operator: &str,
+    ints: fn(i64, i64) -> Option<i64>,
+    floats: fn(f64, f64) -> f64,
+) -> Result<Param, Error> {
+    match (&a, &b) {
+        (Param::Int(x), Param::Int(y)) => ints(*x, *y)
+            .map(Param::Int)
+            .ok_or_else(|| anyhow!("{} {} {} overflows", x, operator, y)),
+        _ => Ok(Param::Float(floats(float(a)?, float(b)?))),
+    }
+}
+
+// Ints are divided into ints, rounding towards zero.
+fn divide([a, b]: [Param; 2]) -> Result<Param, Error> {
+    if matches!(b, Param::Int(0)) {
+        bail!("division by zero");
+    }
+    arithmetic(a, b, "/", i64::checked_div, |a, b| a / b)
+}
+
+fn remainder([a, b]: [Param; 2]) -> Result<Param, Error> {
+    if matches!(b, Param::Int(0)) {
+        bail!("division by zero");
+    }
+    arithmetic(a, b, "%", i64::checked_rem, |a, b| a % b)
+}
+
+fn absolute([x]: [Param; 1]) -> Result<Param, Error> {
+    match x {
+        Param::Int(i) => i
+            .checked_abs()
+            .map(Param::Int)
+            .ok_or_else(|| anyhow!("abs({}) overflows", i)),
+        other => Ok(Param::Float(float(other)?.abs())),
+    }
+}
+
+fn rounded(x: Param, round: fn(f64) -> f64) -> Result<i64, Error> {
+    match x {
+        Param::Int(i) => Ok(i),
+        other => float_to_int(round(float(other)?)),
+    }
+}
+
+fn float_to_int(f: f64) -> Result<i64, Error> {
+    if !f.is_finite() || f < i64::MIN as f64 || f > i64::MAX as f64 {
+        bail!("{} does not fit in an int", f);
+    }
+    Ok(f as i64)
+}
+
+// The smallest or largest of the arguments, depending on `wanted`.
+fn extreme(params: Vec<Param>, wanted: Ordering) -> Result<Param, Error> {
+    let mut params = params.into_iter();
+    let Some(mut best) = params.next() else {
+        bail!("expected at least 1 argument");
+    };
+
+    for param in params {
+        if param.compare(&best)? == wanted {
+            best = param;
+        }
+    }
+
+    Ok(best)
+}
+
+fn to_int([x]: [Param; 1]) -> Result<Param, Error> {
+    let i = match x {
+        Param::Int(i) => i,
+        Param::Float(f) => float_to_int(f.trunc())?,
+        Param::Bool(b) => b as i64,
+        Param::String(s) => match s.trim().parse::<i64>() {
+            Ok(i) => i,
+            Err(_) => s
+                .trim()
+                .parse::<f64>()
+                .map_err(|_| anyhow!("{:?} is not a number", s))
+                .and_then(|f| float_to_int(f.trunc()))?,
+        },
+        other => bail!("cannot turn {} into an int", other.type_name()),
+    };
+
+    Ok(Param::Int(i))
+}
+
+fn to_float([x]: [Param; 1]) -> Result<Param, Error> {
+    let f = match x {
+        Param::Bool(b) => b as i64 as f64,
+        Param::String(s) => s
+            .trim()
+            .parse()
+            .map_err(|_| anyhow!("{:?} is not a number", s))?,
+        Param::Int(i) => i as f64,
+        Param::Float(f) => f,
+        other => bail!("cannot turn {} into a float", other.type_name()),
+    };
+
+    Ok(Param::Float(f))
+}
+
+fn to_bool([x]: [Param; 1]) -> Result<Param, Error> {
+    match x {
+        Param::Function(_) | Param::Message => {
+            bail!("cannot turn {} into a bool", x.type_name())
+        }
+        x => Ok(Param::Bool(x.is_truthy())),
+    }
+}
+
+// parse_time(s) reads an RFC 3339 timestamp, and parse_time(s, format) one in
+// the given strftime format, taken as UTC if it has no offset.
+fn parse_time(params: Vec<Param>) -> Result<Param, Error> {
+    expect_arguments(&params, &[1, 2])?;
+
+    let mut params = params.into_iter();
+    let s = string(params.next().unwrap_or_default())?;
+    let time = match params.next() {
+        None => DateTime::parse_from_rfc3339(&s)
+            .map(|time| time.to_utc())
+            .map_err(|e| anyhow!("{:?} is not an RFC 3339 timestamp: {}", s, e))?,
+        Some(format) => {
+            let format = string(format)?;
+            DateTime::parse_from_str(&s, &format)
+                .map(|time| time.to_utc())
+                .or_else(|_| NaiveDateTime::parse_from_str(&s, &format).map(|time| time.and_utc()))
+                .or_else(|_| {
+                    NaiveDate::parse_from_str(&s, &format)
+                        .map(|date| date.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc())
+                })
+                .map_err(|e| anyhow!("{:?} does not match {:?}: {}", s, format, e))?
+        }
+    };
+
+    Ok(Param::Int(time.timestamp_millis()))
+}
+
+// format_time(ms) writes an RFC 3339 timestamp in UTC, and
+// format_time(ms, format) one in the given strftime format.
+fn format_time(params: Vec<Param>) -> Result<Param, Error> {
+    expect_arguments(&params, &[1, 2])?;
+
+    let mut params = params.into_iter();
+    let millis = int(params.next().unwrap_or_default())?;
+    let time = DateTime::<Utc>::from_timestamp_millis(millis)
+        .ok_or_else(|| anyhow!("{} is out of range", millis))?;
+
+    let formatted = match params.next() {
+        None => time.to_rfc3339_opts(SecondsFormat::Millis, true),
+        Some(format) => {
+            let format = string(format)?;
+            let mut formatted = String::new();
+            write!(formatted, "{}", time.format(&format))
+                .map_err(|_| anyhow!("invalid time format {:?}", format))?;
+            formatted
+        }
+    };
+
+    Ok(Param::String(formatted))
+}
+
+fn parse_json([s]: [Param; 1]) -> Result<Param, Error> {
+    let value: serde_json::Value = serde_json::from_str(&string(s)?)?;
+    Ok(value.into())
+}
+
+fn hash<D: Digest>(x: Param) -> Param {
+    let digest = D::digest(text(x).as_bytes());
+    Param::String(digest.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use crate::messaging::message::Message;
+
+    use super::super::{error::HulangError, Script};
+    use super::*;
+
+    // Runs `source` on a message with a `text` field, giving the fields it
+    // leaves.
+    fn run(source: &str) -> Result<HashMap<String, Value>, HulangError> {
+        let mut message = Message::new(HashMap::from([(
+            "text".to_string(),
+            json!("  Hello, World  "),
+        )]));
+
+        assert!(Script::new(source)?.apply(&mut message)?);
+        Ok(message.fields)
+    }
+
+    fn field(source: &str) -> Value {
+        run(source).unwrap()["x"].clone()
+    }
+
+    fn error(source: &str) -> String {
+        run(source).unwrap_err().message
+    }
+
+    #[test]
+    fn strings() {
+        assert_eq!(field("msg.x = lower(msg.text)"), json!("  hello, world  "));
+        assert_eq!(field("msg.x = upper(msg.text)"), json!("  HELLO, WORLD  "));
+        assert_eq!(field("msg.x = trim(msg.text)"), json!("Hello, World"));
+        assert_eq!(
+            field(r#"msg.x = split(trim(msg.text), ", ")"#),
+            json!(["Hello", "World"])
+        );
+        assert_eq!(field(r#"msg.x = split("", ",")"#), json!([""]));
+        assert_eq!(
+            field(r#"msg.x = replace(msg.text, "l", "L")"#),
+            json!("  HeLLo, WorLd  ")
+        );
+        assert_eq!(
+            error("msg.x = lower(1)"),
+            "lower: expected a string, got int"
+        );
+    }
+
+    #[test]
+    fn map_and_filter_call_user_functions() {
+        assert_eq!(
+            field("fn double(x) { mul(x, 2) }; msg.x = map([1, 2, 3], double)"),
+            json!([2, 4, 6])
+        );
+        assert_eq!(
+            field("let n = 10; msg.x = filter([5, 15, 20], fn(x) { x > n })"),
+            json!([15, 20])
+        );
+        assert_eq!(
+            field("msg.x = map(filter([1, 2, 3, 4], fn(x) { mod(x, 2) }), fn(x) { [x] })"),
+            json!([[1], [3]])
+        );
+        assert_eq!(field("msg.x = map([], fn(x) { div(x, 0) })"), json!([]));
+    }
+
+    #[test]
+    fn map_and_filter_errors() {
+        assert_eq!(
+            error("msg.x = map([1], fn(x) { div(x, 0) })"),
+            "div: division by zero"
+        );
+        assert_eq!(
+            error("msg.x = filter([1], fn(a, b) { a })"),
+            "filter: expected 2 arguments, got 1"
+        );
+        assert_eq!(
+            error("msg.x = map([1], 1)"),
+            "map: expected a function, got int"
+        );
+        assert_eq!(
+            error("msg.x = map(1, lower)"),
+            "map: expected an array, got int"
+        );
+    }
+
+    #[test]
+    fn regexes() {
+        assert_eq!(field(r#"msg.x = matches(msg.text, "W\\w+")"#), json!(true));
+        assert_eq!(field(r#"msg.x = matches(msg.text, "^W")"#), json!(false));
+        assert_eq!(
+            field(r#"msg.x = capture("key=value", "(\\w+)=(\\w+)(;)?")"#),
+            json!(["key=value", "key", "value", null])
+        );
+        assert_eq!(field(r#"msg.x = capture("none", "\\d")"#), json!(null));
+        assert_eq!(
+            field(r#"msg.x = replace_regex("a1b22", "(\\d+)", "<$1>")"#),
+            json!("a<1>b<22>")
+        );
+        assert!(error(r#"msg.x = matches("a", "(")"#).starts_with("matches: regex parse error"));
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(field("msg.x = sum(1, 2, 3)"), json!(6));
+        assert_eq!(field("msg.x = sum(1, 2.5)"), json!(3.5));
+        assert_eq!(field("msg.x = sub(10, 3)"), json!(7));
+        assert_eq!(field("msg.x = mul(4, 2.5)"), json!(10.0));
+        assert_eq!(field("msg.x = div(7, 2)"), json!(3));
+        assert_eq!(field("msg.x = div(-7, 2)"), json!(-3));
+        assert_eq!(field("msg.x = div(7.0, 2)"), json!(3.5));
+        assert_eq!(field("msg.x = mod(7, 3)"), json!(1));
+        assert_eq!(field("msg.x = mod(-7, 3)"), json!(-1));
+    }
+
+    #[test]
+    fn arithmetic_errors() {
+        assert_eq!(error("msg.x = div(1, 0)"), "div: division by zero");
+        assert_eq!(error("msg.x = mod(1, 0)"), "mod: division by zero");
+        assert_eq!(
+            error("msg.x = mod(-9223372036854775808, -1)"),
+            "mod: -9223372036854775808 % -1 overflows"
+        );
+        assert_eq!(
+            error("msg.x = div(-9223372036854775808, -1)"),
+            "div: -9223372036854775808 / -1 overflows"
+        );
+        assert_eq!(
+            error("msg.x = mul(9223372036854775807, 2)"),
+            "mul: 9223372036854775807 * 2 overflows"
+        );
+        assert_eq!(error("msg.x = sum()"), "sum: expected at least 1 argument");
+    }
+
+    #[test]
+    fn times() {
+        assert_eq!(
+            field(r#"msg.x = parse_time("2024-01-02T03:04:05.678Z")"#),
+            json!(1704164645678i64)
+        );
+        assert_eq!(
+            field(r#"msg.x = parse_time("2024-01-02T05:04:05+02:00")"#),
+            json!(1704164645000i64)
+        );
+        assert_eq!(
+            field(r#"msg.x = parse_time("02/01/2024 03:04", "%d/%m/%Y %H:%M")"#),
+            json!(1704164640000i64)
+        );
+        assert_eq!(
+            field(r#"msg.x = parse_time("2024-01-02", "%Y-%m-%d")"#),
+            json!(1704153600000i64)
+        );
+        assert_eq!(
+            field("msg.x = format_time(1704164645678)"),
+            json!("2024-01-02T03:04:05.678Z")
+        );
+        assert_eq!(
+            field(r#"msg.x = format_time(1704164645678, "%Y/%m/%d %H:%M")"#),
+            json!("2024/01/02 03:04")
+        );
+    }
+
+    #[test]
+    fn time_errors() {
+        assert!(error(r#"msg.x = parse_time("yesterday")"#)
+            .starts_with(r#"parse_time: "yesterday" is not an RFC 3339 timestamp"#));
+        assert!(error(r#"msg.x = parse_time("2024", "%d/%m")"#)
+            .starts_with(r#"parse_time: "2024" does not match "%d/%m""#));
+        assert_eq!(
+            error(r#"msg.x = format_time(0, "%Q")"#),
+            r#"format_time: invalid time format "%Q""#
+        );
+    }
+
+    #[test]
+    fn json() {
+        assert_eq!(
+            field(r#"msg.x = to_json({b: [1, "two", nil], a: true})"#),
+            json!(r#"{"a":true,"b":[1,"two",null]}"#)
+        );
+        assert_eq!(
+            field(r#"msg.x = parse_json("{\"a\": [1, 2.5, {\"b\": null}]}")"#),
+            json!({"a": [1, 2.5, {"b": null}]})
+        );
+        assert_eq!(
+            field(r#"msg.x = parse_json(to_json({a: [1, "two"]}))"#),
+            json!({"a": [1, "two"]})
+        );
+        assert!(error(r#"msg.x = parse_json("{")"#).starts_with("parse_json: "));
+    }
+
+    #[test]
+    fn hashes() {
+        assert_eq!(
+            field(r#"msg.x = md5("abc")"#),
+            json!("900150983cd24fb0d6963f7d28e17f72")
+        );
+        assert_eq!(
+            field(r#"msg.x = sha1("abc")"#),
+            json!("a9993e364706816aba3e25717850c26c9cd0d89d")
+        );
+        assert_eq!(
+            field(r#"msg.x = sha256("abc")"#),
+            json!("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+        );
+        assert_eq!(
+            field(r#"msg.x = sha512("abc")"#),
+            json!(
+                "ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a\
+                 2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f"
+            )
+        );
+    }
+}
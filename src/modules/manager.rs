@@ -1,89 +1,525 @@
-use futures::stream::FuturesUnordered;
-use futures::StreamExt;
 use std::collections::{HashMap, HashSet};
-use tokio::task::JoinHandle;
+use std::fmt::Display;
+use std::time::Duration;
 
-use crate::configuration::global_configuration::GlobalConfiguration;
+use async_channel::Receiver;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::task::{AbortHandle, JoinHandle};
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::configuration::error::ConfigurationError;
+use crate::configuration::global_configuration::{
+    remote_destination, GlobalConfiguration, RouteConfiguration,
+};
+use crate::configuration::module_properties::ModuleProperties;
+use crate::messaging::channel::{self, Outbox};
 use crate::messaging::message::Message;
+use crate::modules::module::ModuleTrait;
 use crate::modules::registry::ModulesRegistry;
+use crate::modules::router;
+use crate::modules::supervisor::Supervisor;
+use crate::node::{Node, REMOTE_INBOX};
 
 pub struct Manager {
     configuration: GlobalConfiguration,
 }
 
+// A module that has been started, and has not stopped for good yet.
+struct RunningModule {
+    // Tells apart a module from the one it replaced on a reload.
+    generation: u64,
+    shutdown: CancellationToken,
+    has_inboxes: bool,
+    supervisor: AbortHandle,
+    handle: JoinHandle<()>,
+}
+
+// The channels of a route. They are kept for as long as the route exists, so
+// that restarted modules are wired to the same channels as before.
+struct RunningRoute {
+    outbox: Outbox,
+    inboxes: Vec<(String, Receiver<Message>)>,
+}
+
+impl RunningRoute {
+    fn new(stage_id: Uuid, name: &str, route: &RouteConfiguration, node: &mut Node) -> Self {
+        let (outbox, receiver) = channel::channel(stage_id, name, route);
+
+        // Hand out the route's messages to its destinations. Destinations on
+        // other nodes are fed by the node, local ones by their module.
+        let destinations = route.to.get_modules();
+        let receivers = router::dispatch(stage_id, name, route, receiver, destinations.len());
+
+        let mut inboxes = vec![];
+        for (destination, receiver) in destinations.into_iter().zip(receivers) {
+            match remote_destination(&destination) {
+                Some((module, peer)) => node.forward(module, peer, receiver),
+                None => inboxes.push((destination, receiver)),
+            }
+        }
+
+        RunningRoute {
+            outbox: outbox.with_route(name),
+            inboxes,
+        }
+    }
+}
+
+// The modules a configuration could not be applied for, as they could not be
+// built from it.
+struct ApplyError(Vec<(String, ConfigurationError)>);
+
+impl Display for ApplyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, (name, e)) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, "; ")?;
+            }
+            write!(f, "module {}: {}", name, e)?;
+        }
+
+        Ok(())
+    }
+}
+
+// A module built for a new configuration, along with the properties it was
+// built from.
+type Built = (ModuleProperties, Box<dyn ModuleTrait>);
+
+// Everything the manager has started, along with the configuration it was
+// started from.
+struct State {
+    configuration: GlobalConfiguration,
+    shutdown: CancellationToken,
+    modules: HashMap<String, RunningModule>,
+    routes: HashMap<String, RunningRoute>,
+    node: Node,
+    generation: u64,
+    exited: UnboundedSender<(String, u64)>,
+}
+
 impl Manager {
     pub fn new(configuration: GlobalConfiguration) -> Self {
         Manager { configuration }
     }
 
-    pub fn run(self) -> JoinHandle<()> {
-        let configuration = self.configuration; // Move configuration out of self
-
+    /// Runs all the configured modules until they finish, or until `shutdown`
+    /// is cancelled. On shutdown the sources stop first, and the sinks are
+    /// given the configured drain timeout to process what is still in flight.
+    ///
+    /// New configurations received on `reloads` are applied while running:
+    /// only the modules and routes that changed are stopped, started or
+    /// rewired, everything else keeps running untouched.
+    pub fn run(
+        self,
+        shutdown: CancellationToken,
+        mut reloads: UnboundedReceiver<GlobalConfiguration>,
+    ) -> JoinHandle<()> {
         tokio::spawn(async move {
-            // Parse the configuration for the modules, and start them.
-            let modconfigs = configuration.modules;
-            let mut modules: HashMap<String, Box<dyn crate::modules::module::ModuleTrait>> =
-                HashMap::new();
+            let (exited, mut exits) = mpsc::unbounded_channel();
+            let node = Node::start(&self.configuration, shutdown.clone()).await;
 
-            for (init_name, configuration) in modconfigs {
-                let module =
-                    ModulesRegistry::get_module(&configuration.module_type.clone(), configuration);
+            let mut state = State {
+                configuration: GlobalConfiguration {
+                    node: self.configuration.node.clone(),
+                    cluster: self.configuration.cluster.clone(),
+                    ..GlobalConfiguration::default()
+                },
+                shutdown: shutdown.clone(),
+                modules: HashMap::new(),
+                routes: HashMap::new(),
+                node,
+                generation: 0,
+                exited,
+            };
 
-                modules.insert(init_name, module);
+            // Starting up is applying the configuration over an empty one.
+            if let Err(e) = state.apply(self.configuration).await {
+                println!("Could not start: {}", e);
+            }
+            if state.modules.is_empty() {
+                return;
             }
 
-            // All modules are ready to run. Next, we generate the routes to push
-            // to the modules. We will use senders/receivers from async-channel crate.
-            let (sender, receiver) = async_channel::unbounded::<Message>();
-
-            let mut routable_modules = HashSet::new();
+            // Sources stop as soon as shutdown is requested, which closes their
+            // routes once they are drained. Sinks finish when their inboxes close,
+            // or are aborted when the drain timeout runs out.
+            let drain_deadline = tokio::time::sleep(Duration::MAX);
+            tokio::pin!(drain_deadline);
+            let mut draining = false;
 
-            for (name, route_config) in configuration.routes {
-                // Check if we have the modules from "from" and "to".
-                for module_name in route_config.from.get_modules().into_iter() {
-                    if !modules.contains_key(&module_name) {
-                        panic!("Module {} not found for route {}", module_name, name);
+            loop {
+                tokio::select! {
+                    Some((name, generation)) = exits.recv() => {
+                        state.exited(name, generation);
+                        if state.modules.is_empty() {
+                            break;
+                        }
+                    }
+                    Some(configuration) = reloads.recv(), if !draining => {
+                        if let Err(e) = state.apply(configuration).await {
+                            println!("Not reloading, keeping the running configuration: {}", e);
+                        }
                     }
+                    _ = shutdown.cancelled(), if !draining => {
+                        println!("Shutting down, draining in-flight messages");
+                        draining = true;
 
-                    let module = modules.get_mut(&module_name).unwrap();
-                    module.set_outbox(Some(sender.clone()));
-                    routable_modules.insert(module_name);
+                        // Only the sources may keep routes open from now on.
+                        state.routes.clear();
+                        state.node.close_inboxes();
+                        drain_deadline
+                            .as_mut()
+                            .reset(tokio::time::Instant::now() + state.drain_timeout());
+                    }
+                    _ = &mut drain_deadline, if draining => {
+                        println!(
+                            "Drain timeout reached, stopping {} remaining modules",
+                            state.modules.len()
+                        );
+                        state
+                            .modules
+                            .values()
+                            .for_each(|module| module.supervisor.abort());
+                        break;
+                    }
                 }
+            }
 
-                for module_name in route_config.to.get_modules().into_iter() {
-                    if !modules.contains_key(&module_name) {
-                        panic!("Module {} not found for route {}", module_name, name);
-                    }
+            // Messages sent to other nodes get what is left of the drain
+            // timeout to be acknowledged.
+            let deadline = match draining {
+                true => drain_deadline.deadline(),
+                false => tokio::time::Instant::now() + state.drain_timeout(),
+            };
+            state.node.drain(deadline).await;
+        })
+    }
+}
+
+impl State {
+    fn drain_timeout(&self) -> Duration {
+        Duration::from_millis(self.configuration.shutdown.drain_timeout_ms)
+    }
+
+    /// Moves from the current configuration to `configuration`. Modules that
+    /// changed, and modules on both ends of a route that changed, are
+    /// restarted. Messages still waiting on a changed or removed route are
+    /// lost.
+    ///
+    /// The modules to restart are all built before anything is stopped. If
+    /// any of them cannot be built, nothing changes.
+    async fn apply(&mut self, mut configuration: GlobalConfiguration) -> Result<(), ApplyError> {
+        if configuration.node != self.configuration.node
+            || configuration.cluster != self.configuration.cluster
+        {
+            println!("Changes to [node] and [cluster] are only applied on restart");
+            configuration.node = self.configuration.node.clone();
+            configuration.cluster = self.configuration.cluster.clone();
+        }
+
+        let old = &self.configuration;
+
+        let changed_modules: HashSet<String> = old
+            .modules
+            .keys()
+            .chain(configuration.modules.keys())
+            .filter(
+                |name| match (old.modules.get(*name), configuration.modules.get(*name)) {
+                    (Some(old), Some(new)) => !old.same_configuration(new),
+                    _ => true,
+                },
+            )
+            .cloned()
+            .collect();
+
+        let changed_routes: HashSet<String> = old
+            .routes
+            .keys()
+            .chain(configuration.routes.keys())
+            .filter(
+                |name| match (old.routes.get(*name), configuration.routes.get(*name)) {
+                    (Some(old), Some(new)) => !old.same_configuration(new),
+                    _ => true,
+                },
+            )
+            .cloned()
+            .collect();
+
+        let mut restart = changed_modules;
+        for name in &changed_routes {
+            for route in [old.routes.get(name), configuration.routes.get(name)]
+                .into_iter()
+                .flatten()
+            {
+                restart.extend(route.from.get_modules());
+                restart.extend(route.to.get_modules());
+            }
+        }
+
+        let mut restart: Vec<String> = restart.into_iter().collect();
+        restart.sort();
+        let mut built = self.build(&configuration, &restart)?;
+
+        let stopping: Vec<(String, RunningModule)> = restart
+            .iter()
+            .filter_map(|name| self.modules.remove_entry(name))
+            .collect();
+        self.stop(stopping).await;
+
+        for name in &changed_routes {
+            self.routes.remove(name);
+            if let Some(route) = configuration.routes.get(name) {
+                let running =
+                    RunningRoute::new(configuration.stage_id, name, route, &mut self.node);
+                self.routes.insert(name.clone(), running);
+            }
+        }
+
+        self.configuration = configuration;
 
-                    let module = modules.get_mut(&module_name).unwrap();
-                    module.set_inbox(Some(receiver.clone()));
-                    routable_modules.insert(module_name);
+        for name in restart {
+            match built.remove(&name) {
+                Some((properties, module)) => self.start(name, properties, module),
+                None => self.node.remove_inbox(&name),
+            }
+        }
+
+        Ok(())
+    }
+
+    // Builds the modules among `names` that `configuration` has. Each one gets
+    // its own shutdown token, so that it can be stopped on its own when the
+    // configuration changes.
+    fn build(
+        &self,
+        configuration: &GlobalConfiguration,
+        names: &[String],
+    ) -> Result<HashMap<String, Built>, ApplyError> {
+        let mut built = HashMap::new();
+        let mut failed = vec![];
+
+        for name in names {
+            let Some(properties) = configuration.modules.get(name) else {
+                continue;
+            };
+
+            let mut properties = properties.clone();
+            properties.shutdown = self.shutdown.child_token();
+            match ModulesRegistry::get_module(&properties.module_type, properties.clone()) {
+                Ok(module) => {
+                    built.insert(name.clone(), (properties, module));
                 }
+                Err(e) => failed.push((name.clone(), e)),
             }
+        }
 
-            // We can fire up the modules now! Exciting stuff going to happen next!
-            // We will use futuresunordered to run all the modules concurrently.
+        match failed.is_empty() {
+            true => Ok(built),
+            false => Err(ApplyError(failed)),
+        }
+    }
+
+    fn start(&mut self, name: String, properties: ModuleProperties, module: Box<dyn ModuleTrait>) {
+        let shutdown = properties.shutdown.clone();
+        let uuid = properties.uuid;
+        let receives = ModulesRegistry::capabilities(&properties.module_type)
+            .is_some_and(|capabilities| capabilities.inbox);
+
+        // Every route has its own channel, so that messages sent on one route
+        // never show up on another one. A module that takes part in several
+        // routes gets one outbox or inbox per route.
+        let mut supervisor = Supervisor::new(name.clone(), properties);
+        for (route_name, route) in &self.configuration.routes {
+            let Some(running) = self.routes.get(route_name) else {
+                continue;
+            };
 
-            let mut handles = FuturesUnordered::new();
-            for (name, module) in modules.drain() {
-                if routable_modules.contains(&name) {
-                    handles.push(module.run());
-                    println!("Module {} is running", name);
-                } else {
-                    println!("Module {} is not configured, but has no routes for it. It will not be run.", name);
+            if route.from.get_modules().contains(&name) {
+                supervisor.add_outbox(route_name.clone(), running.outbox.with_source(&name, uuid));
+            }
+
+            for (destination, inbox) in &running.inboxes {
+                if *destination == name {
+                    supervisor.add_inbox(route_name.clone(), inbox.clone());
                 }
             }
+        }
 
-            while let Some(handle) = handles.next().await {
-                match handle {
-                    Ok(_) => {
-                        println!("Module finished successfully");
-                    }
-                    Err(e) => {
-                        println!("Module failed with error: {:?}", e);
-                    }
+        // Modules that can receive messages also receive them from other
+        // nodes, when this one listens for them.
+        if receives && self.node.is_listening() {
+            supervisor.add_inbox(REMOTE_INBOX.into(), self.node.inbox(&name));
+        }
+
+        if !supervisor.is_routed() {
+            println!(
+                "Module {} is configured, but has no routes for it. It will not be run.",
+                name
+            );
+            return;
+        }
+
+        let module = supervisor.wire(module);
+        let has_inboxes = supervisor.has_inboxes();
+        let handle = supervisor.run(module);
+        let abort = handle.abort_handle();
+
+        self.generation += 1;
+        let generation = self.generation;
+        let exited = self.exited.clone();
+        let module_name = name.clone();
+        let handle = tokio::spawn(async move {
+            if let Err(e) = handle.await {
+                if !e.is_cancelled() {
+                    println!("Module {} failed with error: {:?}", module_name, e);
                 }
             }
-        })
+
+            let _ = exited.send((module_name, generation));
+        });
+
+        println!("Module {} is running", name);
+        self.modules.insert(
+            name,
+            RunningModule {
+                generation,
+                shutdown,
+                has_inboxes,
+                supervisor: abort,
+                handle,
+            },
+        );
+    }
+
+    // Sources are given the drain timeout to stop. Modules with inboxes would
+    // only stop once their routes close, so they are stopped right away; what
+    // is waiting on their routes is left for the module replacing them.
+    async fn stop(&self, modules: Vec<(String, RunningModule)>) {
+        for (_, module) in &modules {
+            module.shutdown.cancel();
+            if module.has_inboxes {
+                module.supervisor.abort();
+            }
+        }
+
+        let deadline = tokio::time::Instant::now() + self.drain_timeout();
+        for (name, mut module) in modules {
+            let timed_out = tokio::time::timeout_at(deadline, &mut module.handle)
+                .await
+                .is_err();
+
+            // Supervisors only report modules that stopped on their own.
+            if timed_out {
+                module.supervisor.abort();
+                let _ = module.handle.await;
+            }
+
+            if timed_out || module.has_inboxes {
+                println!("Module {} stopped", name);
+            }
+        }
+    }
+
+    /// Forgets about a module that stopped for good. Routes left without any
+    /// running source are closed, so that their destinations can finish.
+    fn exited(&mut self, name: String, generation: u64) {
+        if self
+            .modules
+            .get(&name)
+            .is_none_or(|module| module.generation != generation)
+        {
+            return;
+        }
+
+        self.modules.remove(&name);
+
+        for (route_name, route) in &self.configuration.routes {
+            let sources = route.from.get_modules();
+            if sources.contains(&name)
+                && !sources
+                    .iter()
+                    .any(|source| self.modules.contains_key(source))
+            {
+                self.routes.remove(route_name);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RUNNING: &str = r#"
+        [modules.input]
+        module_type = "udpsocketlistener"
+        address = "127.0.0.1"
+        port = 0
+        buffer_size = 1024
+
+        [modules.output]
+        module_type = "echo"
+
+        [routes.main]
+        from = { Single = "input" }
+        to = { Single = "output" }
+    "#;
+
+    async fn state() -> State {
+        let configuration = GlobalConfiguration::default();
+        let shutdown = CancellationToken::new();
+        let (exited, _) = mpsc::unbounded_channel();
+
+        State {
+            node: Node::start(&configuration, shutdown.clone()).await,
+            configuration,
+            shutdown,
+            modules: HashMap::new(),
+            routes: HashMap::new(),
+            generation: 0,
+            exited,
+        }
+    }
+
+    #[tokio::test]
+    async fn nothing_changes_when_a_restarted_module_cannot_be_built() {
+        let mut state = state().await;
+        state
+            .apply(toml::from_str(RUNNING).unwrap())
+            .await
+            .unwrap_or_else(|e| panic!("{}", e));
+        let generations: HashMap<String, u64> = state
+            .modules
+            .iter()
+            .map(|(name, module)| (name.clone(), module.generation))
+            .collect();
+
+        let broken = RUNNING.replace(
+            "module_type = \"echo\"",
+            "module_type = \"hulang\"\nscript_file = \"/nonexistent/script.hl\"",
+        );
+        let error = state
+            .apply(toml::from_str(&broken).unwrap())
+            .await
+            .unwrap_err();
+
+        assert!(
+            error
+                .to_string()
+                .starts_with("module output: invalid setting script_file: "),
+            "{}",
+            error
+        );
+        assert_eq!(state.configuration.modules["output"].module_type, "echo");
+        assert_eq!(state.routes.keys().collect::<Vec<_>>(), vec!["main"]);
+        for (name, module) in &state.modules {
+            assert_eq!(generations[name], module.generation);
+            assert!(!module.shutdown.is_cancelled());
+        }
+        assert_eq!(state.modules.len(), 2);
+
+        state.shutdown.cancel();
     }
 }
@@ -1,10 +1,16 @@
+pub mod lang;
+pub mod manager;
 pub mod module;
 pub mod registry;
-pub mod manager;
+pub mod router;
+pub mod supervisor;
+pub mod tls;
 
 pub mod echo_module;
-pub mod udpsocket;
-pub mod tcpsocket;
-pub mod tcpwriter;
+pub mod hulang;
 pub mod infinite_sender;
 pub mod stdinwriter;
+pub mod tcpsocket;
+pub mod tcpwriter;
+pub mod udpsocket;
+pub mod udpwriter;
@@ -1,18 +1,25 @@
-use async_channel::{Receiver, Sender};
+use async_channel::Receiver;
 use tokio::task::JoinHandle;
 
-use crate::{configuration::module_properties::ModuleProperties, messaging::message::Message};
+use crate::{
+    configuration::{error::ConfigurationError, module_properties::ModuleProperties},
+    messaging::{channel::Outbox, message::Message},
+};
 
 pub trait ModuleTrait: Sync + Send {
-    fn new(configuration: ModuleProperties) -> Self
+    fn new(configuration: ModuleProperties) -> Result<Self, ConfigurationError>
     where
         Self: Sized;
 
-    fn set_outbox(&mut self, _outbox: Option<Sender<Message>>) {
+    /// Adds an outbox for the route called `name`. A module that is a source
+    /// on several routes gets one outbox per route.
+    fn add_outbox(&mut self, _name: String, _outbox: Outbox) {
         unimplemented!("This module does not support an outbox");
     }
 
-    fn set_inbox(&mut self, _inbox: Option<Receiver<Message>>) {
+    /// Adds an inbox for the route called `name`. A module that is a
+    /// destination on several routes gets one inbox per route.
+    fn add_inbox(&mut self, _name: String, _inbox: Receiver<Message>) {
         unimplemented!("This module does not support an inbox");
     }
 
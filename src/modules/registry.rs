@@ -1,24 +1,165 @@
-use crate::{configuration::module_properties::ModuleProperties, modules::echo_module::EchoModule};
+use serde::de::DeserializeOwned;
 
-use super::{
-    infinite_sender::InfiniteSender, module::ModuleTrait, stdinwriter::StdinWriter,
-    tcpsocket::TCPSocketListener, tcpwriter::TCPSocketWriter, udpsocket::UDPSocketListener,
+use crate::{
+    configuration::{error::ConfigurationError, module_properties::ModuleProperties},
+    plugins::{self, PluginModule},
 };
 
+use super::module::ModuleTrait;
+
+/// What a module type can be wired to in a route.
+#[derive(Debug, Clone, Copy)]
+pub struct Capabilities {
+    /// The module can be a destination of a route.
+    pub inbox: bool,
+    /// The module can be a source of a route.
+    pub outbox: bool,
+}
+
+impl Capabilities {
+    pub const SOURCE: Capabilities = Capabilities {
+        inbox: false,
+        outbox: true,
+    };
+
+    pub const SINK: Capabilities = Capabilities {
+        inbox: true,
+        outbox: false,
+    };
+
+    /// Both a destination and a source, for modules transforming messages.
+    pub const PROCESSOR: Capabilities = Capabilities {
+        inbox: true,
+        outbox: true,
+    };
+}
+
+type Constructor = fn(ModuleProperties) -> Result<Box<dyn ModuleTrait>, ConfigurationError>;
+type Validator = fn(&ModuleProperties) -> Result<(), ConfigurationError>;
+
+/// A module type that can be used in a configuration. Every module submits
+/// one of these with `inventory::submit!` next to its implementation, which is
+/// all it takes to make it available under `name`.
+pub struct ModuleRegistration {
+    /// The `module_type` used in configurations.
+    pub name: &'static str,
+    pub description: &'static str,
+    pub constructor: Constructor,
+    /// Checks the module settings without building the module, so that
+    /// checking a configuration has no side effects.
+    pub validate: Validator,
+    /// Returns the module settings with their default values.
+    pub settings: fn() -> serde_json::Value,
+    pub capabilities: Capabilities,
+}
+
+inventory::collect!(ModuleRegistration);
+
+/// Constructor for any module type, to be used in a `ModuleRegistration`.
+pub fn construct<T: ModuleTrait + 'static>(
+    configuration: ModuleProperties,
+) -> Result<Box<dyn ModuleTrait>, ConfigurationError> {
+    Ok(Box::new(T::new(configuration)?))
+}
+
+/// Validator for module types whose settings only need to deserialize, to be
+/// used in a `ModuleRegistration`.
+pub fn validate<T: DeserializeOwned>(
+    configuration: &ModuleProperties,
+) -> Result<(), ConfigurationError> {
+    configuration.settings::<T>().map(|_| ())
+}
+
+/// Validator for module types that do not take any settings.
+pub fn no_validation(_: &ModuleProperties) -> Result<(), ConfigurationError> {
+    Ok(())
+}
+
+/// A description of a module type, whether it is built in or loaded from a
+/// plugin.
+pub struct ModuleInfo {
+    pub name: String,
+    pub description: String,
+    pub settings: serde_json::Value,
+    pub capabilities: Capabilities,
+}
+
+/// Settings for module types that do not take any.
+pub fn no_settings() -> serde_json::Value {
+    serde_json::Value::Object(Default::default())
+}
+
 pub struct ModulesRegistry;
 
 impl ModulesRegistry {
-    pub fn get_module(name: &str, configuration: ModuleProperties) -> Box<dyn ModuleTrait> {
-        match name {
-            "echo" => Box::new(EchoModule::new(configuration)),
-            "udpsocketlistener" => Box::new(UDPSocketListener::new(configuration)),
-            "tcpsocketlistener" => Box::new(TCPSocketListener::new(configuration)),
-            "infinitesender" => Box::new(InfiniteSender::new(configuration)),
-            "tcpwriter" => Box::new(TCPSocketWriter::new(configuration)),
-            "stdin" => Box::new(StdinWriter::new(configuration)),
-            _ => {
-                panic!("Unknown module type: {}", name)
-            }
+    pub fn get_module(
+        name: &str,
+        configuration: ModuleProperties,
+    ) -> Result<Box<dyn ModuleTrait>, ConfigurationError> {
+        if let Some(registration) = Self::find(name) {
+            return (registration.constructor)(configuration);
+        }
+
+        if plugins::find(name).is_some() {
+            return construct::<PluginModule>(configuration);
+        }
+
+        Err(ConfigurationError::UnknownModuleType(name.to_string()))
+    }
+
+    /// Checks the settings of a module without building it. The settings of
+    /// plugin modules are only checked by the plugin, once the module is
+    /// built.
+    pub fn validate(
+        name: &str,
+        configuration: &ModuleProperties,
+    ) -> Result<(), ConfigurationError> {
+        if let Some(registration) = Self::find(name) {
+            return (registration.validate)(configuration);
+        }
+
+        if plugins::find(name).is_some() {
+            return Ok(());
         }
+
+        Err(ConfigurationError::UnknownModuleType(name.to_string()))
+    }
+
+    /// Returns the capabilities of a module type, or None if the type is
+    /// unknown.
+    pub fn capabilities(name: &str) -> Option<Capabilities> {
+        Self::find(name)
+            .map(|registration| registration.capabilities)
+            .or_else(|| plugins::find(name).map(|plugin| plugin.capabilities))
+    }
+
+    pub fn find(name: &str) -> Option<&'static ModuleRegistration> {
+        inventory::iter::<ModuleRegistration>
+            .into_iter()
+            .find(|registration| registration.name == name)
+    }
+
+    /// All available module types, including the ones loaded from plugins,
+    /// sorted by name.
+    pub fn list() -> Vec<ModuleInfo> {
+        let builtin = inventory::iter::<ModuleRegistration>
+            .into_iter()
+            .map(|registration| ModuleInfo {
+                name: registration.name.to_string(),
+                description: registration.description.to_string(),
+                settings: (registration.settings)(),
+                capabilities: registration.capabilities,
+            });
+
+        let loaded = plugins::list().into_iter().map(|plugin| ModuleInfo {
+            name: plugin.name.clone(),
+            description: plugin.description.clone(),
+            settings: plugin.default_settings.clone(),
+            capabilities: plugin.capabilities,
+        });
+
+        let mut modules: Vec<_> = builtin.chain(loaded).collect();
+        modules.sort_by(|a, b| a.name.cmp(&b.name));
+        modules
     }
 }
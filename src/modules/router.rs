@@ -0,0 +1,247 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+};
+
+use async_channel::Receiver;
+use uuid::Uuid;
+
+use crate::{
+    configuration::global_configuration::{Delivery, RouteConfiguration},
+    messaging::{
+        channel::{self, Outbox},
+        message::Message,
+    },
+};
+
+/// Splits the receiving end of a route into one receiver per destination,
+/// handing out messages according to the route's delivery mode. Each
+/// destination gets a channel with the same capacity and overflow policy as
+/// the route itself.
+pub fn dispatch(
+    stage_id: Uuid,
+    name: &str,
+    route: &RouteConfiguration,
+    receiver: Receiver<Message>,
+    destinations: usize,
+) -> Vec<Receiver<Message>> {
+    // Nothing to split with a single destination, and competing consumers
+    // simply share the route's receiver.
+    if destinations <= 1 || route.delivery == Delivery::Competing {
+        return vec![receiver; destinations];
+    }
+
+    let (senders, receivers): (Vec<_>, Vec<_>) = (0..destinations)
+        .map(|index| channel::channel(stage_id, &format!("{}.{}", name, index), route))
+        .unzip();
+
+    let delivery = route.delivery.clone();
+    tokio::spawn(async move {
+        let mut next = 0;
+
+        while let Ok(message) = receiver.recv().await {
+            let delivered = match &delivery {
+                Delivery::Broadcast => broadcast(&senders, message).await,
+                Delivery::RoundRobin => {
+                    let start = next;
+                    next = (next + 1) % senders.len();
+                    send_from(&senders, start, message).await
+                }
+                Delivery::Hash(field) => {
+                    let start = hash_field(&message, field) % senders.len();
+                    send_from(&senders, start, message).await
+                }
+                Delivery::Competing => unreachable!("Competing routes are not dispatched"),
+            };
+
+            if !delivered {
+                println!("All destinations of the route are closed, stopping dispatch");
+                break;
+            }
+        }
+    });
+
+    receivers
+}
+
+/// Copies the message to every open destination. Returns false if none of
+/// them accepted it.
+async fn broadcast(senders: &[Outbox], message: Message) -> bool {
+    let mut delivered = false;
+    for sender in senders {
+        if !sender.is_closed() && sender.send(message.clone()).await.is_ok() {
+            delivered = true;
+        }
+    }
+
+    delivered
+}
+
+/// Sends the message to the destination at `start`, moving on to the next
+/// one if that destination has gone away. Returns false if none accepted it.
+async fn send_from(senders: &[Outbox], start: usize, message: Message) -> bool {
+    let mut message = message;
+    for offset in 0..senders.len() {
+        let sender = &senders[(start + offset) % senders.len()];
+        match sender.send(message).await {
+            Ok(_) => return true,
+            Err(async_channel::SendError(returned)) => message = returned,
+        }
+    }
+
+    false
+}
+
+fn hash_field(message: &Message, field: &str) -> usize {
+    let mut hasher = DefaultHasher::new();
+    message
+        .fields
+        .get(field)
+        .map(|value| value.to_string())
+        .hash(&mut hasher);
+
+    hasher.finish() as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    fn route(delivery: &str) -> RouteConfiguration {
+        toml::from_str(&format!(
+            "from = {{ Single = \"a\" }}\nto = {{ Multiple = [\"b\", \"c\", \"d\"] }}\ndelivery = {:?}",
+            delivery
+        ))
+        .unwrap()
+    }
+
+    fn message(fields: Value) -> Message {
+        Message::new(serde_json::from_value(fields).unwrap())
+    }
+
+    // Sends `messages` through a route dispatched to `destinations`, giving
+    // the `n` fields each destination received.
+    async fn deliver(delivery: &str, destinations: usize, messages: Vec<Message>) -> Vec<Vec<i64>> {
+        let (sender, receiver) = async_channel::unbounded();
+        let receivers = dispatch(
+            Uuid::new_v4(),
+            "test",
+            &route(delivery),
+            receiver,
+            destinations,
+        );
+
+        for message in messages {
+            sender.send(message).await.unwrap();
+        }
+        drop(sender);
+
+        let mut received = vec![];
+        for receiver in receivers {
+            let mut values = vec![];
+            while let Ok(message) = receiver.recv().await {
+                values.push(message.fields["n"].as_i64().unwrap());
+            }
+            received.push(values);
+        }
+        received
+    }
+
+    fn numbered(count: i64) -> Vec<Message> {
+        (0..count).map(|n| message(json!({ "n": n }))).collect()
+    }
+
+    #[tokio::test]
+    async fn broadcast_sends_every_message_to_every_destination() {
+        let received = deliver("broadcast", 3, numbered(3)).await;
+
+        assert_eq!(received, vec![vec![0, 1, 2]; 3]);
+    }
+
+    #[tokio::test]
+    async fn round_robin_takes_turns() {
+        let received = deliver("round_robin", 3, numbered(7)).await;
+
+        assert_eq!(received, vec![vec![0, 3, 6], vec![1, 4], vec![2, 5]]);
+    }
+
+    #[tokio::test]
+    async fn round_robin_skips_destinations_that_are_gone() {
+        let (sender, receiver) = async_channel::unbounded();
+        let mut receivers = dispatch(Uuid::new_v4(), "test", &route("round_robin"), receiver, 2);
+        drop(receivers.pop());
+
+        for message in numbered(3) {
+            sender.send(message).await.unwrap();
+        }
+        drop(sender);
+
+        let mut values = vec![];
+        while let Ok(message) = receivers[0].recv().await {
+            values.push(message.fields["n"].as_i64().unwrap());
+        }
+        assert_eq!(values, vec![0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn hash_sends_equal_keys_to_the_same_destination() {
+        let users = ["ana", "bo", "ana", "cy", "bo", "ana", "dee", "cy"];
+        let messages = users
+            .iter()
+            .enumerate()
+            .map(|(n, user)| message(json!({ "n": n, "user": user })))
+            .collect();
+
+        let received = deliver("hash(user)", 3, messages).await;
+
+        let mut destinations: HashMap<&str, usize> = HashMap::new();
+        for (destination, values) in received.iter().enumerate() {
+            for n in values {
+                let user = users[*n as usize];
+                assert_eq!(
+                    *destinations.entry(user).or_insert(destination),
+                    destination
+                );
+            }
+        }
+        assert_eq!(destinations.len(), 4);
+        assert_eq!(received.iter().map(Vec::len).sum::<usize>(), users.len());
+    }
+
+    #[tokio::test]
+    async fn hash_sends_messages_without_the_field_to_one_destination() {
+        let received = deliver("hash(user)", 3, numbered(5)).await;
+
+        let used: Vec<&Vec<i64>> = received
+            .iter()
+            .filter(|values| !values.is_empty())
+            .collect();
+        assert_eq!(used, vec![&vec![0, 1, 2, 3, 4]]);
+    }
+
+    #[tokio::test]
+    async fn competing_destinations_share_the_messages() {
+        let (sender, receiver) = async_channel::unbounded();
+        let receivers = dispatch(Uuid::new_v4(), "test", &route("competing"), receiver, 3);
+        for message in numbered(6) {
+            sender.send(message).await.unwrap();
+        }
+
+        // Whichever destination asks first gets the next message.
+        let mut received = vec![];
+        for receiver in [2, 0, 1, 1, 2, 0] {
+            let message = receivers[receiver].recv().await.unwrap();
+            received.push((receiver, message.fields["n"].as_i64().unwrap()));
+        }
+
+        assert_eq!(
+            received,
+            vec![(2, 0), (0, 1), (1, 2), (1, 3), (2, 4), (0, 5)]
+        );
+        assert!(receivers[0].is_empty());
+    }
+}
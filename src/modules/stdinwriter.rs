@@ -3,8 +3,12 @@ use std::collections::HashMap;
 use tokio::io::AsyncBufReadExt;
 
 use crate::{
-    configuration::module_properties::ModuleProperties, messaging::message::Message,
-    modules::module::ModuleTrait,
+    configuration::{error::ConfigurationError, module_properties::ModuleProperties},
+    messaging::message::Message,
+    modules::{
+        module::ModuleTrait,
+        registry::{self, Capabilities, ModuleRegistration},
+    },
 };
 
 pub struct StdinWriter {
@@ -12,20 +16,17 @@ pub struct StdinWriter {
 }
 
 impl ModuleTrait for StdinWriter {
-    fn new(configuration: ModuleProperties) -> Self
+    fn new(configuration: ModuleProperties) -> Result<Self, ConfigurationError>
     where
         Self: Sized,
     {
-        Self {
+        Ok(Self {
             properties: configuration,
-        }
+        })
     }
 
-    fn set_outbox(
-        &mut self,
-        outbox: Option<async_channel::Sender<crate::messaging::message::Message>>,
-    ) {
-        self.properties.outbox = outbox;
+    fn add_outbox(&mut self, name: String, outbox: crate::messaging::channel::Outbox) {
+        self.properties.outboxes.insert(name, outbox);
     }
 
     fn run(self: Box<Self>) -> tokio::task::JoinHandle<()> {
@@ -35,19 +36,20 @@ impl ModuleTrait for StdinWriter {
 
             loop {
                 buffer.clear(); // Clear the buffer before each read
-                match stdin.read_line(&mut buffer).await {
+                let read = tokio::select! {
+                    _ = self.properties.shutdown.cancelled() => break,
+                    read = stdin.read_line(&mut buffer) => read,
+                };
+
+                match read {
                     Ok(0) => break, // End of input
                     Ok(_) => {
-                        if let Some(outbox) = self.properties.outbox.clone() {
-                            let timestamp = format!("{:?}", std::time::SystemTime::now());
-                            let mut map = HashMap::new();
-                            map.insert("data".into(), buffer.trim().to_string().into());
-                            map.insert("timestamp".into(), timestamp.into());
-
-                            if let Err(e) = outbox.send(Message::new(map)).await {
-                                eprintln!("Error sending message internally: {}", e);
-                                break;
-                            }
+                        let mut map = HashMap::new();
+                        map.insert("data".into(), buffer.trim().to_string().into());
+
+                        if let Err(e) = self.properties.outboxes.send(Message::new(map)).await {
+                            eprintln!("Error sending message internally: {}", e);
+                            break;
                         }
                     }
                     Err(e) => {
@@ -59,3 +61,14 @@ impl ModuleTrait for StdinWriter {
         })
     }
 }
+
+inventory::submit! {
+    ModuleRegistration {
+        name: "stdin",
+        description: "Sends every line read from standard input as a message",
+        constructor: registry::construct::<StdinWriter>,
+        validate: registry::no_validation,
+        settings: registry::no_settings,
+        capabilities: Capabilities::SOURCE,
+    }
+}
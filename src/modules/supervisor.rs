@@ -0,0 +1,276 @@
+use std::{collections::VecDeque, time::Duration};
+
+use async_channel::Receiver;
+use tokio::{
+    task::{AbortHandle, JoinHandle},
+    time::Instant,
+};
+
+use crate::{
+    configuration::{
+        error::ConfigurationError,
+        module_properties::{ModuleProperties, RestartPolicy, SupervisionConfiguration},
+    },
+    messaging::{channel::Outbox, message::Message},
+    modules::{module::ModuleTrait, registry::ModulesRegistry},
+};
+
+/// Runs a module, and starts it again according to its restart policy when
+/// it stops. Restarted modules are wired to the same route channels as before,
+/// so the rest of the pipeline does not notice.
+pub struct Supervisor {
+    name: String,
+    properties: ModuleProperties,
+    outboxes: Vec<(String, Outbox)>,
+    inboxes: Vec<(String, Receiver<Message>)>,
+}
+
+// Aborts the module task if the supervisor itself is aborted.
+struct AbortOnDrop(AbortHandle);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+impl Supervisor {
+    pub fn new(name: String, properties: ModuleProperties) -> Self {
+        Supervisor {
+            name,
+            properties,
+            outboxes: vec![],
+            inboxes: vec![],
+        }
+    }
+
+    pub fn add_outbox(&mut self, route: String, outbox: Outbox) {
+        self.outboxes.push((route, outbox));
+    }
+
+    pub fn add_inbox(&mut self, route: String, inbox: Receiver<Message>) {
+        self.inboxes.push((route, inbox));
+    }
+
+    /// True if the module takes part in at least one route.
+    pub fn is_routed(&self) -> bool {
+        !self.outboxes.is_empty() || !self.inboxes.is_empty()
+    }
+
+    /// True if the module receives messages from at least one route.
+    pub fn has_inboxes(&self) -> bool {
+        !self.inboxes.is_empty()
+    }
+
+    /// Creates a new instance of the module, wired to its routes.
+    pub fn build(&self) -> Result<Box<dyn ModuleTrait>, ConfigurationError> {
+        let module =
+            ModulesRegistry::get_module(&self.properties.module_type, self.properties.clone())?;
+
+        Ok(self.wire(module))
+    }
+
+    /// Wires a module built from the supervisor's properties to its routes.
+    pub fn wire(&self, mut module: Box<dyn ModuleTrait>) -> Box<dyn ModuleTrait> {
+        for (route, outbox) in &self.outboxes {
+            module.add_outbox(route.clone(), outbox.clone());
+        }
+
+        for (route, inbox) in &self.inboxes {
+            module.add_inbox(route.clone(), inbox.clone());
+        }
+
+        module
+    }
+
+    /// Runs `module`, restarting it as needed. The returned task finishes
+    /// once the module has stopped for good.
+    pub fn run(self, module: Box<dyn ModuleTrait>) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let shutdown = self.properties.shutdown.clone();
+            let mut restarts = Restarts::new(self.properties.supervision.clone());
+            let mut module = module;
+
+            loop {
+                let handle = module.run();
+                let _guard = AbortOnDrop(handle.abort_handle());
+
+                let failed = match handle.await {
+                    Ok(_) => false,
+                    Err(e) if e.is_cancelled() => return,
+                    Err(e) => {
+                        println!("Module {} failed with error: {:?}", self.name, e);
+                        true
+                    }
+                };
+
+                if !restarts.wanted(failed) || shutdown.is_cancelled() || self.inboxes_closed() {
+                    break;
+                }
+
+                let Some(delay) = restarts.delay() else {
+                    println!(
+                        "Module {} was restarted {} times within {:?}, giving up on it",
+                        self.name,
+                        restarts.history.len(),
+                        restarts.window()
+                    );
+                    break;
+                };
+
+                println!("Restarting module {} in {:?}", self.name, delay);
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {}
+                    _ = shutdown.cancelled() => break,
+                }
+
+                module = match self.build() {
+                    Ok(module) => module,
+                    Err(e) => {
+                        println!("Could not restart module {}: {}", self.name, e);
+                        break;
+                    }
+                };
+            }
+
+            println!("Module {} stopped", self.name);
+        })
+    }
+
+    // A module whose inboxes are all closed and drained has nothing left to do,
+    // so there is no point restarting it.
+    fn inboxes_closed(&self) -> bool {
+        !self.inboxes.is_empty()
+            && self
+                .inboxes
+                .iter()
+                .all(|(_, inbox)| inbox.is_closed() && inbox.is_empty())
+    }
+}
+
+// Whether and when a stopped module is started again, following its
+// supervision settings.
+struct Restarts {
+    supervision: SupervisionConfiguration,
+
+    // When the module was restarted within the window, oldest first.
+    history: VecDeque<Instant>,
+}
+
+impl Restarts {
+    fn new(supervision: SupervisionConfiguration) -> Self {
+        Restarts {
+            supervision,
+            history: VecDeque::new(),
+        }
+    }
+
+    fn window(&self) -> Duration {
+        Duration::from_secs(self.supervision.restart_window_secs)
+    }
+
+    // True if the restart policy wants a module that stopped, having failed
+    // or not, to be started again.
+    fn wanted(&self, failed: bool) -> bool {
+        match self.supervision.restart {
+            RestartPolicy::Never => false,
+            RestartPolicy::Always => true,
+            RestartPolicy::OnFailure => failed,
+        }
+    }
+
+    // Records a restart, and gives how long to wait before it. The delay
+    // doubles with every restart within the window, up to the maximum. None
+    // if the module was already restarted too many times within the window.
+    fn delay(&mut self) -> Option<Duration> {
+        let now = Instant::now();
+        let window = self.window();
+        while self
+            .history
+            .front()
+            .is_some_and(|restarted| now.duration_since(*restarted) > window)
+        {
+            self.history.pop_front();
+        }
+
+        if self.history.len() >= self.supervision.max_restarts as usize {
+            return None;
+        }
+
+        let delay = Duration::from_millis(
+            self.supervision
+                .backoff_ms
+                .saturating_mul(1 << self.history.len().min(32))
+                .min(self.supervision.max_backoff_ms),
+        );
+        self.history.push_back(now);
+
+        Some(delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::advance;
+
+    use super::*;
+
+    fn restarts(restart: RestartPolicy, max_restarts: u32) -> Restarts {
+        Restarts::new(SupervisionConfiguration {
+            restart,
+            max_restarts,
+            restart_window_secs: 60,
+            backoff_ms: 100,
+            max_backoff_ms: 1000,
+        })
+    }
+
+    #[test]
+    fn restart_policies() {
+        let never = restarts(RestartPolicy::Never, 5);
+        let always = restarts(RestartPolicy::Always, 5);
+        let on_failure = restarts(RestartPolicy::OnFailure, 5);
+
+        assert!(!never.wanted(false) && !never.wanted(true));
+        assert!(always.wanted(false) && always.wanted(true));
+        assert!(!on_failure.wanted(false) && on_failure.wanted(true));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn backoff_doubles_up_to_the_maximum() {
+        let mut restarts = restarts(RestartPolicy::Always, 10);
+
+        let delays: Vec<u128> = (0..6)
+            .map(|_| restarts.delay().unwrap().as_millis())
+            .collect();
+        assert_eq!(delays, vec![100, 200, 400, 800, 1000, 1000]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_after_too_many_restarts_within_the_window() {
+        let mut restarts = restarts(RestartPolicy::Always, 3);
+
+        for _ in 0..3 {
+            assert!(restarts.delay().is_some());
+            advance(Duration::from_secs(10)).await;
+        }
+        assert_eq!(restarts.delay(), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn restarts_older_than_the_window_are_forgotten() {
+        let mut restarts = restarts(RestartPolicy::Always, 2);
+        assert_eq!(restarts.delay(), Some(Duration::from_millis(100)));
+        advance(Duration::from_secs(30)).await;
+        assert_eq!(restarts.delay(), Some(Duration::from_millis(200)));
+
+        // Only the first restart is out of the window.
+        advance(Duration::from_secs(31)).await;
+        assert_eq!(restarts.delay(), Some(Duration::from_millis(200)));
+        assert_eq!(restarts.delay(), None);
+
+        // Both are out of the window, so the backoff starts over.
+        advance(Duration::from_secs(61)).await;
+        assert_eq!(restarts.delay(), Some(Duration::from_millis(100)));
+    }
+}
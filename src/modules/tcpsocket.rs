@@ -1,72 +1,163 @@
-use std::{collections::HashMap, net::SocketAddr};
+use std::{net::SocketAddr, time::Duration};
 
+use futures::StreamExt;
 use serde::{Deserialize, Serialize};
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
-};
+use tokio::{io::AsyncWriteExt, net::TcpListener, time::timeout};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::{codec::FramedRead, sync::CancellationToken};
 
 use crate::{
-    configuration::module_properties::ModuleProperties, messaging::message::Message,
-    modules::module::ModuleTrait,
+    configuration::{error::ConfigurationError, module_properties::ModuleProperties},
+    messaging::{framing::Framing, mailbox::Outboxes, message::Message, parser::Parser},
+    modules::{
+        module::ModuleTrait,
+        registry::{self, Capabilities, ModuleRegistration},
+        tls::{AsyncStream, ServerTlsConfiguration},
+    },
 };
 
-#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+// How long a client has to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How long to wait before accepting connections again after failing to.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TCPListenerConfiguration {
     address: String,
     port: u16,
     buffer_size: usize,
+
+    #[serde(default)]
+    framing: Framing,
+
+    // Longer frames are skipped, or close the connection when the framing
+    // leaves no way to find the next frame.
+    #[serde(default = "TCPListenerConfiguration::default_max_frame_length")]
+    max_frame_length: usize,
+
+    // Turns frames into fields. Frames are sent as raw bodies if unset.
+    #[serde(default)]
+    parser: Option<Parser>,
+
+    // Accept TLS connections only, if set.
+    #[serde(default)]
+    tls: Option<ServerTlsConfiguration>,
+}
+
+impl TCPListenerConfiguration {
+    fn default_max_frame_length() -> usize {
+        64 * 1024
+    }
+}
+
+impl Default for TCPListenerConfiguration {
+    fn default() -> Self {
+        TCPListenerConfiguration {
+            address: String::new(),
+            port: 0,
+            buffer_size: 0,
+            framing: Framing::default(),
+            max_frame_length: Self::default_max_frame_length(),
+            parser: None,
+            tls: None,
+        }
+    }
 }
 
 pub struct TCPSocketListener {
     pub(crate) properties: ModuleProperties,
     configuration: TCPListenerConfiguration,
+    acceptor: Option<TlsAcceptor>,
 }
 
 impl ModuleTrait for TCPSocketListener {
-    fn new(configuration: ModuleProperties) -> Self
+    fn new(configuration: ModuleProperties) -> Result<Self, ConfigurationError>
     where
         Self: Sized,
     {
         // Convert module config to tcp socket config.
-        let config = configuration.module_settings.clone();
-        let serialized_config = serde_json::to_string(&config).unwrap();
+        let module_config: TCPListenerConfiguration = configuration.settings()?;
+
+        // Load the certificates once, rather than for every connection.
+        // Validation loads them as well, so problems with them are reported
+        // with the rest of the configuration.
+        let acceptor = match &module_config.tls {
+            Some(tls) => Some(tls.acceptor()?),
+            None => None,
+        };
 
-        // Convert the serialized config to module config.
-        let module_config: TCPListenerConfiguration = serde_json::from_str(&serialized_config)
-            .expect("Error configuring the tcpsocket module");
-        Self {
+        Ok(Self {
             properties: configuration,
             configuration: module_config,
-        }
+            acceptor,
+        })
     }
 
-    fn set_outbox(
-        &mut self,
-        outbox: Option<async_channel::Sender<crate::messaging::message::Message>>,
-    ) {
-        self.properties.outbox = outbox;
+    fn add_outbox(&mut self, name: String, outbox: crate::messaging::channel::Outbox) {
+        self.properties.outboxes.insert(name, outbox);
     }
 
     fn run(self: Box<Self>) -> tokio::task::JoinHandle<()> {
         tokio::spawn(async move {
-            let listener = TcpListener::bind(format!(
-                "{}:{}",
-                self.configuration.address, self.configuration.port
-            ))
-            .await
-            .expect("Failed to bind to address");
+            let address = format!("{}:{}", self.configuration.address, self.configuration.port);
+            let listener = match TcpListener::bind(&address).await {
+                Ok(listener) => listener,
+                Err(e) => {
+                    println!("Error listening on {}: {}", address, e);
+                    return;
+                }
+            };
 
             loop {
-                let (socket, _addr) = listener
-                    .accept()
-                    .await
-                    .expect("Failed to accept connection");
+                let accepted = tokio::select! {
+                    _ = self.properties.shutdown.cancelled() => break,
+                    accepted = listener.accept() => accepted,
+                };
+
+                // Errors such as running out of file descriptors only affect
+                // the connection being accepted. Pause a little, so as not to
+                // spin while they last.
+                let (socket, _addr) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        println!("Error accepting connection: {}", e);
+                        tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                        continue;
+                    }
+                };
 
                 // Need to call handle_connection here to spawn a new task for each connection.
-                let cloned_chan = self.properties.outbox.clone();
+                let cloned_chan = self.properties.outboxes.clone();
+                let shutdown = self.properties.shutdown.clone();
+                let configuration = self.configuration.clone();
+                let acceptor = self.acceptor.clone();
                 tokio::spawn(async move {
-                    TCPSocketListener::handle_connection(socket, _addr, cloned_chan).await;
+                    let socket: Box<dyn AsyncStream> = match acceptor {
+                        Some(acceptor) => {
+                            match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(socket)).await {
+                                Ok(Ok(tls)) => Box::new(tls),
+                                Ok(Err(e)) => {
+                                    println!("TLS handshake with {} failed: {}", _addr, e);
+                                    return;
+                                }
+                                Err(_) => {
+                                    println!("TLS handshake with {} timed out", _addr);
+                                    return;
+                                }
+                            }
+                        }
+                        None => Box::new(socket),
+                    };
+
+                    TCPSocketListener::handle_connection(
+                        socket,
+                        _addr,
+                        cloned_chan,
+                        shutdown,
+                        configuration,
+                    )
+                    .await;
                 });
             }
         })
@@ -74,53 +165,91 @@ impl ModuleTrait for TCPSocketListener {
 }
 
 impl TCPSocketListener {
-    #[allow(dead_code)]
+    /// Checks the settings, and loads the TLS certificates and key if any,
+    /// without binding.
+    pub(crate) fn validate(configuration: &ModuleProperties) -> Result<(), ConfigurationError> {
+        let module_config: TCPListenerConfiguration = configuration.settings()?;
+        if let Some(tls) = &module_config.tls {
+            tls.acceptor()?;
+        }
+
+        Ok(())
+    }
+
     pub(crate) fn get_settings() -> serde_json::Value {
         let default_settings = TCPListenerConfiguration::default();
         serde_json::to_value(default_settings).expect("Failed to serialize default settings")
     }
 
     async fn handle_connection(
-        mut socket: TcpStream,
-        _addr: SocketAddr,
-        outbox: Option<async_channel::Sender<Message>>,
+        socket: Box<dyn AsyncStream>,
+        addr: SocketAddr,
+        outboxes: Outboxes,
+        shutdown: CancellationToken,
+        configuration: TCPListenerConfiguration,
     ) {
         println!("Starting to handle connection");
-        let mut buffer = Vec::with_capacity(1024);
-
-        while let Ok(size) = socket.read_buf(&mut buffer).await {
-            if size == 0 {
-                println!("Connection closed");
-                break;
-            }
-
-            let timestamp = format!("{:?}", std::time::SystemTime::now());
-            let mut messages = vec![];
-
-            let content = std::str::from_utf8(&mut buffer[..size]);
-            if let Ok(content) = content {
-                // Split content into lines.
-                for line in content.lines() {
-                    let mut event: HashMap<String, serde_json::Value> = HashMap::new();
-                    event.insert("data".into(), line.into());
-                    event.insert("timestamp".into(), timestamp.clone().into());
-
-                    if !event.is_empty() {
-                        messages.push(event);
-                    }
+        let decoder = configuration
+            .framing
+            .decoder(configuration.max_frame_length);
+        let mut frames =
+            FramedRead::with_capacity(socket, decoder, configuration.buffer_size.max(1024));
+
+        loop {
+            let frame = tokio::select! {
+                _ = shutdown.cancelled() => break,
+                frame = frames.next() => frame,
+            };
+
+            let frame = match frame {
+                Some(Ok(frame)) => frame,
+                Some(Err(e)) => {
+                    println!("Closing connection from {}: {}", addr, e);
+                    break;
                 }
-
-                buffer.clear();
-            }
-
-            if let Some(outbox) = outbox.clone() {
-                for message in messages {
-                    let message = Message::new(message);
-                    outbox.send(message).await.unwrap();
+                None => {
+                    println!("Connection closed");
+                    break;
+                }
+            };
+
+            let messages = match configuration.parser {
+                Some(parser) => parser.parse(&frame),
+                // Without a parser, the frame is kept as is in the body, and
+                // as text in `data` for what relies on that field.
+                None => {
+                    let data = String::from_utf8_lossy(&frame).into_owned();
+                    let mut message = Message::from_body(frame);
+                    message.fields.insert("data".into(), data.into());
+                    vec![message]
+                }
+            };
+
+            for mut message in messages {
+                message
+                    .fields
+                    .insert("remote_address".into(), addr.ip().to_string().into());
+                message
+                    .fields
+                    .insert("remote_port".into(), addr.port().into());
+
+                if outboxes.send(message).await.is_err() {
+                    return;
                 }
             }
         }
 
-        socket.shutdown().await.expect("Failed to shutdown socket");
+        let _ = frames.into_inner().shutdown().await;
+    }
+}
+
+inventory::submit! {
+    ModuleRegistration {
+        name: "tcpsocketlistener",
+        description: "Accepts TCP or TLS connections and sends every frame received as a message",
+        constructor: registry::construct::<TCPSocketListener>,
+        validate: TCPSocketListener::validate,
+        settings: TCPSocketListener::get_settings,
+        capabilities: Capabilities::SOURCE,
     }
 }
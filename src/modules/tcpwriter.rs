@@ -1,79 +1,296 @@
+use std::{collections::VecDeque, io, time::Duration};
+
+use futures::FutureExt;
 use serde::{Deserialize, Serialize};
-use serde_json::json;
-use tokio::{io::AsyncWriteExt, net::TcpStream};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::{sleep_until, timeout, Instant},
+};
+use tokio_rustls::{rustls::pki_types::ServerName, TlsConnector};
+
+use crate::{
+    configuration::{error::ConfigurationError, module_properties::ModuleProperties},
+    messaging::{
+        framing::Framing, mailbox::Inboxes, message::Message, serialization::Serialization,
+    },
+    modules::{
+        module::ModuleTrait,
+        registry::{self, Capabilities, ModuleRegistration},
+        tls::{AsyncStream, ClientTlsConfiguration},
+    },
+};
 
-use crate::{configuration::module_properties::ModuleProperties, modules::module::ModuleTrait};
+// How long to wait for the remote end to accept a connection, TLS handshake
+// included.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
 
-#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TCPWriterConfiguration {
     address: String,
     port: u16,
+
+    #[serde(default)]
+    framing: Framing,
+
+    #[serde(default)]
+    serialization: Serialization,
+
+    // Fields written with the "fields" serialization. All of them if unset.
+    #[serde(default)]
+    fields: Option<Vec<String>>,
+
+    // Messages kept while the connection is down. Once full, no more
+    // messages are taken from the routes until the connection is back.
+    #[serde(default = "TCPWriterConfiguration::default_buffer_size")]
+    buffer_size: usize,
+
+    // Delay before reconnecting, doubled after every failed attempt.
+    #[serde(default = "TCPWriterConfiguration::default_reconnect_backoff")]
+    reconnect_backoff_ms: u64,
+    #[serde(default = "TCPWriterConfiguration::default_max_reconnect_backoff")]
+    max_reconnect_backoff_ms: u64,
+
+    // Connect with TLS, if set.
+    #[serde(default)]
+    tls: Option<ClientTlsConfiguration>,
+}
+
+impl TCPWriterConfiguration {
+    fn default_buffer_size() -> usize {
+        1000
+    }
+
+    fn default_reconnect_backoff() -> u64 {
+        500
+    }
+
+    fn default_max_reconnect_backoff() -> u64 {
+        30_000
+    }
+}
+
+impl Default for TCPWriterConfiguration {
+    fn default() -> Self {
+        TCPWriterConfiguration {
+            address: String::new(),
+            port: 0,
+            framing: Framing::default(),
+            serialization: Serialization::default(),
+            fields: None,
+            buffer_size: Self::default_buffer_size(),
+            reconnect_backoff_ms: Self::default_reconnect_backoff(),
+            max_reconnect_backoff_ms: Self::default_max_reconnect_backoff(),
+            tls: None,
+        }
+    }
 }
 
 pub struct TCPSocketWriter {
     pub(crate) properties: ModuleProperties,
     configuration: TCPWriterConfiguration,
+    tls: Option<(TlsConnector, ServerName<'static>)>,
 }
 
 impl ModuleTrait for TCPSocketWriter {
-    fn new(configuration: ModuleProperties) -> Self
+    fn new(configuration: ModuleProperties) -> Result<Self, ConfigurationError>
     where
         Self: Sized,
     {
         // Convert module config to tcp socket config.
-        let config = configuration.module_settings.clone();
-        let serialized_config = serde_json::to_string(&config).unwrap();
+        let module_config: TCPWriterConfiguration = configuration.settings()?;
 
-        // Convert the serialized config to module config.
-        let module_config: TCPWriterConfiguration = serde_json::from_str(&serialized_config)
-            .expect("Error configuring the tcpsocket module");
+        // Load the certificates once, rather than for every connection.
+        // Validation loads them as well, so problems with them are reported
+        // with the rest of the configuration.
+        let tls = match &module_config.tls {
+            Some(tls) => Some(tls.connector(&module_config.address)?),
+            None => None,
+        };
 
-        Self {
+        Ok(Self {
             properties: configuration,
             configuration: module_config,
-        }
+            tls,
+        })
     }
 
-    fn set_inbox(
+    fn add_inbox(
         &mut self,
-        inbox: Option<async_channel::Receiver<crate::messaging::message::Message>>,
+        name: String,
+        inbox: async_channel::Receiver<crate::messaging::message::Message>,
     ) {
-        self.properties.inbox = inbox;
+        self.properties.inboxes.insert(name, inbox);
     }
 
     fn run(self: Box<Self>) -> tokio::task::JoinHandle<()> {
-        // Check that self.inbox exists.
-        if self.properties.inbox.is_none() {
-            panic!("TCPWriter requires an inbox to function.");
-        }
-
-        // SAFETY: safe to unwrap - checks above.
-        let inbox = self.properties.inbox.unwrap();
-        let address = format!("{}:{}", self.configuration.address, self.configuration.port);
+        let inboxes = self.properties.inboxes;
+        let configuration = self.configuration;
+        let tls = self.tls;
 
         tokio::spawn(async move {
-            // Generate a TCP Writer.
-            let mut remote_socket = TcpStream::connect(address)
-                .await
-                .expect("Error binding to remote socket");
-
-            loop {
-                if let Ok(message) = inbox.try_recv() {
-                    let data_to_send: Vec<u8> = serde_json::to_vec(
-                        message.fields.get("data").unwrap_or(&json!("default text")),
-                    )
-                    .unwrap_or_else(|_| {
-                        println!("Error deserializing bytes from message: {:?}", message);
-                        vec![]
-                    });
-
-                    // Write data!
-                    remote_socket
-                        .write_all(&data_to_send)
-                        .await
-                        .expect("Could not write message to remote stream!");
-                }
+            StreamWriter {
+                address: format!("{}:{}", configuration.address, configuration.port),
+                configuration,
+                tls,
             }
+            .run(inboxes)
+            .await;
         })
     }
 }
+
+struct StreamWriter {
+    address: String,
+    configuration: TCPWriterConfiguration,
+    tls: Option<(TlsConnector, ServerName<'static>)>,
+}
+
+impl StreamWriter {
+    /// Writes messages until the inboxes are closed and everything taken from
+    /// them has been written.
+    async fn run(&self, inboxes: Inboxes) {
+        let initial_backoff = Duration::from_millis(self.configuration.reconnect_backoff_ms);
+        let max_backoff = Duration::from_millis(self.configuration.max_reconnect_backoff_ms);
+        let buffer_size = self.configuration.buffer_size.max(1);
+
+        let mut pending: VecDeque<Vec<u8>> = VecDeque::new();
+        let mut stream: Option<Box<dyn AsyncStream>> = None;
+        let mut backoff = initial_backoff;
+        let mut next_attempt = Instant::now();
+        let mut open = true;
+
+        loop {
+            if stream.is_none() && Instant::now() >= next_attempt {
+                match timeout(CONNECT_TIMEOUT, self.connect()).await {
+                    Ok(Ok(connected)) => {
+                        println!("Connected to {}", self.address);
+                        stream = Some(connected);
+                        backoff = initial_backoff;
+                    }
+                    failed => {
+                        let reason = match failed {
+                            Ok(Err(e)) => e.to_string(),
+                            _ => "timed out".to_string(),
+                        };
+                        println!(
+                            "Could not connect to {} ({}), retrying in {:?}",
+                            self.address, reason, backoff
+                        );
+                        next_attempt = Instant::now() + backoff;
+                        backoff = (backoff * 2).min(max_backoff);
+                    }
+                }
+            }
+
+            // A write to a connection the remote end has closed still succeeds,
+            // so check for that first rather than lose what is written.
+            if stream.as_mut().is_some_and(is_closed) {
+                println!("Connection to {} closed by the remote end", self.address);
+                stream = None;
+                next_attempt = Instant::now();
+                continue;
+            }
+
+            if let Some(connection) = stream.as_mut() {
+                while let Some(frame) = pending.front() {
+                    if let Err(e) = connection.write_all(frame).await {
+                        // The frame is kept, and written again once reconnected.
+                        println!("Error writing to {}: {}", self.address, e);
+                        stream = None;
+                        next_attempt = Instant::now();
+                        break;
+                    }
+
+                    pending.pop_front();
+                }
+            }
+
+            if !open && pending.is_empty() {
+                break;
+            }
+
+            tokio::select! {
+                message = inboxes.recv(), if open && pending.len() < buffer_size => match message {
+                    Ok(message) => pending.extend(self.frame(&message)),
+                    Err(_) => open = false,
+                },
+                _ = sleep_until(next_attempt), if stream.is_none() => {}
+                else => break,
+            }
+        }
+
+        if let Some(mut connection) = stream {
+            let _ = connection.shutdown().await;
+        }
+    }
+
+    async fn connect(&self) -> io::Result<Box<dyn AsyncStream>> {
+        let stream = TcpStream::connect(&self.address).await?;
+
+        match &self.tls {
+            Some((connector, server_name)) => Ok(Box::new(
+                connector.connect(server_name.clone(), stream).await?,
+            )),
+            None => Ok(Box::new(stream)),
+        }
+    }
+
+    fn frame(&self, message: &Message) -> Option<Vec<u8>> {
+        let payload = self
+            .configuration
+            .serialization
+            .serialize(message, self.configuration.fields.as_deref());
+
+        let mut frame = Vec::with_capacity(payload.len() + 4);
+        match self.configuration.framing.encode(&payload, &mut frame) {
+            Ok(_) => Some(frame),
+            Err(e) => {
+                println!("Dropping message {}: {}", message.id, e);
+                None
+            }
+        }
+    }
+}
+
+// True if the remote end has closed the connection. Anything it sent is
+// discarded.
+fn is_closed(connection: &mut Box<dyn AsyncStream>) -> bool {
+    let mut buffer = [0u8; 1024];
+    loop {
+        match connection.read(&mut buffer).now_or_never() {
+            None => return false,
+            Some(Ok(0)) | Some(Err(_)) => return true,
+            Some(Ok(_)) => continue,
+        }
+    }
+}
+
+impl TCPSocketWriter {
+    /// Checks the settings, and loads the TLS certificates if any, without
+    /// connecting.
+    pub(crate) fn validate(configuration: &ModuleProperties) -> Result<(), ConfigurationError> {
+        let module_config: TCPWriterConfiguration = configuration.settings()?;
+        if let Some(tls) = &module_config.tls {
+            tls.connector(&module_config.address)?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn get_settings() -> serde_json::Value {
+        let default_settings = TCPWriterConfiguration::default();
+        serde_json::to_value(default_settings).expect("Failed to serialize default settings")
+    }
+}
+
+inventory::submit! {
+    ModuleRegistration {
+        name: "tcpwriter",
+        description: "Writes every message it receives to a TCP or TLS connection, reconnecting when it drops",
+        constructor: registry::construct::<TCPSocketWriter>,
+        validate: TCPSocketWriter::validate,
+        settings: TCPSocketWriter::get_settings,
+        capabilities: Capabilities::SINK,
+    }
+}
@@ -0,0 +1,186 @@
+use std::{fs::File, io::BufReader, path::Path, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::{
+    rustls::{
+        crypto::{ring, CryptoProvider},
+        pki_types::{CertificateDer, PrivateKeyDer, ServerName},
+        server::WebPkiClientVerifier,
+        ClientConfig, RootCertStore, ServerConfig,
+    },
+    TlsAcceptor, TlsConnector,
+};
+
+use crate::configuration::error::ConfigurationError;
+
+/// A connection, with or without TLS.
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for T {}
+
+/// TLS settings of modules accepting connections.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ServerTlsConfiguration {
+    // PEM files with the certificate chain and private key shown to clients.
+    pub certificate: String,
+    pub key: String,
+
+    // PEM file with the CAs client certificates must be signed by. Clients
+    // do not need a certificate if unset.
+    #[serde(default)]
+    pub client_ca: Option<String>,
+}
+
+/// TLS settings of modules making connections.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ClientTlsConfiguration {
+    // PEM file with the CAs the server certificate must be signed by. The
+    // system CAs are used if unset.
+    #[serde(default)]
+    pub ca: Option<String>,
+
+    // Name the server certificate must be valid for, also sent with SNI.
+    // The address connected to is used if unset.
+    #[serde(default)]
+    pub server_name: Option<String>,
+
+    // PEM files with the certificate chain and private key shown to servers
+    // that ask for one.
+    #[serde(default)]
+    pub certificate: Option<String>,
+    #[serde(default)]
+    pub key: Option<String>,
+}
+
+impl ServerTlsConfiguration {
+    /// Loads the certificates and key, ready to accept connections.
+    pub fn acceptor(&self) -> Result<TlsAcceptor, ConfigurationError> {
+        let certificates = load_certificates(&self.certificate, "tls.certificate")?;
+        let key = load_key(&self.key, "tls.key")?;
+
+        let builder = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| invalid("tls", e))?;
+
+        let builder = match &self.client_ca {
+            Some(client_ca) => {
+                let roots = load_roots(client_ca, "tls.client_ca")?;
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider())
+                        .build()
+                        .map_err(|e| invalid("tls.client_ca", e))?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        let config = builder
+            .with_single_cert(certificates, key)
+            .map_err(|e| invalid("tls.key", e))?;
+
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+impl ClientTlsConfiguration {
+    /// Loads the CAs and client certificate, ready to connect to `address`.
+    pub fn connector(
+        &self,
+        address: &str,
+    ) -> Result<(TlsConnector, ServerName<'static>), ConfigurationError> {
+        let roots = match &self.ca {
+            Some(ca) => load_roots(ca, "tls.ca")?,
+            None => {
+                let mut roots = RootCertStore::empty();
+                roots.add_parsable_certificates(rustls_native_certs::load_native_certs().certs);
+                roots
+            }
+        };
+
+        let builder = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| invalid("tls", e))?
+            .with_root_certificates(roots);
+
+        let config = match (&self.certificate, &self.key) {
+            (Some(certificate), Some(key)) => builder
+                .with_client_auth_cert(
+                    load_certificates(certificate, "tls.certificate")?,
+                    load_key(key, "tls.key")?,
+                )
+                .map_err(|e| invalid("tls.key", e))?,
+            (None, None) => builder.with_no_client_auth(),
+            (Some(_), None) => {
+                return Err(invalid("tls.key", "a key is needed with a certificate"))
+            }
+            (None, Some(_)) => {
+                return Err(invalid(
+                    "tls.certificate",
+                    "a certificate is needed with a key",
+                ))
+            }
+        };
+
+        let name = self.server_name.as_deref().unwrap_or(address);
+        let server_name = ServerName::try_from(name.to_string())
+            .map_err(|e| invalid("tls.server_name", format!("{:?}: {}", name, e)))?;
+
+        Ok((TlsConnector::from(Arc::new(config)), server_name))
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn invalid(path: &str, message: impl ToString) -> ConfigurationError {
+    ConfigurationError::InvalidSettings {
+        path: path.into(),
+        message: message.to_string(),
+    }
+}
+
+fn open(path: &str, setting: &str) -> Result<BufReader<File>, ConfigurationError> {
+    File::open(Path::new(path))
+        .map(BufReader::new)
+        .map_err(|e| invalid(setting, format!("error reading {}: {}", path, e)))
+}
+
+fn load_certificates(
+    path: &str,
+    setting: &str,
+) -> Result<Vec<CertificateDer<'static>>, ConfigurationError> {
+    let certificates = rustls_pemfile::certs(&mut open(path, setting)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| invalid(setting, format!("error reading {}: {}", path, e)))?;
+
+    if certificates.is_empty() {
+        return Err(invalid(
+            setting,
+            format!("no certificates found in {}", path),
+        ));
+    }
+
+    Ok(certificates)
+}
+
+fn load_key(path: &str, setting: &str) -> Result<PrivateKeyDer<'static>, ConfigurationError> {
+    rustls_pemfile::private_key(&mut open(path, setting)?)
+        .map_err(|e| invalid(setting, format!("error reading {}: {}", path, e)))?
+        .ok_or_else(|| invalid(setting, format!("no private key found in {}", path)))
+}
+
+fn load_roots(path: &str, setting: &str) -> Result<RootCertStore, ConfigurationError> {
+    let mut roots = RootCertStore::empty();
+    for certificate in load_certificates(path, setting)? {
+        roots.add(certificate).map_err(|e| {
+            invalid(
+                setting,
+                format!("invalid CA certificate in {}: {}", path, e),
+            )
+        })?;
+    }
+
+    Ok(roots)
+}
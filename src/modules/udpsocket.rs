@@ -1,73 +1,250 @@
-use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
 use serde::{Deserialize, Serialize};
 use tokio::net::UdpSocket;
 
 use crate::{
-    configuration::module_properties::ModuleProperties, messaging::message::Message,
-    modules::module::ModuleTrait,
+    configuration::{error::ConfigurationError, module_properties::ModuleProperties},
+    messaging::{message::Message, parser::Parser},
+    modules::{
+        module::ModuleTrait,
+        registry::{self, Capabilities, ModuleRegistration},
+    },
 };
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct UdpSocketConfiguration {
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct UdpSocketConfiguration {
     address: String,
     port: u16,
     buffer_size: usize,
+
+    // Multicast group to join, IPv4 or IPv6. `address` should then be the
+    // unspecified address of the same family.
+    #[serde(default)]
+    multicast_group: Option<IpAddr>,
+
+    // Interface to receive multicast on: the address of a local interface
+    // for IPv4, or an interface index for IPv6. Chosen by the system if unset.
+    #[serde(default)]
+    multicast_interface: Option<String>,
+
+    // Turns datagrams into fields. Datagrams are sent as raw bodies if unset.
+    #[serde(default)]
+    parser: Option<Parser>,
+}
+
+enum Multicast {
+    V4 {
+        group: Ipv4Addr,
+        interface: Ipv4Addr,
+    },
+    V6 {
+        group: Ipv6Addr,
+        interface: u32,
+    },
+}
+
+impl UdpSocketConfiguration {
+    // The address to bind to, and the multicast group to join, if any.
+    fn addresses(&self) -> Result<(IpAddr, Option<Multicast>), ConfigurationError> {
+        let invalid = |path: &str, message: String| ConfigurationError::InvalidSettings {
+            path: path.into(),
+            message,
+        };
+
+        let ip: IpAddr = self.address.parse().map_err(|e| {
+            invalid(
+                "address",
+                format!("{:?} is not an IP address: {}", self.address, e),
+            )
+        })?;
+
+        let interface = self.multicast_interface.as_deref();
+        let multicast = match self.multicast_group {
+            None => None,
+            Some(group) if !group.is_multicast() => {
+                return Err(invalid(
+                    "multicast_group",
+                    format!("{} is not a multicast address", group),
+                ));
+            }
+            Some(IpAddr::V4(group)) => Some(Multicast::V4 {
+                group,
+                interface: match interface {
+                    Some(interface) => interface.parse().map_err(|_| {
+                        invalid(
+                            "multicast_interface",
+                            format!("{:?} is not an IPv4 address", interface),
+                        )
+                    })?,
+                    None => Ipv4Addr::UNSPECIFIED,
+                },
+            }),
+            Some(IpAddr::V6(group)) => Some(Multicast::V6 {
+                group,
+                interface: match interface {
+                    Some(interface) => interface.parse().map_err(|_| {
+                        invalid(
+                            "multicast_interface",
+                            format!("{:?} is not an interface index", interface),
+                        )
+                    })?,
+                    None => 0,
+                },
+            }),
+        };
+
+        if let Some(group) = self.multicast_group {
+            if group.is_ipv4() != ip.is_ipv4() {
+                return Err(invalid(
+                    "multicast_group",
+                    format!(
+                        "{} cannot be joined on {}, which is of another IP version",
+                        group, ip
+                    ),
+                ));
+            }
+        }
+
+        Ok((ip, multicast))
+    }
 }
 
 pub struct UDPSocketListener {
     properties: ModuleProperties,
     configuration: UdpSocketConfiguration,
+    ip: IpAddr,
+    multicast: Option<Multicast>,
 }
 
 impl ModuleTrait for UDPSocketListener {
-    fn new(configuration: ModuleProperties) -> Self {
+    fn new(configuration: ModuleProperties) -> Result<Self, ConfigurationError> {
         // Convert module config to udp socket config.
-        let _config = configuration.module_settings.clone();
-        let serialized_config = serde_json::to_string(&_config).unwrap();
+        let module_config: UdpSocketConfiguration = configuration.settings()?;
 
-        // Convert the serialized config to module config.
-        let module_config: UdpSocketConfiguration = serde_json::from_str(&serialized_config)
-            .expect("Error configuring the filechange module");
+        let (ip, multicast) = module_config.addresses()?;
 
-        UDPSocketListener {
+        Ok(UDPSocketListener {
             properties: configuration,
             configuration: module_config,
-        }
+            ip,
+            multicast,
+        })
     }
 
-    fn set_outbox(
-        &mut self,
-        outbox: Option<async_channel::Sender<crate::messaging::message::Message>>,
-    ) {
-        self.properties.outbox = outbox;
+    fn add_outbox(&mut self, name: String, outbox: crate::messaging::channel::Outbox) {
+        self.properties.outboxes.insert(name, outbox);
     }
 
     fn run(self: Box<Self>) -> tokio::task::JoinHandle<()> {
         tokio::spawn(async move {
-            let socket = UdpSocket::bind(format!(
-                "{}:{}",
-                self.configuration.address, self.configuration.port
-            ))
-            .await
-            .expect("Failed to create UDP socket");
-
-            let mut buffer = Vec::with_capacity(self.configuration.buffer_size);
+            let address = SocketAddr::new(self.ip, self.configuration.port);
+            let socket = match UdpSocket::bind(address).await {
+                Ok(socket) => socket,
+                Err(e) => {
+                    println!("Error listening on {}: {}", address, e);
+                    return;
+                }
+            };
+
+            let joined = match &self.multicast {
+                Some(Multicast::V4 { group, interface }) => {
+                    socket.join_multicast_v4(*group, *interface)
+                }
+                Some(Multicast::V6 { group, interface }) => {
+                    socket.join_multicast_v6(group, *interface)
+                }
+                None => Ok(()),
+            };
+            if let Err(e) = joined {
+                println!("Error joining multicast group on {}: {}", address, e);
+                return;
+            }
+
+            // Datagrams longer than the buffer are truncated.
+            let buffer_size = match self.configuration.buffer_size {
+                0 => 65_535,
+                size => size,
+            };
+            let mut buffer = vec![0u8; buffer_size];
             loop {
-                // Connection established
-                while let Ok((size, _src)) = socket.recv_from(&mut buffer).await {
-                    let mut event = HashMap::new();
-                    // Run parsers on the buffer to split it into key-value pairs.
-                    event.insert("message_size".into(), (size as i64).into());
-                    event.insert("message".into(), "Hola!".into());
-
-                    let message = Message::new(event);
-                    if let Some(outbox) = &self.properties.outbox {
-                        outbox.send(message).await.expect("Failed to send message");
+                let received = tokio::select! {
+                    _ = self.properties.shutdown.cancelled() => break,
+                    received = socket.recv_from(&mut buffer) => received,
+                };
+
+                let Ok((size, sender)) = received else {
+                    continue;
+                };
+
+                let datagram = &buffer[..size];
+                let messages = match self.configuration.parser {
+                    Some(parser) => parser.parse(datagram),
+                    None => vec![Message::from_body(datagram.to_vec())],
+                };
+
+                for mut message in messages {
+                    message
+                        .fields
+                        .insert("message_size".into(), (size as i64).into());
+                    message
+                        .fields
+                        .insert("remote_address".into(), sender.ip().to_string().into());
+                    message
+                        .fields
+                        .insert("remote_port".into(), sender.port().into());
+
+                    if self.properties.outboxes.send(message).await.is_err() {
+                        return;
                     }
                 }
-                // Connection closed.
             }
         })
     }
 }
+
+impl UDPSocketListener {
+    /// Checks the settings, including the addresses, without binding.
+    pub(crate) fn validate(configuration: &ModuleProperties) -> Result<(), ConfigurationError> {
+        configuration
+            .settings::<UdpSocketConfiguration>()?
+            .addresses()
+            .map(|_| ())
+    }
+
+    pub(crate) fn get_settings() -> serde_json::Value {
+        let default_settings = UdpSocketConfiguration::default();
+        serde_json::to_value(default_settings).expect("Failed to serialize default settings")
+    }
+}
+
+inventory::submit! {
+    ModuleRegistration {
+        name: "udpsocketlistener",
+        description: "Receives UDP datagrams and sends each one as a message",
+        constructor: registry::construct::<UDPSocketListener>,
+        validate: UDPSocketListener::validate,
+        settings: UDPSocketListener::get_settings,
+        capabilities: Capabilities::SOURCE,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn a_port_in_use_stops_the_listener_instead_of_panicking() {
+        let taken = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = taken.local_addr().unwrap().port();
+        let properties: ModuleProperties = toml::from_str(&format!(
+            "module_type = \"udpsocketlistener\"\naddress = \"127.0.0.1\"\nport = {}\nbuffer_size = 0",
+            port
+        ))
+        .unwrap();
+
+        let listener = Box::new(UDPSocketListener::new(properties).unwrap());
+
+        assert!(listener.run().await.is_ok());
+    }
+}
@@ -0,0 +1,285 @@
+use std::{
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::net::{lookup_host, UdpSocket};
+
+use crate::{
+    configuration::{error::ConfigurationError, module_properties::ModuleProperties},
+    messaging::{mailbox::Inboxes, serialization::Serialization},
+    modules::{
+        module::ModuleTrait,
+        registry::{self, Capabilities, ModuleRegistration},
+    },
+};
+
+// Delay before trying to set up the socket again, doubled after every failed
+// attempt.
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UDPWriterConfiguration {
+    address: String,
+    port: u16,
+
+    // Must be set to send to a broadcast address.
+    #[serde(default)]
+    broadcast: bool,
+
+    // How many hops multicast datagrams may go through.
+    #[serde(default = "UDPWriterConfiguration::default_multicast_ttl")]
+    multicast_ttl: u32,
+
+    #[serde(default)]
+    serialization: Serialization,
+
+    // Fields written with the "fields" serialization. All of them if unset.
+    #[serde(default)]
+    fields: Option<Vec<String>>,
+
+    // If set, messages waiting to be sent are put together in one datagram,
+    // separated by newlines, as long as it stays within this many bytes.
+    #[serde(default)]
+    mtu: Option<usize>,
+}
+
+impl UDPWriterConfiguration {
+    fn default_multicast_ttl() -> u32 {
+        1
+    }
+
+    // Resolves the destination, and sets up a socket to send to it.
+    async fn open(&self) -> io::Result<(UdpSocket, SocketAddr)> {
+        let destination = lookup_host((self.address.as_str(), self.port))
+            .await?
+            .next()
+            .ok_or_else(|| io::Error::new(ErrorKind::NotFound, "no address found"))?;
+
+        let local: SocketAddr = if destination.is_ipv4() {
+            "0.0.0.0:0".parse().unwrap()
+        } else {
+            "[::]:0".parse().unwrap()
+        };
+
+        let socket = UdpSocket::bind(local).await?;
+        socket.set_broadcast(self.broadcast)?;
+
+        if destination.ip().is_multicast() && destination.is_ipv4() {
+            socket.set_multicast_ttl_v4(self.multicast_ttl)?;
+        }
+
+        Ok((socket, destination))
+    }
+}
+
+impl Default for UDPWriterConfiguration {
+    fn default() -> Self {
+        UDPWriterConfiguration {
+            address: String::new(),
+            port: 0,
+            broadcast: false,
+            multicast_ttl: Self::default_multicast_ttl(),
+            serialization: Serialization::default(),
+            fields: None,
+            mtu: None,
+        }
+    }
+}
+
+pub struct UDPSocketWriter {
+    pub(crate) properties: ModuleProperties,
+    configuration: UDPWriterConfiguration,
+}
+
+impl ModuleTrait for UDPSocketWriter {
+    fn new(configuration: ModuleProperties) -> Result<Self, ConfigurationError>
+    where
+        Self: Sized,
+    {
+        let module_config: UDPWriterConfiguration = configuration.settings()?;
+
+        Ok(Self {
+            properties: configuration,
+            configuration: module_config,
+        })
+    }
+
+    fn add_inbox(
+        &mut self,
+        name: String,
+        inbox: async_channel::Receiver<crate::messaging::message::Message>,
+    ) {
+        self.properties.inboxes.insert(name, inbox);
+    }
+
+    fn run(self: Box<Self>) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let configuration = self.configuration;
+
+            // Messages wait in the inboxes while the socket cannot be set
+            // up, e.g. while the destination does not resolve.
+            let mut backoff = RETRY_BACKOFF;
+            let (socket, destination) = loop {
+                match configuration.open().await {
+                    Ok(opened) => break opened,
+                    Err(e) => {
+                        println!(
+                            "Error setting up UDP socket to {}:{}: {}, retrying in {:?}",
+                            configuration.address, configuration.port, e, backoff
+                        );
+
+                        tokio::select! {
+                            _ = self.properties.shutdown.cancelled() => return,
+                            _ = tokio::time::sleep(backoff) => {}
+                        }
+                        backoff = (backoff * 2).min(MAX_RETRY_BACKOFF);
+                    }
+                }
+            };
+
+            let writer = DatagramWriter {
+                socket,
+                destination,
+                serialization: configuration.serialization,
+                fields: configuration.fields,
+                mtu: configuration.mtu,
+            };
+            writer.run(self.properties.inboxes).await;
+        })
+    }
+}
+
+struct DatagramWriter {
+    socket: UdpSocket,
+    destination: SocketAddr,
+    serialization: Serialization,
+    fields: Option<Vec<String>>,
+    mtu: Option<usize>,
+}
+
+impl DatagramWriter {
+    async fn run(&self, inboxes: Inboxes) {
+        let mut batch: Vec<u8> = vec![];
+
+        while let Ok(message) = inboxes.recv().await {
+            let mut next = Some(message);
+
+            // Take whatever else is already waiting, so it can share datagrams.
+            while let Some(message) = next.take() {
+                let bytes = self
+                    .serialization
+                    .serialize(&message, self.fields.as_deref());
+
+                match self.mtu {
+                    None => self.send(&bytes).await,
+                    Some(mtu) => {
+                        if !batch.is_empty() && batch.len() + 1 + bytes.len() > mtu {
+                            self.send(&batch).await;
+                            batch.clear();
+                        }
+
+                        if !batch.is_empty() {
+                            batch.push(b'\n');
+                        }
+                        batch.extend_from_slice(&bytes);
+                    }
+                }
+
+                next = inboxes.try_recv().ok();
+            }
+
+            if !batch.is_empty() {
+                self.send(&batch).await;
+                batch.clear();
+            }
+        }
+    }
+
+    async fn send(&self, datagram: &[u8]) {
+        if let Err(e) = self.socket.send_to(datagram, self.destination).await {
+            println!("Error sending datagram to {}: {:?}", self.destination, e);
+        }
+    }
+}
+
+impl UDPSocketWriter {
+    pub(crate) fn get_settings() -> serde_json::Value {
+        let default_settings = UDPWriterConfiguration::default();
+        serde_json::to_value(default_settings).expect("Failed to serialize default settings")
+    }
+}
+
+inventory::submit! {
+    ModuleRegistration {
+        name: "udpwriter",
+        description: "Sends every message it receives as a UDP datagram, to a unicast, broadcast or multicast address",
+        constructor: registry::construct::<UDPSocketWriter>,
+        validate: registry::validate::<UDPWriterConfiguration>,
+        settings: UDPSocketWriter::get_settings,
+        capabilities: Capabilities::SINK,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::messaging::message::Message;
+
+    use super::*;
+
+    // Sends `data` through a writer with the given MTU, and returns the
+    // datagrams it sent.
+    async fn datagrams(mtu: usize, data: &[&str]) -> Vec<String> {
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let writer = DatagramWriter {
+            socket: UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+            destination: receiver.local_addr().unwrap(),
+            serialization: Serialization::Body,
+            fields: None,
+            mtu: Some(mtu),
+        };
+
+        // Everything is waiting before the writer starts, so that it all
+        // goes out in one batch.
+        let (sender, inbox) = async_channel::unbounded();
+        for data in data {
+            let message = Message::new(HashMap::from([("data".to_string(), (*data).into())]));
+            sender.send(message).await.unwrap();
+        }
+        drop(sender);
+
+        let mut inboxes = Inboxes::default();
+        inboxes.insert("main".into(), inbox);
+        writer.run(inboxes).await;
+
+        let mut datagrams = vec![];
+        let mut buffer = [0u8; 1024];
+        while let Ok(Ok(size)) =
+            tokio::time::timeout(Duration::from_millis(100), receiver.recv(&mut buffer)).await
+        {
+            datagrams.push(String::from_utf8(buffer[..size].to_vec()).unwrap());
+        }
+        datagrams
+    }
+
+    #[tokio::test]
+    async fn waiting_messages_share_datagrams_up_to_the_mtu() {
+        assert_eq!(
+            datagrams(9, &["aaaa", "bbbb", "cccc", "dd", "e"]).await,
+            vec!["aaaa\nbbbb", "cccc\ndd\ne"]
+        );
+    }
+
+    #[tokio::test]
+    async fn messages_bigger_than_the_mtu_are_sent_on_their_own() {
+        assert_eq!(
+            datagrams(9, &["aaaa", "bbbbbbbbbbbbbbb", "cccc"]).await,
+            vec!["aaaa", "bbbbbbbbbbbbbbb", "cccc"]
+        );
+    }
+}
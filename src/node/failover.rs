@@ -0,0 +1,271 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use async_channel::{Receiver, SendError, Sender};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::{configuration::global_configuration::NodeConfiguration, messaging::message::Message};
+
+use super::{
+    link::{Address, Link, Queue},
+    membership::{Member, Membership},
+};
+
+// How many messages rejected by members are remembered, along with the
+// members that rejected them.
+const MAX_REJECTED: usize = 1024;
+
+// A message going back to the members, along with the member that rejected
+// it, if it was rejected rather than left unacknowledged.
+type Requeued = (Message, Option<Uuid>);
+
+/// Sends messages for `module@*` to the alive members of the cluster that
+/// receive messages for the module, taking turns. When a member dies, the
+/// messages it has not acknowledged are sent to the other members. Messages
+/// a member rejects are sent to the other members too, and dropped once every
+/// member rejected them.
+pub struct Failover {
+    pub module: String,
+    pub membership: Arc<Membership>,
+
+    // Stage id and name of this node.
+    pub node: Uuid,
+    pub name: String,
+
+    pub configuration: NodeConfiguration,
+    pub stop: CancellationToken,
+}
+
+impl Failover {
+    /// Sends what arrives on `receiver` until it is closed, then waits for
+    /// every member to acknowledge what it was sent.
+    pub async fn run(self, receiver: Receiver<Message>) {
+        let (requeue, requeued) = async_channel::unbounded::<Requeued>();
+        let mut links: HashMap<Uuid, (Queue, JoinHandle<()>)> = HashMap::new();
+        let mut changes = self.membership.subscribe();
+        let mut rejected_by: HashMap<Uuid, HashSet<Uuid>> = HashMap::new();
+        let mut next = 0;
+
+        'messages: loop {
+            let mut message = tokio::select! {
+                biased;
+                Ok((message, rejecter)) = requeued.recv() => {
+                    if let Some(rejecter) = rejecter {
+                        // Forgetting who rejected messages only means trying
+                        // them on those members again.
+                        if rejected_by.len() >= MAX_REJECTED {
+                            rejected_by.clear();
+                        }
+                        rejected_by.entry(message.id).or_default().insert(rejecter);
+                    }
+                    message
+                }
+                message = receiver.recv() => match message {
+                    Ok(message) => message,
+                    Err(_) => break,
+                },
+                _ = self.stop.cancelled() => break,
+            };
+
+            loop {
+                let members = self.membership.alive_with(&self.module);
+                if members.is_empty() {
+                    // Wait for a member to take the message, without taking
+                    // any more from the route meanwhile.
+                    tokio::select! {
+                        _ = changes.changed() => continue,
+                        _ = self.stop.cancelled() => break 'messages,
+                    }
+                }
+
+                let Some(member) = take_turns(members, rejected_by.get(&message.id), &mut next)
+                else {
+                    println!(
+                        "Dropping message {} for {}@*, every member rejected it",
+                        message.id, self.module
+                    );
+                    rejected_by.remove(&message.id);
+                    continue 'messages;
+                };
+
+                let (queue, _) = links
+                    .entry(member.id)
+                    .or_insert_with(|| self.link(&member, requeue.clone()));
+
+                // A link only stops taking messages once its member is dead.
+                match queue.send((self.module.clone(), message)).await {
+                    Ok(_) => break,
+                    Err(SendError((_, returned))) => {
+                        message = returned;
+                        links.remove(&member.id);
+                    }
+                }
+            }
+        }
+
+        let handles: Vec<JoinHandle<()>> = links.into_values().map(|(_, handle)| handle).collect();
+        for handle in handles {
+            let _ = handle.await;
+        }
+
+        if !requeued.is_empty() {
+            println!(
+                "Giving up on {} messages for {}@*, no member is left to take them",
+                requeued.len(),
+                self.module
+            );
+        }
+    }
+
+    // Starts a link to `member`, stopped once the member is dead. What the
+    // member rejects, and what it has not acknowledged by then, goes back to
+    // `requeue`.
+    fn link(&self, member: &Member, requeue: Sender<Requeued>) -> (Queue, JoinHandle<()>) {
+        let (queue, receiver) = async_channel::bounded(1);
+        let (rejected, rejections) = async_channel::unbounded();
+        let link = Link {
+            peer: member.name.clone(),
+            address: Address::Fixed(member.address.clone()),
+            node: self.node,
+            name: self.name.clone(),
+            configuration: self.configuration.clone(),
+            rejected: Some(rejected),
+        };
+
+        let stop = self.stop.child_token();
+        let membership = self.membership.clone();
+        let given_up = self.stop.clone();
+        let id = member.id;
+        let name = member.name.clone();
+
+        let handle = tokio::spawn(async move {
+            let dead = stop.clone();
+            let watcher = tokio::spawn(async move {
+                membership.wait_dead(id).await;
+                dead.cancel();
+            });
+
+            let rejecter = requeue.clone();
+            let forwarder = tokio::spawn(async move {
+                while let Ok(message) = rejections.recv().await {
+                    let _ = rejecter.send((message, Some(id))).await;
+                }
+            });
+
+            let unacknowledged = link.run(receiver, stop).await;
+            watcher.abort();
+            let _ = forwarder.await;
+
+            if unacknowledged.is_empty() {
+                return;
+            }
+
+            if given_up.is_cancelled() {
+                println!(
+                    "Giving up on {} unacknowledged messages for node {}",
+                    unacknowledged.len(),
+                    name
+                );
+                return;
+            }
+
+            println!(
+                "Sending the {} messages node {} did not acknowledge to other members",
+                unacknowledged.len(),
+                name
+            );
+            for (_, message) in unacknowledged {
+                let _ = requeue.send((message, None)).await;
+            }
+        });
+
+        (queue, handle)
+    }
+}
+
+// The member to send a message to, taking turns among `members` but for those
+// that rejected it. None if every one of them rejected it.
+fn take_turns(
+    mut members: Vec<Member>,
+    rejecters: Option<&HashSet<Uuid>>,
+    next: &mut usize,
+) -> Option<Member> {
+    if let Some(rejecters) = rejecters {
+        members.retain(|member| !rejecters.contains(&member.id));
+    }
+    if members.is_empty() {
+        return None;
+    }
+
+    let member = members.swap_remove(*next % members.len());
+    *next += 1;
+    Some(member)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::configuration::global_configuration::ClusterConfiguration;
+
+    use super::*;
+
+    fn member(name: &str, modules: &[&str]) -> Member {
+        Member {
+            id: Uuid::new_v4(),
+            name: name.into(),
+            address: format!("{}:9000", name),
+            incarnation: 1,
+            heartbeat: 1,
+            modules: modules.iter().map(|module| module.to_string()).collect(),
+        }
+    }
+
+    fn names(picked: impl IntoIterator<Item = Option<Member>>) -> Vec<Option<String>> {
+        picked
+            .into_iter()
+            .map(|member| member.map(|member| member.name))
+            .collect()
+    }
+
+    #[test]
+    fn messages_go_to_the_alive_members_with_the_module_in_turn() {
+        let configuration: ClusterConfiguration = toml::from_str("").unwrap();
+        let membership =
+            Membership::new(Uuid::new_v4(), "me".into(), "me:9000".into(), configuration);
+        membership.set_modules(vec!["process".into()]);
+        membership.merge(vec![
+            member("b", &["process"]),
+            member("a", &["other", "process"]),
+            member("c", &["other"]),
+        ]);
+
+        let mut next = 0;
+        let picked: Vec<Option<Member>> = (0..3)
+            .map(|_| take_turns(membership.alive_with("process"), None, &mut next))
+            .collect();
+
+        // This node is left out, even though it has the module.
+        assert_eq!(
+            names(picked),
+            vec![Some("a".into()), Some("b".into()), Some("a".into())]
+        );
+    }
+
+    #[test]
+    fn members_that_rejected_a_message_are_left_out() {
+        let members = vec![member("a", &["process"]), member("b", &["process"])];
+        let mut next = 0;
+
+        let rejecters = HashSet::from([members[0].id]);
+        let picked: Vec<Option<Member>> = (0..2)
+            .map(|_| take_turns(members.clone(), Some(&rejecters), &mut next))
+            .collect();
+        assert_eq!(names(picked), vec![Some("b".into()), Some("b".into())]);
+
+        let rejecters = HashSet::from([members[0].id, members[1].id]);
+        assert!(take_turns(members, Some(&rejecters), &mut next).is_none());
+    }
+}
@@ -0,0 +1,278 @@
+use std::{collections::VecDeque, io, sync::Arc, time::Duration};
+
+use async_channel::{Receiver, Sender};
+use futures::StreamExt;
+use tokio::{
+    io::AsyncWriteExt,
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    time::{sleep_until, timeout, Instant},
+};
+use tokio_util::{codec::FramedRead, sync::CancellationToken};
+use uuid::Uuid;
+
+use crate::{
+    configuration::global_configuration::NodeConfiguration,
+    messaging::{framing::FrameDecoder, message::Message},
+};
+
+use super::{
+    membership::Membership,
+    protocol::{self, Frame},
+};
+
+// How long to wait for the other node to accept a connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where messages for another node are queued, along with the module they
+/// are for.
+pub type Queue = Sender<(String, Message)>;
+
+type Connection = (FramedRead<OwnedReadHalf, FrameDecoder>, OwnedWriteHalf);
+
+/// Where to reach another node.
+pub enum Address {
+    /// A fixed address, from node.peers or from gossip.
+    Fixed(String),
+
+    /// The address of the cluster member with the given name, looked up on
+    /// every connection attempt as members come and go.
+    Member(String, Arc<Membership>),
+}
+
+impl Address {
+    fn resolve(&self) -> io::Result<String> {
+        match self {
+            Address::Fixed(address) => Ok(address.clone()),
+            Address::Member(name, membership) => membership.address_of(name).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "not a live member of the cluster")
+            }),
+        }
+    }
+}
+
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Address::Fixed(address) => write!(f, "{}", address),
+            Address::Member(name, membership) => match membership.address_of(name) {
+                Some(address) => write!(f, "{}", address),
+                None => write!(f, "an unknown address"),
+            },
+        }
+    }
+}
+
+/// The connection to another node. Messages are kept until the other node
+/// acknowledges or rejects them, and sent again after reconnecting, so they
+/// survive the other node restarting.
+pub struct Link {
+    pub peer: String,
+    pub address: Address,
+
+    // Stage id and name of this node.
+    pub node: Uuid,
+    pub name: String,
+
+    pub configuration: NodeConfiguration,
+
+    // Where messages the other node rejects go, dropped if unset.
+    pub rejected: Option<Sender<Message>>,
+}
+
+impl Link {
+    /// Sends the `(module, message)` pairs from `queue` until it is closed and
+    /// everything has been acknowledged, or until `stop` is cancelled. Returns
+    /// the messages that were not acknowledged.
+    pub async fn run(
+        self,
+        queue: Receiver<(String, Message)>,
+        stop: CancellationToken,
+    ) -> Vec<(String, Message)> {
+        let initial_backoff = Duration::from_millis(self.configuration.reconnect_backoff_ms);
+        let max_backoff = Duration::from_millis(self.configuration.max_reconnect_backoff_ms);
+        let window = self.configuration.max_unacknowledged.max(1);
+
+        // Sequence numbers are only unique within a link, so the other node
+        // tells links apart by their session.
+        let hello = protocol::encode(&Frame::Hello {
+            node: self.node,
+            name: self.name.clone(),
+            session: Uuid::new_v4(),
+        })
+        .expect("Failed to encode hello");
+
+        let mut unacknowledged: VecDeque<(u64, Vec<u8>)> = VecDeque::new();
+        let mut next_seq = 1;
+        let mut connection: Option<Connection> = None;
+        let mut backoff = initial_backoff;
+        let mut next_attempt = Instant::now();
+        let mut open = true;
+
+        loop {
+            if !open && unacknowledged.is_empty() {
+                break;
+            }
+
+            if connection.is_none() && Instant::now() >= next_attempt {
+                match timeout(CONNECT_TIMEOUT, self.connect(&hello, &unacknowledged)).await {
+                    Ok(Ok(connected)) => {
+                        println!("Connected to node {} at {}", self.peer, self.address);
+                        if !unacknowledged.is_empty() {
+                            println!(
+                                "Sent the {} unacknowledged messages for node {}",
+                                unacknowledged.len(),
+                                self.peer
+                            );
+                        }
+                        connection = Some(connected);
+                        backoff = initial_backoff;
+                    }
+                    failed => {
+                        let reason = match failed {
+                            Ok(Err(e)) => e.to_string(),
+                            _ => "timed out".to_string(),
+                        };
+                        println!(
+                            "Could not connect to node {} at {} ({}), retrying in {:?}",
+                            self.peer, self.address, reason, backoff
+                        );
+                        next_attempt = Instant::now() + backoff;
+                        backoff = (backoff * 2).min(max_backoff);
+                    }
+                }
+            }
+
+            tokio::select! {
+                _ = stop.cancelled() => break,
+                item = queue.recv(), if open && unacknowledged.len() < window => {
+                    let Ok((module, message)) = item else {
+                        open = false;
+                        continue;
+                    };
+
+                    let frame = Frame::Message {
+                        seq: next_seq,
+                        module,
+                        message: (&message).into(),
+                    };
+                    let frame = match protocol::encode(&frame) {
+                        Ok(frame) => frame,
+                        Err(e) => {
+                            println!(
+                                "Dropping message {} for node {}: {}",
+                                message.id, self.peer, e
+                            );
+                            continue;
+                        }
+                    };
+
+                    if let Some((_, writer)) = connection.as_mut() {
+                        if let Err(e) = writer.write_all(&frame).await {
+                            // The frame is kept, and sent again once reconnected.
+                            println!("Error writing to node {}: {}", self.peer, e);
+                            connection = None;
+                            next_attempt = Instant::now();
+                        }
+                    }
+
+                    unacknowledged.push_back((next_seq, frame));
+                    next_seq += 1;
+                }
+                frame = next_frame(&mut connection), if connection.is_some() => match frame {
+                    Some(Ok(Frame::Ack { seq })) => {
+                        while unacknowledged.front().is_some_and(|(sent, _)| *sent <= seq) {
+                            unacknowledged.pop_front();
+                        }
+                    }
+                    Some(Ok(Frame::Reject { seq, reason })) => {
+                        let Some(index) = unacknowledged
+                            .iter()
+                            .position(|(sent, _)| *sent == seq)
+                        else {
+                            continue;
+                        };
+                        let frame = unacknowledged.remove(index).unwrap_or_default().1;
+                        let Some((module, message)) = message_of(&frame) else {
+                            continue;
+                        };
+
+                        println!(
+                            "Node {} rejected message {} for {}: {}",
+                            self.peer, message.id, module, reason
+                        );
+                        match &self.rejected {
+                            Some(rejected) => {
+                                let _ = rejected.send(message).await;
+                            }
+                            None => println!(
+                                "Dropping message {} for {}@{}",
+                                message.id, module, self.peer
+                            ),
+                        }
+                    }
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) => {
+                        println!("Connection to node {} failed: {}", self.peer, e);
+                        connection = None;
+                        next_attempt = Instant::now();
+                    }
+                    None => {
+                        println!("Connection to node {} closed", self.peer);
+                        connection = None;
+                        next_attempt = Instant::now() + backoff;
+                    }
+                },
+                _ = sleep_until(next_attempt), if connection.is_none() => {}
+            }
+        }
+
+        if let Some((_, mut writer)) = connection {
+            let _ = writer.shutdown().await;
+        }
+
+        unacknowledged
+            .into_iter()
+            .filter_map(|(_, frame)| message_of(&frame))
+            .collect()
+    }
+
+    // Connects, introduces this node, and sends again whatever has not been
+    // acknowledged yet.
+    async fn connect(
+        &self,
+        hello: &[u8],
+        unacknowledged: &VecDeque<(u64, Vec<u8>)>,
+    ) -> io::Result<Connection> {
+        let stream = TcpStream::connect(self.address.resolve()?).await?;
+        stream.set_nodelay(true)?;
+
+        let (reader, mut writer) = stream.into_split();
+        writer.write_all(hello).await?;
+
+        for (_, frame) in unacknowledged {
+            writer.write_all(frame).await?;
+        }
+
+        Ok((FramedRead::new(reader, protocol::decoder()), writer))
+    }
+}
+
+// Takes a message frame apart again, past its u32 length prefix, rather than
+// keeping a copy of every message around.
+fn message_of(frame: &[u8]) -> Option<(String, Message)> {
+    match protocol::decode(&frame[4..]) {
+        Ok(Frame::Message {
+            module, message, ..
+        }) => Some((module, Message::try_from(message).ok()?)),
+        _ => None,
+    }
+}
+
+async fn next_frame(connection: &mut Option<Connection>) -> Option<io::Result<Frame>> {
+    let (reader, _) = connection.as_mut()?;
+    let bytes = reader.next().await?;
+    Some(bytes.and_then(|bytes| protocol::decode(&bytes)))
+}
@@ -0,0 +1,324 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_channel::Sender;
+use futures::StreamExt;
+use tokio::{
+    io::AsyncWriteExt,
+    net::{tcp::OwnedReadHalf, TcpListener, TcpStream},
+    time::Instant,
+};
+use tokio_util::{codec::FramedRead, sync::CancellationToken};
+use uuid::Uuid;
+
+use crate::messaging::{framing::FrameDecoder, message::Message};
+
+use super::{
+    membership::{self, Membership},
+    protocol::{self, Frame},
+};
+
+/// Where messages from other nodes are handed to, by module name.
+pub type RemoteInboxes = Arc<Mutex<HashMap<String, Sender<Message>>>>;
+
+// How many rejected messages are remembered for every session.
+const MAX_REJECTED: usize = 1024;
+
+// How long a session is remembered once none of its connections are left.
+// A link reconnecting later than this may have messages it sent just before
+// losing its connection delivered twice.
+const SESSION_TTL: Duration = Duration::from_secs(60 * 60);
+
+// What was delivered for every session. Messages sent again after a
+// reconnection that were already delivered are acknowledged without
+// delivering them twice, while rejected ones are tried again.
+type Delivered = Arc<Mutex<HashMap<Uuid, Session>>>;
+
+struct Session {
+    // The last message delivered or rejected.
+    last: u64,
+
+    // The most recent messages up to `last` that were rejected.
+    rejected: BTreeSet<u64>,
+
+    // Connections currently open for the session, and when the last one
+    // closed.
+    connections: usize,
+    disconnected_at: Instant,
+}
+
+// Keeps a session from being forgotten while one of its connections is open.
+struct Connected {
+    delivered: Delivered,
+    session: Uuid,
+}
+
+impl Connected {
+    // Forgets the sessions that have had no connection for `SESSION_TTL`.
+    fn new(delivered: Delivered, session: Uuid) -> Self {
+        let mut sessions = delivered.lock().unwrap();
+        sessions.retain(|_, session| {
+            session.connections > 0 || session.disconnected_at.elapsed() < SESSION_TTL
+        });
+        sessions
+            .entry(session)
+            .or_insert_with(Session::new)
+            .connections += 1;
+        drop(sessions);
+
+        Connected { delivered, session }
+    }
+}
+
+impl Drop for Connected {
+    fn drop(&mut self) {
+        if let Some(session) = self.delivered.lock().unwrap().get_mut(&self.session) {
+            session.connections -= 1;
+            session.disconnected_at = Instant::now();
+        }
+    }
+}
+
+impl Session {
+    fn new() -> Self {
+        Session {
+            last: 0,
+            rejected: BTreeSet::new(),
+            connections: 0,
+            disconnected_at: Instant::now(),
+        }
+    }
+
+    fn delivered(&self, seq: u64) -> bool {
+        seq <= self.last && !self.rejected.contains(&seq)
+    }
+
+    fn record(&mut self, seq: u64, rejected: bool) {
+        self.last = self.last.max(seq);
+
+        if !rejected {
+            self.rejected.remove(&seq);
+            return;
+        }
+
+        self.rejected.insert(seq);
+        if self.rejected.len() > MAX_REJECTED {
+            self.rejected.pop_first();
+        }
+    }
+}
+
+/// Accepts connections from other nodes until `shutdown` is cancelled.
+/// Gossip is answered if this node is part of a cluster.
+pub async fn listen(
+    listener: TcpListener,
+    inboxes: RemoteInboxes,
+    membership: Option<Arc<Membership>>,
+    shutdown: CancellationToken,
+) {
+    let delivered: Delivered = Arc::default();
+
+    loop {
+        let (stream, address) = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    println!("Error accepting a connection from another node: {}", e);
+                    continue;
+                }
+            },
+        };
+
+        tokio::spawn(receive(
+            stream,
+            address,
+            inboxes.clone(),
+            membership.clone(),
+            delivered.clone(),
+            shutdown.clone(),
+        ));
+    }
+}
+
+// Hands the messages of one connection to their modules, acknowledging each
+// one once it is on its module's inbox. Messages no module here can take are
+// rejected, so that the other node knows they were not delivered. Messages
+// not acknowledged when shutting down are sent again by the other node once
+// this one is back.
+async fn receive(
+    stream: TcpStream,
+    address: SocketAddr,
+    inboxes: RemoteInboxes,
+    membership: Option<Arc<Membership>>,
+    delivered: Delivered,
+    shutdown: CancellationToken,
+) {
+    let (reader, mut writer) = stream.into_split();
+    let mut frames = FramedRead::new(reader, protocol::decoder());
+
+    let (name, session) = match next_frame(&mut frames).await {
+        Some(Ok(Frame::Hello { name, session, .. })) => (name, session),
+        Some(Ok(Frame::Gossip { members })) if membership.is_some() => {
+            if let Some(membership) = membership {
+                membership::answer(&membership, members, &mut writer).await;
+            }
+            return;
+        }
+        _ => {
+            println!("Closing connection from {}: expected a hello", address);
+            return;
+        }
+    };
+
+    println!("Node {} connected from {}", name, address);
+    let _connected = Connected::new(delivered.clone(), session);
+
+    loop {
+        let frame = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            frame = next_frame(&mut frames) => frame,
+        };
+
+        let (seq, module, message) = match frame {
+            Some(Ok(Frame::Message {
+                seq,
+                module,
+                message,
+            })) => (seq, module, message),
+            Some(Ok(_)) => continue,
+            Some(Err(e)) => {
+                println!("Closing connection from node {}: {}", name, e);
+                break;
+            }
+            None => {
+                println!("Node {} disconnected", name);
+                break;
+            }
+        };
+
+        let duplicate = delivered
+            .lock()
+            .unwrap()
+            .get(&session)
+            .is_some_and(|delivered| delivered.delivered(seq));
+
+        let mut reply = Frame::Ack { seq };
+        if !duplicate {
+            let inbox = inboxes.lock().unwrap().get(&module).cloned();
+
+            let rejection = match (Message::try_from(message), inbox) {
+                (Ok(message), Some(inbox)) => {
+                    tokio::select! {
+                        _ = shutdown.cancelled() => break,
+                        sent = inbox.send(message) => if sent.is_err() {
+                            break;
+                        },
+                    }
+                    None
+                }
+                (Ok(_), None) => Some(format!(
+                    "there is no module {:?} receiving messages here",
+                    module
+                )),
+                (Err(e), _) => Some(format!("the message is unreadable: {}", e)),
+            };
+
+            if let Some(delivered) = delivered.lock().unwrap().get_mut(&session) {
+                delivered.record(seq, rejection.is_some());
+            }
+
+            if let Some(reason) = rejection {
+                println!("Rejecting message {} from node {}: {}", seq, name, reason);
+                reply = Frame::Reject { seq, reason };
+            }
+        }
+
+        let written = match protocol::encode(&reply) {
+            Ok(reply) => writer.write_all(&reply).await,
+            Err(e) => Err(e),
+        };
+
+        if let Err(e) = written {
+            println!("Error answering messages from node {}: {}", name, e);
+            break;
+        }
+    }
+}
+
+async fn next_frame(
+    frames: &mut FramedRead<OwnedReadHalf, FrameDecoder>,
+) -> Option<io::Result<Frame>> {
+    let bytes = frames.next().await?;
+    Some(bytes.and_then(|bytes| protocol::decode(&bytes)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_up_to_the_last_one_are_delivered() {
+        let mut session = Session::new();
+        assert!(!session.delivered(1));
+
+        session.record(1, false);
+        session.record(3, false);
+
+        // Acknowledgements are cumulative, so 2 was delivered before 3.
+        assert!(session.delivered(1));
+        assert!(session.delivered(2));
+        assert!(session.delivered(3));
+        assert!(!session.delivered(4));
+    }
+
+    #[test]
+    fn rejected_messages_are_tried_again() {
+        let mut session = Session::new();
+        session.record(1, true);
+        session.record(2, false);
+
+        assert!(!session.delivered(1));
+        assert!(session.delivered(2));
+
+        session.record(1, false);
+        assert!(session.delivered(1));
+        assert!(session.rejected.is_empty());
+        assert_eq!(session.last, 2);
+    }
+
+    #[test]
+    fn only_the_most_recent_rejections_are_remembered() {
+        let mut session = Session::new();
+        for seq in 1..=MAX_REJECTED as u64 + 1 {
+            session.record(seq, true);
+        }
+
+        assert_eq!(session.rejected.len(), MAX_REJECTED);
+        assert!(session.delivered(1));
+        assert!(!session.delivered(2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sessions_are_forgotten_once_disconnected_for_long_enough() {
+        let delivered: Delivered = Arc::default();
+        let (open, closed, recent) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+
+        let _open = Connected::new(delivered.clone(), open);
+        drop(Connected::new(delivered.clone(), closed));
+        tokio::time::advance(SESSION_TTL).await;
+        drop(Connected::new(delivered.clone(), recent));
+
+        // Connecting again is when old sessions are forgotten.
+        let _again = Connected::new(delivered.clone(), recent);
+        let sessions = delivered.lock().unwrap();
+        assert!(sessions.contains_key(&open));
+        assert!(!sessions.contains_key(&closed));
+        assert_eq!(sessions[&recent].connections, 1);
+    }
+}
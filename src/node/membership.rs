@@ -0,0 +1,482 @@
+use std::{
+    collections::HashMap,
+    io,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    sync::watch,
+    time::{interval, timeout, Instant, MissedTickBehavior},
+};
+use tokio_util::{codec::FramedRead, sync::CancellationToken};
+use uuid::Uuid;
+
+use crate::configuration::global_configuration::ClusterConfiguration;
+
+use super::protocol::{self, Frame};
+
+// How long a gossip exchange with another member may take.
+const GOSSIP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// What members tell each other about every member of the cluster.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Member {
+    // The member's stage id.
+    pub id: Uuid,
+    pub name: String,
+
+    // Address the member listens for other nodes on.
+    pub address: String,
+
+    // Changes every time the member starts, so that a member that restarted
+    // is not taken for an old version of itself.
+    pub incarnation: u64,
+
+    // Increased by the member itself, for as long as it is running.
+    pub heartbeat: u64,
+
+    // Modules of the member receiving messages from other nodes.
+    pub modules: Vec<String>,
+}
+
+impl Member {
+    fn version(&self) -> (u64, u64) {
+        (self.incarnation, self.heartbeat)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemberState {
+    Alive,
+    /// Not heard from in a while. Sent no new messages, but not given up on.
+    Suspect,
+    /// Not heard from for long enough to be given up on.
+    Dead,
+}
+
+struct Entry {
+    member: Member,
+    state: MemberState,
+    // When the member's heartbeat last went up.
+    updated: Instant,
+}
+
+/// The members of the cluster as this node knows them, including itself.
+/// Members are told apart by their stage id, and are alive, suspect or dead
+/// depending on how long ago their heartbeat last went up.
+pub struct Membership {
+    id: Uuid,
+    configuration: ClusterConfiguration,
+    entries: Mutex<HashMap<Uuid, Entry>>,
+    changes: watch::Sender<()>,
+}
+
+impl Membership {
+    pub fn new(
+        id: Uuid,
+        name: String,
+        address: String,
+        configuration: ClusterConfiguration,
+    ) -> Self {
+        let incarnation = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as u64)
+            .unwrap_or_default();
+
+        let me = Entry {
+            member: Member {
+                id,
+                name,
+                address,
+                incarnation,
+                heartbeat: 0,
+                modules: vec![],
+            },
+            state: MemberState::Alive,
+            updated: Instant::now(),
+        };
+
+        Membership {
+            id,
+            configuration,
+            entries: Mutex::new(HashMap::from([(id, me)])),
+            changes: watch::channel(()).0,
+        }
+    }
+
+    /// Notified whenever a member joins, or changes state or modules.
+    pub fn subscribe(&self) -> watch::Receiver<()> {
+        self.changes.subscribe()
+    }
+
+    /// Sets the modules of this node that other nodes can send messages to.
+    pub fn set_modules(&self, mut modules: Vec<String>) {
+        modules.sort();
+        if let Some(me) = self.entries.lock().unwrap().get_mut(&self.id) {
+            me.member.modules = modules;
+        }
+    }
+
+    pub fn state(&self, id: Uuid) -> Option<MemberState> {
+        self.entries
+            .lock()
+            .unwrap()
+            .get(&id)
+            .map(|entry| entry.state)
+    }
+
+    /// Other alive members receiving messages for `module`, sorted by name.
+    pub fn alive_with(&self, module: &str) -> Vec<Member> {
+        let mut members: Vec<Member> = self
+            .entries
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|entry| entry.member.id != self.id && entry.state == MemberState::Alive)
+            .filter(|entry| entry.member.modules.iter().any(|name| name == module))
+            .map(|entry| entry.member.clone())
+            .collect();
+
+        members.sort_by(|a, b| a.name.cmp(&b.name));
+        members
+    }
+
+    /// Address of the member called `name`, unless it is dead.
+    pub fn address_of(&self, name: &str) -> Option<String> {
+        self.entries
+            .lock()
+            .unwrap()
+            .values()
+            .find(|entry| entry.member.name == name && entry.state != MemberState::Dead)
+            .map(|entry| entry.member.address.clone())
+    }
+
+    /// Returns once the member `id` is dead.
+    pub async fn wait_dead(&self, id: Uuid) {
+        let mut changes = self.subscribe();
+        while self.state(id) != Some(MemberState::Dead) {
+            if changes.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// What this node tells others: every member it does not think is dead.
+    fn snapshot(&self) -> Vec<Member> {
+        self.entries
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|entry| entry.state != MemberState::Dead)
+            .map(|entry| entry.member.clone())
+            .collect()
+    }
+
+    /// Takes in what another member knows. Newer heartbeats bring members
+    /// back to life.
+    pub fn merge(&self, members: Vec<Member>) {
+        let mut changed = false;
+        let mut entries = self.entries.lock().unwrap();
+
+        for member in members {
+            if member.id == self.id {
+                continue;
+            }
+
+            match entries.get_mut(&member.id) {
+                None => {
+                    println!(
+                        "Node {} joined the cluster at {}",
+                        member.name, member.address
+                    );
+                    entries.insert(
+                        member.id,
+                        Entry {
+                            member,
+                            state: MemberState::Alive,
+                            updated: Instant::now(),
+                        },
+                    );
+                    changed = true;
+                }
+                Some(entry) if member.version() > entry.member.version() => {
+                    if entry.state != MemberState::Alive {
+                        println!("Node {} is alive", member.name);
+                        entry.state = MemberState::Alive;
+                        changed = true;
+                    }
+
+                    changed |= entry.member.modules != member.modules
+                        || entry.member.address != member.address;
+                    entry.member = member;
+                    entry.updated = Instant::now();
+                }
+                Some(_) => {}
+            }
+        }
+
+        drop(entries);
+        if changed {
+            self.changes.send_replace(());
+        }
+    }
+
+    // Beats this node's heart, and updates the state of the other members
+    // according to when they were last heard from.
+    fn tick(&self) {
+        let suspect_after = Duration::from_millis(self.configuration.suspect_after_ms);
+        let dead_after = Duration::from_millis(self.configuration.dead_after_ms);
+
+        let mut changed = false;
+        let mut entries = self.entries.lock().unwrap();
+
+        for entry in entries.values_mut() {
+            if entry.member.id == self.id {
+                entry.member.heartbeat += 1;
+                continue;
+            }
+
+            let silent = entry.updated.elapsed();
+            let state = if silent >= dead_after {
+                MemberState::Dead
+            } else if silent >= suspect_after {
+                MemberState::Suspect
+            } else {
+                MemberState::Alive
+            };
+
+            if state != entry.state {
+                match state {
+                    MemberState::Alive => println!("Node {} is alive", entry.member.name),
+                    MemberState::Suspect => {
+                        println!("Node {} is suspected to be down", entry.member.name)
+                    }
+                    MemberState::Dead => println!("Node {} is dead", entry.member.name),
+                }
+
+                entry.state = state;
+                changed = true;
+            }
+        }
+
+        drop(entries);
+        if changed {
+            self.changes.send_replace(());
+        }
+    }
+
+    // Addresses to gossip with: the members not known to be dead, and the
+    // seeds, which may know of members this node has not heard of yet.
+    fn targets(&self) -> Vec<String> {
+        let entries = self.entries.lock().unwrap();
+        let own_address = &entries[&self.id].member.address;
+
+        let mut targets: Vec<String> = entries
+            .values()
+            .filter(|entry| entry.member.id != self.id && entry.state != MemberState::Dead)
+            .map(|entry| entry.member.address.clone())
+            .chain(self.configuration.seeds.iter().cloned())
+            .filter(|address| address != own_address)
+            .collect();
+
+        targets.sort();
+        targets.dedup();
+        targets
+    }
+}
+
+/// Gossips with one member after another until `shutdown` is cancelled. Each
+/// exchange sends what this node knows, and takes in what the other knows.
+pub async fn gossip(membership: Arc<Membership>, shutdown: CancellationToken) {
+    let mut ticker = interval(Duration::from_millis(
+        membership.configuration.gossip_interval_ms.max(1),
+    ));
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut round = 0;
+
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            _ = ticker.tick() => {}
+        }
+
+        membership.tick();
+
+        let targets = membership.targets();
+        if targets.is_empty() {
+            continue;
+        }
+
+        let target = &targets[round % targets.len()];
+        round += 1;
+
+        // Exchanges run on their own, so that a member that does not answer
+        // does not hold up gossip with the others. Members that cannot be
+        // reached are found out by their heartbeat stopping, so failed
+        // exchanges are not reported.
+        let target = target.clone();
+        let members = membership.snapshot();
+        let membership = membership.clone();
+        tokio::spawn(async move {
+            if let Ok(Ok(members)) = timeout(GOSSIP_TIMEOUT, exchange(&target, members)).await {
+                membership.merge(members);
+            }
+        });
+    }
+}
+
+/// Answers a gossip exchange started by another member.
+pub async fn answer(
+    membership: &Membership,
+    members: Vec<Member>,
+    writer: &mut (impl AsyncWrite + Unpin),
+) {
+    membership.merge(members);
+
+    if let Ok(frame) = protocol::encode(&Frame::Gossip {
+        members: membership.snapshot(),
+    }) {
+        let _ = writer.write_all(&frame).await;
+    }
+}
+
+async fn exchange(address: &str, members: Vec<Member>) -> io::Result<Vec<Member>> {
+    let mut stream = TcpStream::connect(address).await?;
+    stream
+        .write_all(&protocol::encode(&Frame::Gossip { members })?)
+        .await?;
+
+    let mut frames = FramedRead::new(stream, protocol::decoder());
+    match frames.next().await {
+        Some(Ok(bytes)) => match protocol::decode(&bytes)? {
+            Frame::Gossip { members } => Ok(members),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "expected gossip in return",
+            )),
+        },
+        Some(Err(e)) => Err(e),
+        None => Err(io::ErrorKind::UnexpectedEof.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn membership() -> Membership {
+        let configuration: ClusterConfiguration =
+            toml::from_str("suspect_after_ms = 1000\ndead_after_ms = 3000").unwrap();
+
+        Membership::new(Uuid::new_v4(), "me".into(), "me:9000".into(), configuration)
+    }
+
+    fn member(name: &str) -> Member {
+        Member {
+            id: Uuid::new_v4(),
+            name: name.into(),
+            address: format!("{}:9000", name),
+            incarnation: 1,
+            heartbeat: 1,
+            modules: vec!["process".into()],
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn silent_members_are_suspected_then_dead() {
+        let membership = membership();
+        let other = member("other");
+        membership.merge(vec![other.clone()]);
+        let changes = membership.subscribe();
+
+        tokio::time::advance(Duration::from_millis(999)).await;
+        membership.tick();
+        assert_eq!(membership.state(other.id), Some(MemberState::Alive));
+        assert!(!changes.has_changed().unwrap());
+
+        tokio::time::advance(Duration::from_millis(1)).await;
+        membership.tick();
+        assert_eq!(membership.state(other.id), Some(MemberState::Suspect));
+        assert!(changes.has_changed().unwrap());
+
+        // Suspects are sent no new messages, but can still be reached.
+        assert!(membership.alive_with("process").is_empty());
+        assert_eq!(
+            membership.address_of("other").as_deref(),
+            Some("other:9000")
+        );
+
+        tokio::time::advance(Duration::from_millis(2000)).await;
+        membership.tick();
+        assert_eq!(membership.state(other.id), Some(MemberState::Dead));
+        assert_eq!(membership.address_of("other"), None);
+        assert!(membership
+            .snapshot()
+            .iter()
+            .all(|member| member.id != other.id));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn newer_heartbeats_bring_members_back() {
+        let membership = membership();
+        let mut other = member("other");
+        membership.merge(vec![other.clone()]);
+
+        tokio::time::advance(Duration::from_millis(3000)).await;
+        membership.tick();
+        assert_eq!(membership.state(other.id), Some(MemberState::Dead));
+
+        // Hearing the same heartbeat again, e.g. from a member that has not
+        // heard about the death yet, changes nothing.
+        membership.merge(vec![other.clone()]);
+        membership.tick();
+        assert_eq!(membership.state(other.id), Some(MemberState::Dead));
+
+        other.heartbeat += 1;
+        membership.merge(vec![other.clone()]);
+        assert_eq!(membership.state(other.id), Some(MemberState::Alive));
+        assert_eq!(membership.alive_with("process").len(), 1);
+
+        // A member that restarted starts counting again.
+        other.incarnation += 1;
+        other.heartbeat = 0;
+        other.modules.clear();
+        membership.merge(vec![other.clone()]);
+        assert!(membership.alive_with("process").is_empty());
+    }
+
+    #[tokio::test]
+    async fn gossip_is_answered_with_what_this_node_knows() {
+        let membership = membership();
+        let other = member("other");
+        membership.set_modules(vec!["b".into(), "a".into()]);
+
+        let mut reply = vec![];
+        answer(&membership, vec![other.clone()], &mut reply).await;
+
+        assert_eq!(membership.state(other.id), Some(MemberState::Alive));
+
+        // Past the length prefix.
+        let payload = &reply[4..];
+        let Frame::Gossip { mut members } = protocol::decode(payload).unwrap() else {
+            panic!("expected gossip in return");
+        };
+        members.sort_by(|a, b| a.name.cmp(&b.name));
+        let members: Vec<(&str, &[String])> = members
+            .iter()
+            .map(|member| (member.name.as_str(), &member.modules[..]))
+            .collect();
+        assert_eq!(
+            members,
+            vec![
+                ("me", &["a".to_string(), "b".to_string()][..]),
+                ("other", &["process".to_string()][..]),
+            ]
+        );
+    }
+}
@@ -0,0 +1,255 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use async_channel::Receiver;
+use tokio::{net::TcpListener, task::JoinHandle, time::Instant};
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::{
+    configuration::global_configuration::{GlobalConfiguration, NodeConfiguration, ANY_NODE},
+    messaging::message::Message,
+};
+
+use self::{
+    failover::Failover,
+    link::{Address, Link, Queue},
+    listener::RemoteInboxes,
+    membership::Membership,
+};
+
+pub mod failover;
+pub mod link;
+pub mod listener;
+pub mod membership;
+pub mod protocol;
+
+// Name of the inbox modules receive messages from other nodes on.
+pub const REMOTE_INBOX: &str = "@remote";
+
+// Messages from other nodes waiting for a module, before they stop being
+// acknowledged.
+const REMOTE_INBOX_CAPACITY: usize = 1024;
+
+/// This instance as seen by other hulaak instances. It keeps a link to every
+/// node that routes send to, and hands messages received from other nodes to
+/// the modules they are for. As part of a cluster, it also keeps track of the
+/// other members.
+pub struct Node {
+    id: Uuid,
+    name: String,
+    configuration: NodeConfiguration,
+    listening: bool,
+    membership: Option<Arc<Membership>>,
+    inboxes: RemoteInboxes,
+    receivers: HashMap<String, Receiver<Message>>,
+    links: HashMap<String, (Queue, JoinHandle<()>)>,
+    failovers: Vec<JoinHandle<()>>,
+    stop: CancellationToken,
+}
+
+impl Node {
+    /// Starts listening for other nodes and gossiping with the cluster, if
+    /// configured to. The stage id identifies this node to the others.
+    pub async fn start(configuration: &GlobalConfiguration, shutdown: CancellationToken) -> Self {
+        let stage_id = configuration.stage_id;
+        let name = configuration
+            .node
+            .name
+            .clone()
+            .unwrap_or_else(|| stage_id.to_string());
+
+        let inboxes = RemoteInboxes::default();
+        let mut listening = false;
+        let mut membership = None;
+
+        if let Some(address) = &configuration.node.listen {
+            match TcpListener::bind(address).await {
+                Ok(listener) => {
+                    println!("Listening for other nodes on {}", address);
+                    listening = true;
+
+                    if let Some(cluster) = &configuration.cluster {
+                        let advertise = cluster.advertise.clone().unwrap_or(address.clone());
+                        let joined = Arc::new(Membership::new(
+                            stage_id,
+                            name.clone(),
+                            advertise,
+                            cluster.clone(),
+                        ));
+                        tokio::spawn(membership::gossip(joined.clone(), shutdown.clone()));
+                        membership = Some(joined);
+                    }
+
+                    tokio::spawn(listener::listen(
+                        listener,
+                        inboxes.clone(),
+                        membership.clone(),
+                        shutdown,
+                    ));
+                }
+                Err(e) => println!("Could not listen for other nodes on {}: {}", address, e),
+            }
+        }
+
+        Node {
+            id: stage_id,
+            name,
+            configuration: configuration.node.clone(),
+            listening,
+            membership,
+            inboxes,
+            receivers: HashMap::new(),
+            links: HashMap::new(),
+            failovers: vec![],
+            stop: CancellationToken::new(),
+        }
+    }
+
+    /// True if other nodes can send messages to the modules of this one.
+    pub fn is_listening(&self) -> bool {
+        self.listening
+    }
+
+    /// The inbox `module` receives messages from other nodes on. It is kept
+    /// when the module restarts, until `remove_inbox` is called.
+    pub fn inbox(&mut self, module: &str) -> Receiver<Message> {
+        if let Some(receiver) = self.receivers.get(module) {
+            return receiver.clone();
+        }
+
+        let (sender, receiver) = async_channel::bounded(REMOTE_INBOX_CAPACITY);
+        self.inboxes
+            .lock()
+            .unwrap()
+            .insert(module.to_string(), sender);
+        self.receivers.insert(module.to_string(), receiver.clone());
+        self.advertise_modules();
+
+        receiver
+    }
+
+    pub fn remove_inbox(&mut self, module: &str) {
+        self.inboxes.lock().unwrap().remove(module);
+        self.receivers.remove(module);
+        self.advertise_modules();
+    }
+
+    /// Stops handing messages from other nodes to modules, so that modules
+    /// can finish once their routes are drained.
+    pub fn close_inboxes(&mut self) {
+        self.inboxes.lock().unwrap().clear();
+        self.receivers.clear();
+        self.advertise_modules();
+    }
+
+    // Lets the other members know which modules they can send messages to.
+    fn advertise_modules(&self) {
+        if let Some(membership) = &self.membership {
+            membership.set_modules(self.receivers.keys().cloned().collect());
+        }
+    }
+
+    /// Sends what arrives on `receiver` to `module` on the node `peer`, or on
+    /// any member of the cluster for `*`, until `receiver` is closed.
+    pub fn forward(&mut self, module: &str, peer: &str, receiver: Receiver<Message>) {
+        if peer == ANY_NODE {
+            let Some(membership) = self.membership.clone() else {
+                println!(
+                    "This node is not part of a cluster, dropping messages for {}@{}",
+                    module, peer
+                );
+                return;
+            };
+
+            let failover = Failover {
+                module: module.to_string(),
+                membership,
+                node: self.id,
+                name: self.name.clone(),
+                configuration: self.configuration.clone(),
+                stop: self.stop.clone(),
+            };
+            self.failovers.push(tokio::spawn(failover.run(receiver)));
+            return;
+        }
+
+        let Some(queue) = self.link(peer) else {
+            println!(
+                "Node {} is not one of the peers, dropping messages for {}@{}",
+                peer, module, peer
+            );
+            return;
+        };
+
+        let module = module.to_string();
+        tokio::spawn(async move {
+            while let Ok(message) = receiver.recv().await {
+                if queue.send((module.clone(), message)).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    // The queue of the link to `peer`, connecting to it the first time. Peers
+    // not in node.peers are looked up among the members of the cluster.
+    fn link(&mut self, peer: &str) -> Option<Queue> {
+        if let Some((queue, _)) = self.links.get(peer) {
+            return Some(queue.clone());
+        }
+
+        let address = match (self.configuration.peers.get(peer), &self.membership) {
+            (Some(address), _) => Address::Fixed(address.clone()),
+            (None, Some(membership)) => Address::Member(peer.to_string(), membership.clone()),
+            (None, None) => return None,
+        };
+
+        let (queue, receiver) = async_channel::bounded(1);
+        let link = Link {
+            peer: peer.to_string(),
+            address,
+            node: self.id,
+            name: self.name.clone(),
+            configuration: self.configuration.clone(),
+            rejected: None,
+        };
+
+        let stop = self.stop.clone();
+        let name = peer.to_string();
+        let handle = tokio::spawn(async move {
+            let unacknowledged = link.run(receiver, stop).await;
+            if !unacknowledged.is_empty() {
+                println!(
+                    "Giving up on {} unacknowledged messages for node {}",
+                    unacknowledged.len(),
+                    name
+                );
+            }
+        });
+
+        self.links.insert(peer.to_string(), (queue.clone(), handle));
+
+        Some(queue)
+    }
+
+    /// Waits until every message sent to other nodes has been acknowledged,
+    /// giving up on the ones that are not by `deadline`.
+    pub async fn drain(&mut self, deadline: Instant) {
+        let handles: Vec<JoinHandle<()>> = self
+            .links
+            .drain()
+            .map(|(_, (_, handle))| handle)
+            .chain(self.failovers.drain(..))
+            .collect();
+
+        for mut handle in handles {
+            if tokio::time::timeout_at(deadline, &mut handle)
+                .await
+                .is_err()
+            {
+                self.stop.cancel();
+                let _ = tokio::time::timeout(Duration::from_secs(1), handle).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,238 @@
+use std::{collections::HashMap, io};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::messaging::{
+    framing::{FrameDecoder, Framing},
+    message::{Message, MessageSource},
+};
+
+use super::membership::Member;
+
+// Longest frame accepted from another node.
+const MAX_FRAME_LENGTH: usize = 64 * 1024 * 1024;
+
+/// What nodes send each other, bincode encoded with a u32 length prefix.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Frame {
+    /// First frame on a connection, telling who is sending. Every link to
+    /// another node has a session of its own, new every time it starts.
+    Hello {
+        node: Uuid,
+        name: String,
+        session: Uuid,
+    },
+
+    /// A message for a module of the receiving node. Sequence numbers start
+    /// at 1 for every session, and keep counting across reconnections.
+    Message {
+        seq: u64,
+        module: String,
+        message: WireMessage,
+    },
+
+    /// Every message up to and including `seq` has been handed to its
+    /// module, but for those rejected.
+    Ack { seq: u64 },
+
+    /// The message `seq` was not handed to any module, because the receiving
+    /// node has no module by that name, or could not read the message.
+    Reject { seq: u64, reason: String },
+
+    /// The members of the cluster the sender knows of. Sent on a connection
+    /// of its own, and answered with the members the receiver knows of.
+    Gossip { members: Vec<Member> },
+}
+
+/// A message as sent to other nodes. Field values are sent as JSON, as
+/// bincode cannot read back a `serde_json::Value`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WireMessage {
+    id: Uuid,
+    ingested_at: DateTime<Utc>,
+    source: Option<MessageSource>,
+    routes: Vec<String>,
+    headers: HashMap<String, String>,
+    hops: u32,
+    fields: String,
+    body: Option<Vec<u8>>,
+}
+
+impl From<&Message> for WireMessage {
+    fn from(message: &Message) -> Self {
+        WireMessage {
+            id: message.id,
+            ingested_at: message.ingested_at,
+            source: message.source.clone(),
+            routes: message.routes.clone(),
+            headers: message.headers.clone(),
+            hops: message.hops,
+            fields: serde_json::to_string(&message.fields).unwrap_or_else(|_| "{}".into()),
+            body: message.body.clone(),
+        }
+    }
+}
+
+impl TryFrom<WireMessage> for Message {
+    type Error = serde_json::Error;
+
+    fn try_from(wire: WireMessage) -> Result<Self, Self::Error> {
+        Ok(Message {
+            id: wire.id,
+            ingested_at: wire.ingested_at,
+            source: wire.source,
+            routes: wire.routes,
+            headers: wire.headers,
+            hops: wire.hops,
+            fields: serde_json::from_str(&wire.fields)?,
+            body: wire.body,
+        })
+    }
+}
+
+pub fn encode(frame: &Frame) -> io::Result<Vec<u8>> {
+    let payload = bincode::serialize(frame).map_err(invalid_data)?;
+
+    let mut bytes = Vec::with_capacity(payload.len() + 4);
+    Framing::LengthPrefixedU32
+        .encode(&payload, &mut bytes)
+        .map_err(invalid_data)?;
+
+    Ok(bytes)
+}
+
+pub fn decode(bytes: &[u8]) -> io::Result<Frame> {
+    bincode::deserialize(bytes).map_err(invalid_data)
+}
+
+pub fn decoder() -> FrameDecoder {
+    Framing::LengthPrefixedU32.decoder(MAX_FRAME_LENGTH)
+}
+
+fn invalid_data(e: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_util::{bytes::BytesMut, codec::Decoder};
+
+    use super::*;
+
+    // Encodes `frame`, and reads it back the way connections do.
+    fn round_trip(frame: &Frame) -> Frame {
+        let mut buffer = BytesMut::from(&encode(frame).unwrap()[..]);
+        let bytes = decoder().decode(&mut buffer).unwrap().unwrap();
+        assert!(buffer.is_empty());
+
+        decode(&bytes).unwrap()
+    }
+
+    #[test]
+    fn hellos_and_acknowledgements_are_read_back() {
+        let (node, session) = (Uuid::new_v4(), Uuid::new_v4());
+        let hello = Frame::Hello {
+            node,
+            name: "edge".into(),
+            session,
+        };
+        assert!(matches!(
+            round_trip(&hello),
+            Frame::Hello { node: n, name, session: s } if n == node && name == "edge" && s == session
+        ));
+
+        assert!(matches!(
+            round_trip(&Frame::Ack { seq: 7 }),
+            Frame::Ack { seq: 7 }
+        ));
+
+        let reject = Frame::Reject {
+            seq: 8,
+            reason: "no module".into(),
+        };
+        assert!(matches!(
+            round_trip(&reject),
+            Frame::Reject { seq: 8, reason } if reason == "no module"
+        ));
+    }
+
+    #[test]
+    fn messages_are_read_back_whole() {
+        let mut message = Message::new(HashMap::from([
+            ("a".to_string(), 1.into()),
+            (
+                "nested".to_string(),
+                serde_json::json!({"b": [true, null, 2.5]}),
+            ),
+        ]));
+        message.body = Some(vec![0, 1, 255]);
+        message.source = Some(MessageSource {
+            module: "input".into(),
+            uuid: Uuid::new_v4(),
+        });
+        message.routes.push("main".into());
+        message.headers.insert("origin".into(), "edge".into());
+        message.hops = 2;
+
+        let frame = Frame::Message {
+            seq: 1,
+            module: "output".into(),
+            message: (&message).into(),
+        };
+        let Frame::Message {
+            seq,
+            module,
+            message: wire,
+        } = round_trip(&frame)
+        else {
+            panic!("expected a message");
+        };
+        let read = Message::try_from(wire).unwrap();
+
+        assert_eq!((seq, module.as_str()), (1, "output"));
+        assert_eq!(read.id, message.id);
+        assert_eq!(read.ingested_at, message.ingested_at);
+        assert_eq!(read.source, message.source);
+        assert_eq!(read.routes, message.routes);
+        assert_eq!(read.headers, message.headers);
+        assert_eq!(read.hops, message.hops);
+        assert_eq!(read.fields, message.fields);
+        assert_eq!(read.body, message.body);
+    }
+
+    #[test]
+    fn frames_are_read_once_they_are_whole() {
+        let bytes = encode(&Frame::Ack { seq: 1 }).unwrap();
+        let mut decoder = decoder();
+        let mut buffer = BytesMut::new();
+
+        for byte in &bytes[..bytes.len() - 1] {
+            buffer.extend_from_slice(&[*byte]);
+            assert!(decoder.decode(&mut buffer).unwrap().is_none());
+        }
+        buffer.extend_from_slice(&bytes[bytes.len() - 1..]);
+
+        let frame = decoder.decode(&mut buffer).unwrap().unwrap();
+        assert!(matches!(decode(&frame).unwrap(), Frame::Ack { seq: 1 }));
+    }
+
+    #[test]
+    fn unreadable_frames_are_errors() {
+        let error = decode(&[0xff, 0xff, 0xff, 0xff]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        let Frame::Message { message, .. } = round_trip(&Frame::Message {
+            seq: 1,
+            module: "output".into(),
+            message: WireMessage {
+                fields: "not json".into(),
+                ..(&Message::new(HashMap::new())).into()
+            },
+        }) else {
+            panic!("expected a message");
+        };
+        assert!(Message::try_from(message).is_err());
+    }
+}
@@ -0,0 +1,118 @@
+//! The stable interface between Hulaak and modules loaded from shared
+//! libraries. Everything here is `#[repr(C)]` and only passes plain pointers
+//! and integers, so plugins can be built with any Rust compiler version (or
+//! any language that can export C functions).
+//!
+//! A plugin exports a single symbol, `hulaak_module_declaration`, returning a
+//! pointer to a [`ModuleDeclaration`] that lives as long as the library:
+//!
+//! ```ignore
+//! #[no_mangle]
+//! pub extern "C" fn hulaak_module_declaration() -> *const ModuleDeclaration {
+//!     &DECLARATION
+//! }
+//! ```
+//!
+//! Messages and settings cross the boundary as UTF-8 JSON. A message is an
+//! object of the form `{"fields": {"data": "..."}}`, along with its envelope
+//! (`id`, `ingested_at`, `source`, `routes`, `headers` and `hops`). Plugins
+//! may leave the envelope out of the messages they emit, and should ignore
+//! keys they do not know about.
+//!
+//! Messages carrying raw data rather than fields also have a `body`, an
+//! array of byte values: `{"fields": {}, "body": [104, 105]}`. It is left out
+//! of messages that have none. Plugins may emit it as a string instead, which
+//! is taken as its UTF-8 bytes.
+
+use std::ffi::{c_char, c_void};
+
+/// Bumped whenever the layout of anything in this file changes. Libraries
+/// built against a different version are refused.
+pub const ABI_VERSION: u32 = 2;
+
+/// Name of the symbol every plugin exports.
+pub const DECLARATION_SYMBOL: &[u8] = b"hulaak_module_declaration\0";
+
+/// Set in `ModuleDeclaration::capabilities` if the module can be a route
+/// destination, and implements `handle`.
+pub const CAPABILITY_INBOX: u32 = 1;
+
+/// Set in `ModuleDeclaration::capabilities` if the module can be a route
+/// source, and implements `run`.
+pub const CAPABILITY_OUTBOX: u32 = 2;
+
+/// Borrowed bytes, only valid for the duration of the call they are passed to.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct FfiBytes {
+    pub ptr: *const u8,
+    pub len: usize,
+}
+
+impl FfiBytes {
+    pub fn new(bytes: &[u8]) -> Self {
+        FfiBytes {
+            ptr: bytes.as_ptr(),
+            len: bytes.len(),
+        }
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must point to `len` readable bytes for the lifetime `'a`.
+    pub unsafe fn as_slice<'a>(&self) -> &'a [u8] {
+        if self.ptr.is_null() {
+            return &[];
+        }
+
+        std::slice::from_raw_parts(self.ptr, self.len)
+    }
+}
+
+/// Called by the plugin with a JSON encoded message. Returns 0 if the message
+/// was accepted, and anything else if the module should stop sending.
+pub type EmitCallback = unsafe extern "C" fn(host: *mut c_void, message: FfiBytes) -> i32;
+
+/// Called by the plugin with a UTF-8 error message.
+pub type ErrorCallback = unsafe extern "C" fn(host: *mut c_void, error: FfiBytes);
+
+#[repr(C)]
+pub struct ModuleDeclaration {
+    /// Must be `ABI_VERSION`.
+    pub abi_version: u32,
+
+    /// The `module_type` used in configurations, NUL terminated.
+    pub name: *const c_char,
+
+    /// A one line description, NUL terminated.
+    pub description: *const c_char,
+
+    /// The module settings with their default values as a JSON object, NUL
+    /// terminated.
+    pub default_settings: *const c_char,
+
+    /// A combination of `CAPABILITY_INBOX` and `CAPABILITY_OUTBOX`.
+    pub capabilities: u32,
+
+    /// Creates an instance of the module from its JSON encoded settings.
+    /// Returns null on failure, after reporting why through `error`.
+    pub create: unsafe extern "C" fn(
+        settings: FfiBytes,
+        host: *mut c_void,
+        error: ErrorCallback,
+    ) -> *mut c_void,
+
+    /// Runs a source module, calling `emit` for every message it produces.
+    /// Returns once the source is done, or `emit` returns non-zero. Called
+    /// on a dedicated thread, so it may block.
+    pub run: Option<
+        unsafe extern "C" fn(instance: *mut c_void, host: *mut c_void, emit: EmitCallback) -> i32,
+    >,
+
+    /// Hands a JSON encoded message to a destination module. Returns 0 on
+    /// success. Called on a dedicated thread, so it may block.
+    pub handle: Option<unsafe extern "C" fn(instance: *mut c_void, message: FfiBytes) -> i32>,
+
+    /// Frees an instance returned by `create`.
+    pub destroy: unsafe extern "C" fn(instance: *mut c_void),
+}
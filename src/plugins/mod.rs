@@ -0,0 +1,338 @@
+pub mod abi;
+
+use std::{
+    collections::HashMap,
+    ffi::{c_void, CStr},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use libloading::Library;
+use once_cell::sync::Lazy;
+use tokio::{runtime::Handle, task::JoinHandle};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    configuration::{
+        error::ConfigurationError, global_configuration::GlobalConfiguration,
+        module_properties::ModuleProperties,
+    },
+    messaging::{channel::Outbox, mailbox::Outboxes, message::Message},
+    modules::{module::ModuleTrait, registry::Capabilities},
+};
+
+use abi::{FfiBytes, ModuleDeclaration};
+
+// Module types loaded from shared libraries, by name.
+static PLUGINS: Lazy<RwLock<HashMap<String, Arc<Plugin>>>> = Lazy::new(Default::default);
+
+/// A module type loaded from a shared library.
+pub struct Plugin {
+    pub name: String,
+    pub description: String,
+    pub default_settings: serde_json::Value,
+    pub capabilities: Capabilities,
+    pub path: PathBuf,
+    declaration: *const ModuleDeclaration,
+
+    // Kept last, so that the library outlives everything pointing into it.
+    _library: Library,
+}
+
+// SAFETY: the declaration is immutable and lives as long as the library,
+// which is owned by the plugin.
+unsafe impl Send for Plugin {}
+unsafe impl Sync for Plugin {}
+
+impl Plugin {
+    fn declaration(&self) -> &ModuleDeclaration {
+        // SAFETY: checked to be non-null on load, and valid while the library
+        // is loaded.
+        unsafe { &*self.declaration }
+    }
+}
+
+/// Loads a module type from a shared library, making it available to
+/// configurations under the name it declares. Returns that name.
+pub fn load(path: &Path) -> Result<String> {
+    // SAFETY: loading a library runs its initializers. Plugins are trusted
+    // code, listed explicitly in the configuration.
+    let library = unsafe { Library::new(path) }
+        .with_context(|| format!("error loading plugin {}", path.display()))?;
+
+    let declaration = unsafe {
+        let symbol = library
+            .get::<unsafe extern "C" fn() -> *const ModuleDeclaration>(abi::DECLARATION_SYMBOL)
+            .with_context(|| format!("{} is not a hulaak plugin", path.display()))?;
+        symbol()
+    };
+
+    if declaration.is_null() {
+        bail!("{} returned no module declaration", path.display());
+    }
+
+    // SAFETY: non-null, and lives as long as the library.
+    let (abi_version, name, description, default_settings, capabilities) = unsafe {
+        let declaration = &*declaration;
+        (
+            declaration.abi_version,
+            c_string(declaration.name),
+            c_string(declaration.description),
+            c_string(declaration.default_settings),
+            declaration.capabilities,
+        )
+    };
+
+    if abi_version != abi::ABI_VERSION {
+        bail!(
+            "{} was built for plugin ABI version {}, but this is version {}",
+            path.display(),
+            abi_version,
+            abi::ABI_VERSION
+        );
+    }
+
+    let name = name.ok_or_else(|| anyhow!("{} declares no module name", path.display()))?;
+    if crate::modules::registry::ModulesRegistry::find(&name).is_some() {
+        bail!(
+            "{} declares module type {:?}, which already exists",
+            path.display(),
+            name
+        );
+    }
+
+    let default_settings = match default_settings {
+        Some(settings) => serde_json::from_str(&settings)
+            .with_context(|| format!("{} declares invalid default settings", path.display()))?,
+        None => serde_json::Value::Object(Default::default()),
+    };
+
+    let plugin = Plugin {
+        name: name.clone(),
+        description: description.unwrap_or_default(),
+        default_settings,
+        capabilities: Capabilities {
+            inbox: capabilities & abi::CAPABILITY_INBOX != 0,
+            outbox: capabilities & abi::CAPABILITY_OUTBOX != 0,
+        },
+        path: path.to_path_buf(),
+        declaration,
+        _library: library,
+    };
+
+    let mut plugins = PLUGINS.write().unwrap();
+    if plugins.contains_key(&name) {
+        bail!(
+            "{} declares module type {:?}, which already exists",
+            path.display(),
+            name
+        );
+    }
+    plugins.insert(name.clone(), Arc::new(plugin));
+
+    Ok(name)
+}
+
+/// Loads the plugins listed in a configuration, skipping the ones that are
+/// already loaded.
+pub fn load_configured(configuration: &GlobalConfiguration) -> Result<()> {
+    for path in &configuration.plugins {
+        if list().iter().any(|plugin| plugin.path == *path) {
+            continue;
+        }
+
+        let name = load(path)?;
+        println!("Loaded module type {} from {}", name, path.display());
+    }
+
+    Ok(())
+}
+
+pub fn find(name: &str) -> Option<Arc<Plugin>> {
+    PLUGINS.read().unwrap().get(name).cloned()
+}
+
+pub fn list() -> Vec<Arc<Plugin>> {
+    PLUGINS.read().unwrap().values().cloned().collect()
+}
+
+/// Reads an optional NUL terminated string from a plugin.
+unsafe fn c_string(pointer: *const std::ffi::c_char) -> Option<String> {
+    if pointer.is_null() {
+        return None;
+    }
+
+    Some(CStr::from_ptr(pointer).to_string_lossy().into_owned())
+}
+
+/// An instance of a plugin module, destroyed through the plugin when dropped.
+struct Instance {
+    plugin: Arc<Plugin>,
+    pointer: *mut c_void,
+}
+
+// SAFETY: plugins are required to allow their instances to be used from any
+// thread.
+unsafe impl Send for Instance {}
+unsafe impl Sync for Instance {}
+
+impl Drop for Instance {
+    fn drop(&mut self) {
+        // SAFETY: the pointer came from `create`, and is destroyed only once.
+        unsafe { (self.plugin.declaration().destroy)(self.pointer) }
+    }
+}
+
+/// Runs a module provided by a plugin.
+pub struct PluginModule {
+    pub(crate) properties: ModuleProperties,
+    instance: Arc<Instance>,
+}
+
+impl ModuleTrait for PluginModule {
+    fn new(configuration: ModuleProperties) -> Result<Self, ConfigurationError>
+    where
+        Self: Sized,
+    {
+        let plugin = find(&configuration.module_type).ok_or_else(|| {
+            ConfigurationError::UnknownModuleType(configuration.module_type.clone())
+        })?;
+
+        let settings =
+            serde_json::to_vec(&configuration.module_settings.clone().unwrap_or_default())
+                .expect("Failed to serialize module settings");
+
+        let mut error = String::new();
+        let pointer = unsafe {
+            (plugin.declaration().create)(
+                FfiBytes::new(&settings),
+                &mut error as *mut String as *mut c_void,
+                report_error,
+            )
+        };
+
+        if pointer.is_null() {
+            return Err(ConfigurationError::InvalidSettings {
+                path: ".".into(),
+                message: if error.is_empty() {
+                    "rejected by the plugin".into()
+                } else {
+                    error
+                },
+            });
+        }
+
+        Ok(PluginModule {
+            properties: configuration,
+            instance: Arc::new(Instance { plugin, pointer }),
+        })
+    }
+
+    fn add_outbox(&mut self, name: String, outbox: Outbox) {
+        self.properties.outboxes.insert(name, outbox);
+    }
+
+    fn add_inbox(&mut self, name: String, inbox: async_channel::Receiver<Message>) {
+        self.properties.inboxes.insert(name, inbox);
+    }
+
+    fn run(self: Box<Self>) -> JoinHandle<()> {
+        let runtime = Handle::current();
+        let declaration = self.instance.plugin.declaration();
+        let mut tasks = vec![];
+
+        if let (Some(run), false) = (declaration.run, self.properties.outboxes.is_empty()) {
+            let instance = self.instance.clone();
+            let host = EmitHost {
+                outboxes: self.properties.outboxes.clone(),
+                runtime: runtime.clone(),
+                shutdown: self.properties.shutdown.clone(),
+            };
+
+            tasks.push(tokio::task::spawn_blocking(move || {
+                let code = unsafe {
+                    run(
+                        instance.pointer,
+                        &host as *const EmitHost as *mut c_void,
+                        emit,
+                    )
+                };
+
+                if code != 0 {
+                    println!(
+                        "Plugin module {} stopped with code {}",
+                        instance.plugin.name, code
+                    );
+                }
+            }));
+        }
+
+        if let (Some(handle), false) = (declaration.handle, self.properties.inboxes.is_empty()) {
+            let instance = self.instance.clone();
+            let inboxes = self.properties.inboxes.clone();
+
+            tasks.push(tokio::task::spawn_blocking(move || {
+                while let Ok(message) = runtime.block_on(inboxes.recv()) {
+                    let bytes = match serde_json::to_vec(&message) {
+                        Ok(bytes) => bytes,
+                        Err(e) => {
+                            println!("Error serializing message for plugin: {:?}", e);
+                            continue;
+                        }
+                    };
+
+                    let code = unsafe { handle(instance.pointer, FfiBytes::new(&bytes)) };
+                    if code != 0 {
+                        println!(
+                            "Plugin module {} failed to handle a message with code {}",
+                            instance.plugin.name, code
+                        );
+                    }
+                }
+            }));
+        }
+
+        tokio::spawn(async move {
+            for task in tasks {
+                if let Err(e) = task.await {
+                    println!("Plugin module failed with error: {:?}", e);
+                }
+            }
+        })
+    }
+}
+
+/// What `emit` needs to hand messages from a plugin to its routes.
+struct EmitHost {
+    outboxes: Outboxes,
+    runtime: Handle,
+    shutdown: CancellationToken,
+}
+
+unsafe extern "C" fn emit(host: *mut c_void, message: FfiBytes) -> i32 {
+    let host = &*(host as *const EmitHost);
+
+    // Asks the plugin to stop once hulaak is shutting down.
+    if host.shutdown.is_cancelled() {
+        return 1;
+    }
+
+    let message: Message = match serde_json::from_slice(message.as_slice()) {
+        Ok(message) => message,
+        Err(e) => {
+            println!("Discarding invalid message from plugin: {:?}", e);
+            return 0;
+        }
+    };
+
+    match host.runtime.block_on(host.outboxes.send(message)) {
+        Ok(_) => 0,
+        Err(_) => 1,
+    }
+}
+
+unsafe extern "C" fn report_error(host: *mut c_void, error: FfiBytes) {
+    let target = &mut *(host as *mut String);
+    *target = String::from_utf8_lossy(error.as_slice()).into_owned();
+}